@@ -1,4 +1,13 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use wigglyair::metadata::ReplayGain;
+use wigglyair::ring::chunk_ring;
+use wigglyair::sink::{AudioSink, Failed, Render, SinkError, Stream};
+use wigglyair::types::{AudioParams, Player, PlayerCommand, RepeatMode, Track, TrackList};
 
 #[inline]
 fn copy_buf(data: &mut [f32], buf: Vec<f32>) {
@@ -19,10 +28,153 @@ fn bench_copy_buf(c: &mut Criterion) {
     });
 }
 
+// what the audio callback does: fill the device buffer from the ring,
+// scaling in place
+fn bench_chunk_ring(c: &mut Criterion) {
+    let (mut producer, mut consumer) = chunk_ring::<()>(1 << 16, 64);
+    let chunk = vec![1.0f32; 2048];
+    let mut data = [0.0f32; 8192];
+
+    c.bench_function("chunk_ring", |b| {
+        b.iter(|| {
+            while producer.push((), &chunk).is_ok() {}
+
+            let mut filled = 0;
+            while filled < data.len() {
+                let (n, _) = consumer.read(&mut data[filled..]);
+                for sample in &mut data[filled..filled + n] {
+                    *sample *= 0.5;
+                }
+                filled += n;
+            }
+            black_box(&mut data);
+        })
+    });
+}
+
+// a long queue of four minute tracks, some of them at another rate
+fn long_queue() -> TrackList {
+    (0..2000u32)
+        .map(|i| Track {
+            path: PathBuf::from(format!("{i}.flac")),
+            sample_rate: if i % 3 == 0 { 48_000 } else { 44_100 },
+            samples: 240 * 44_100 + u64::from(i),
+            channels: 2,
+            album: format!("Album {}", i / 10),
+            album_artist: "Artist".to_owned(),
+            title: format!("Track {i}"),
+            track: i % 10 + 1,
+            replay_gain: ReplayGain::default(),
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// what the audio callback does to find the playing track, and what the TUI
+// does for every row it draws
+fn bench_track_lookup(c: &mut Criterion) {
+    let tracks = long_queue();
+    let near_the_end = tracks.total_samples - 1000;
+
+    c.bench_function("find_playing", |b| {
+        b.iter(|| tracks.find_playing(black_box(near_the_end)))
+    });
+    c.bench_function("get_start_point every row", |b| {
+        b.iter(|| {
+            (0..tracks.tracks.len())
+                .map(|i| tracks.get_start_point(black_box(i)))
+                .sum::<u64>()
+        })
+    });
+}
+
+/// Hands the player's render callback over, so it can be called directly
+#[derive(Clone, Default)]
+struct CallbackSink(Arc<Mutex<Option<Render>>>);
+
+impl AudioSink for CallbackSink {
+    fn open(&mut self, _: AudioParams, render: Render, _: Failed) -> Result<Stream, SinkError> {
+        *self.0.lock().unwrap() = Some(render);
+        Ok(Stream::new(()))
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+}
+
+// the whole audio callback, from the ring through gain, the equalizer and the
+// volume ramp to the tap, playing a track on repeat
+fn bench_output_render(c: &mut Criterion) {
+    let frames = 10 * 44_100u32;
+    let path = std::env::temp_dir().join(format!("wigglyair-bench-{}.wav", std::process::id()));
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: 44_100,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(&path, spec).unwrap();
+    for frame in 0..frames {
+        let sample = (frame % 100) as f32 / 100.0;
+        writer.write_sample(sample).unwrap();
+        writer.write_sample(-sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    let track = Track {
+        path: path.clone(),
+        sample_rate: 44_100,
+        samples: u64::from(frames),
+        channels: 2,
+        album: "Album".to_owned(),
+        album_artist: "Artist".to_owned(),
+        title: "Track".to_owned(),
+        track: 1,
+        replay_gain: ReplayGain::default(),
+    };
+
+    let sink = CallbackSink::default();
+    let player = Player::new(TrackList::from(vec![track])).with_sink(sink.clone());
+    player.set_repeat(RepeatMode::All);
+    let handle = player.handle();
+    let thread = player.start();
+    while sink.0.lock().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut render = sink.0.lock().unwrap().take().unwrap();
+    let mut data = [0.0f32; 8192];
+
+    // only the callback is timed. whenever it runs short, the reader gets a
+    // moment to catch up.
+    c.bench_function("output render", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                let rendered = render(black_box(&mut data));
+                elapsed += start.elapsed();
+                if rendered < data.len() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            elapsed
+        })
+    });
+
+    // the callback is what notices the player stopping
+    handle.send(PlayerCommand::Stop);
+    while !thread.is_finished() {
+        render(&mut data);
+        thread::sleep(Duration::from_millis(1));
+    }
+    thread.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default();
-    targets = bench_copy_buf
+    targets = bench_copy_buf, bench_chunk_ring, bench_track_lookup, bench_output_render
 }
 
 criterion_main!(benches);
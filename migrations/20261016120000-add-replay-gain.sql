@@ -0,0 +1,4 @@
+ALTER TABLE tracks ADD COLUMN track_gain REAL;
+ALTER TABLE tracks ADD COLUMN track_peak REAL;
+ALTER TABLE tracks ADD COLUMN album_gain REAL;
+ALTER TABLE tracks ADD COLUMN album_peak REAL;
@@ -0,0 +1,15 @@
+CREATE TABLE track_loudness(
+    path TEXT NOT NULL,
+    integrated_lufs REAL,
+    loudness_range REAL,
+    true_peak REAL NOT NULL,
+    UNIQUE(path)
+);
+
+CREATE TABLE album_loudness(
+    album_artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    integrated_lufs REAL,
+    true_peak REAL NOT NULL,
+    UNIQUE(album_artist, album)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE track_waveforms(
+    path TEXT NOT NULL,
+    buckets BLOB NOT NULL,
+    UNIQUE(path)
+);
@@ -0,0 +1,301 @@
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The most recent audio the output has written, for anything that wants to
+/// look at it
+///
+/// The audio callback writes into a fixed ring of atomics and bumps a
+/// counter, so it never waits on readers and readers never hold it up.
+/// Readers always get the latest audio; if they're slow they miss some, and a
+/// read racing a write can see a few samples from a buffer later, which
+/// doesn't matter for drawing it.
+pub struct AudioTap {
+    samples: Box<[AtomicU32]>,
+    /// How many samples have been written altogether
+    written: AtomicUsize,
+    channels: AtomicUsize,
+    sample_rate: AtomicU32,
+}
+
+impl Default for AudioTap {
+    /// Room for a few FFT windows of 8 channels
+    fn default() -> Self {
+        Self::new(1 << 15)
+    }
+}
+
+impl AudioTap {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            channels: AtomicUsize::new(2),
+            sample_rate: AtomicU32::new(44_100),
+        }
+    }
+
+    /// Add interleaved `samples` that were just handed to the output
+    pub fn write(&self, samples: &[f32], channels: usize, sample_rate: u32) {
+        self.channels.store(channels.max(1), Ordering::Relaxed);
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        let capacity = self.samples.len();
+        let start = self.written.load(Ordering::Relaxed);
+        // anything older than the ring would be overwritten anyway
+        let skip = samples.len().saturating_sub(capacity);
+        for (i, &sample) in samples.iter().enumerate().skip(skip) {
+            self.samples[(start + i) % capacity].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.written.store(start + samples.len(), Ordering::Release);
+    }
+
+    /// Copy the last `frames` frames into `out`, oldest first, and return
+    /// the channel count and sample rate they're at. Frames from before
+    /// anything was written are silent.
+    pub fn latest(&self, frames: usize, out: &mut Vec<f32>) -> (usize, u32) {
+        let written = self.written.load(Ordering::Acquire);
+        let channels = self.channels.load(Ordering::Relaxed);
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let capacity = self.samples.len();
+        let len = (frames * channels).min(capacity / channels * channels);
+
+        out.clear();
+        out.extend((0..len).map(|i| match (written + i).checked_sub(len) {
+            Some(index) => f32::from_bits(self.samples[index % capacity].load(Ordering::Relaxed)),
+            None => 0.0,
+        }));
+        (channels, sample_rate)
+    }
+}
+
+/// Levels for one channel, in dBFS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Meter {
+    pub peak: f32,
+    pub rms: f32,
+    /// The highest recent peak, which hangs around for a moment before
+    /// falling back
+    pub held: f32,
+    hold_left: Duration,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            peak: Analyzer::FLOOR_DB,
+            rms: Analyzer::FLOOR_DB,
+            held: Analyzer::FLOOR_DB,
+            hold_left: Duration::ZERO,
+        }
+    }
+}
+
+/// Turns what's coming out of an `AudioTap` into a spectrum and level meters
+pub struct Analyzer {
+    fft: Arc<dyn RealToComplex<f32>>,
+    /// Hann window, as long as the FFT
+    window: Vec<f32>,
+    interleaved: Vec<f32>,
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Level of each band in dBFS, low to high
+    spectrum: Vec<f32>,
+    meters: Vec<Meter>,
+}
+
+impl Analyzer {
+    /// Frames looked at each update, which is a little under 50ms at 44.1kHz
+    const FFT_SIZE: usize = 2048;
+    /// Quietest level anything is shown at
+    pub const FLOOR_DB: f32 = -72.0;
+    /// Frequencies the spectrum covers, in Hz
+    const LOWEST: f32 = 30.0;
+    const HIGHEST: f32 = 16_000.0;
+    /// How fast the spectrum and held peaks fall, in dB per second
+    const FALL: f32 = 36.0;
+    /// How long a peak is held before it starts falling
+    const HOLD: Duration = Duration::from_millis(1500);
+
+    /// An analyzer that splits the spectrum into `bands` bands
+    #[must_use]
+    pub fn new(bands: usize) -> Self {
+        let fft = RealFftPlanner::new().plan_fft_forward(Self::FFT_SIZE);
+        #[allow(clippy::cast_precision_loss)]
+        let window = (0..Self::FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / Self::FFT_SIZE as f32).cos())
+            .collect();
+        Self {
+            input: fft.make_input_vec(),
+            output: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
+            window,
+            interleaved: Vec::new(),
+            spectrum: vec![Self::FLOOR_DB; bands.max(1)],
+            meters: Vec::new(),
+        }
+    }
+
+    /// Look at the latest audio from `tap`, `elapsed` after the last update
+    pub fn update(&mut self, tap: &AudioTap, elapsed: Duration) {
+        let mut interleaved = std::mem::take(&mut self.interleaved);
+        let (channels, sample_rate) = tap.latest(Self::FFT_SIZE, &mut interleaved);
+        self.analyze(&interleaved, channels, sample_rate, elapsed);
+        self.interleaved = interleaved;
+    }
+
+    /// Level of each band in dBFS, from low to high frequencies
+    #[must_use]
+    pub fn spectrum(&self) -> &[f32] {
+        &self.spectrum
+    }
+
+    /// Levels for each channel
+    #[must_use]
+    pub fn meters(&self) -> &[Meter] {
+        &self.meters
+    }
+
+    fn analyze(
+        &mut self,
+        interleaved: &[f32],
+        channels: usize,
+        sample_rate: u32,
+        elapsed: Duration,
+    ) {
+        let fall = Self::FALL * elapsed.as_secs_f32();
+        let frames = interleaved.chunks_exact(channels.max(1));
+
+        self.meters.resize(channels, Meter::default());
+        for (channel, meter) in self.meters.iter_mut().enumerate() {
+            let (peak, energy) = frames
+                .clone()
+                .map(|frame| frame[channel])
+                .fold((0.0f32, 0.0f32), |(peak, energy), s| {
+                    (peak.max(s.abs()), energy + s * s)
+                });
+            #[allow(clippy::cast_precision_loss)]
+            let rms = (energy / frames.len().max(1) as f32).sqrt();
+            meter.peak = to_db(peak);
+            meter.rms = to_db(rms);
+            meter.hold_left = meter.hold_left.saturating_sub(elapsed);
+            if meter.peak >= meter.held {
+                meter.held = meter.peak;
+                meter.hold_left = Self::HOLD;
+            } else if meter.hold_left.is_zero() {
+                meter.held = (meter.held - fall).max(meter.peak);
+            }
+        }
+
+        // everything gets mixed down to mono for the spectrum
+        #[allow(clippy::cast_precision_loss)]
+        let scale = 1.0 / channels.max(1) as f32;
+        self.input.fill(0.0);
+        for ((input, frame), window) in self.input.iter_mut().zip(frames).zip(&self.window) {
+            *input = frame.iter().sum::<f32>() * scale * window;
+        }
+        if self
+            .fft
+            .process_with_scratch(&mut self.input, &mut self.output, &mut self.scratch)
+            .is_err()
+        {
+            return;
+        }
+
+        // a full scale sine comes out of a Hann window at a quarter of the
+        // FFT size
+        #[allow(clippy::cast_precision_loss)]
+        let normalize = 4.0 / Self::FFT_SIZE as f32;
+        #[allow(clippy::cast_precision_loss)]
+        let bin_width = sample_rate.max(1) as f32 / Self::FFT_SIZE as f32;
+        let highest = Self::HIGHEST.min(bin_width * (self.output.len() - 1) as f32);
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = (highest / Self::LOWEST).powf(1.0 / self.spectrum.len() as f32);
+        let last_bin = self.output.len() - 1;
+        let mut low = Self::LOWEST;
+        for band in &mut self.spectrum {
+            let high = low * ratio;
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let (first, last) = (
+                ((low / bin_width).round() as usize).min(last_bin),
+                ((high / bin_width).round() as usize).min(last_bin),
+            );
+            let magnitude = self.output[first..=last.max(first)]
+                .iter()
+                .map(|bin| bin.norm())
+                .fold(0.0, f32::max);
+            *band = to_db(magnitude * normalize).max(*band - fall);
+            low = high;
+        }
+    }
+}
+
+fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.log10()).max(Analyzer::FLOOR_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tap_keeps_the_latest_frames() {
+        let tap = AudioTap::new(8);
+        let mut out = vec![];
+        assert_eq!(tap.latest(2, &mut out), (2, 44_100));
+        assert_eq!(out, vec![0.0; 4]);
+
+        tap.write(&[1.0, -1.0], 2, 48_000);
+        assert_eq!(tap.latest(2, &mut out), (2, 48_000));
+        assert_eq!(out, vec![0.0, 0.0, 1.0, -1.0]);
+
+        let samples = (0..10).map(|i| i as f32).collect::<Vec<_>>();
+        tap.write(&samples, 2, 48_000);
+        tap.latest(3, &mut out);
+        assert_eq!(out, vec![4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        // never more than the ring holds
+        tap.latest(100, &mut out);
+        assert_eq!(out, vec![2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+    }
+
+    #[test]
+    fn test_sine_shows_up_in_its_band_and_meters() {
+        let rate = 48_000;
+        let tap = AudioTap::new(1 << 14);
+        // full scale on the left, silence on the right
+        let samples = (0..4096)
+            .flat_map(|i| [(2.0 * PI * 1000.0 * i as f32 / rate as f32).sin(), 0.0])
+            .collect::<Vec<_>>();
+        tap.write(&samples, 2, rate);
+
+        let mut analyzer = Analyzer::new(24);
+        analyzer.update(&tap, Duration::from_millis(20));
+        let meters = analyzer.meters();
+        assert!(meters[0].peak.abs() < 0.1 && meters[0].held == meters[0].peak);
+        assert!((meters[0].rms + 3.0).abs() < 0.1);
+        assert_eq!(meters[1].peak, Analyzer::FLOOR_DB);
+
+        let spectrum = analyzer.spectrum();
+        let loudest = (0..spectrum.len())
+            .max_by(|&a, &b| spectrum[a].total_cmp(&spectrum[b]))
+            .unwrap();
+        // 30Hz to 16kHz in 24 bands puts 1kHz in the 14th. it's mixed down
+        // with a silent channel, so it's 6dB down.
+        assert_eq!(loudest, 13);
+        assert!((spectrum[loudest] + 6.0).abs() < 1.0);
+        assert!(spectrum[2] < -60.0 && spectrum[23] < -60.0);
+
+        // the peak holds for a while once it's gone, then falls
+        tap.write(&vec![0.0; 1 << 14], 2, rate);
+        analyzer.update(&tap, Duration::from_secs(1));
+        assert_eq!(analyzer.meters()[0].peak, Analyzer::FLOOR_DB);
+        assert!(analyzer.meters()[0].held.abs() < 0.1);
+        analyzer.update(&tap, Duration::from_secs(1));
+        assert!((analyzer.meters()[0].held + 36.0).abs() < 0.2);
+    }
+}
@@ -1,13 +1,14 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use clap::Parser;
-use crossbeam::channel;
 use futures::future;
 use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite_migration::{Migrations, M};
+use tokio::sync::{mpsc, Mutex};
 use tokio::task;
 use tokio_rusqlite::Connection as AsyncConnection;
 use tracing_unwrap::*;
@@ -16,7 +17,10 @@ use walkdir::WalkDir;
 use wigglyair::{
     self, configuration,
     database::{Database, Kind},
+    files,
+    loudness::{Loudness, Meter},
     metadata::{self, Track},
+    waveform::{Waveform, WaveformBuilder},
 };
 
 #[derive(Parser, Debug)]
@@ -28,6 +32,20 @@ struct Cli {
     #[clap(long, help = "Filter files by pattern")]
     filter: Option<String>,
 
+    #[clap(
+        long,
+        help = "Decode each file to measure its loudness. Much slower than reading tags",
+        default_value_t = false
+    )]
+    loudness: bool,
+
+    #[clap(
+        long,
+        help = "Decode each file to store an overview of its waveform. Decodes once if measuring loudness too",
+        default_value_t = false
+    )]
+    waveforms: bool,
+
     #[clap(help = "Path to db file")]
     db: String,
 
@@ -42,7 +60,9 @@ enum AnalyzerMessage {
 
 #[derive(Debug)]
 enum WriterMessage {
-    AddTrack(Track),
+    Track(Track),
+    Loudness { path: PathBuf, loudness: Loudness },
+    Waveform { path: PathBuf, waveform: Waveform },
 }
 
 #[tokio::main]
@@ -58,9 +78,20 @@ async fn main() {
         let db = Database::connect(Kind::parse(&db_path)).await;
         db.conn
             .call(|conn| {
-                Migrations::new(vec![M::up(include_str!(
-                    "../../migrations/20230809235427-create-tracks.sql"
-                ))])
+                Migrations::new(vec![
+                    M::up(include_str!(
+                        "../../migrations/20230809235427-create-tracks.sql"
+                    )),
+                    M::up(include_str!(
+                        "../../migrations/20261016120000-add-replay-gain.sql"
+                    )),
+                    M::up(include_str!(
+                        "../../migrations/20261016130000-create-loudness.sql"
+                    )),
+                    M::up(include_str!(
+                        "../../migrations/20261017120000-create-waveforms.sql"
+                    )),
+                ])
                 .to_latest(conn)
                 .unwrap_or_log();
                 Ok(())
@@ -70,27 +101,30 @@ async fn main() {
         db
     };
     let conn = Arc::new(db.conn);
+    let with_loudness = cli.loudness;
+    let with_waveforms = cli.waveforms;
 
-    let (analyzer_tx, analyzer_rx) = channel::unbounded::<AnalyzerMessage>();
-    let (writer_tx, writer_rx) = channel::unbounded::<WriterMessage>();
+    let (analyzer_tx, analyzer_rx) = mpsc::unbounded_channel::<AnalyzerMessage>();
+    let (writer_tx, mut writer_rx) = mpsc::unbounded_channel::<WriterMessage>();
+    // the analyzers take turns at the one receiver
+    let analyzer_rx = Arc::new(Mutex::new(analyzer_rx));
 
     let analyzer_tasks = (0..4).map(|id| {
         let conn = Arc::clone(&conn);
-        let analyzer_rx = analyzer_rx.clone();
+        let analyzer_rx = Arc::clone(&analyzer_rx);
         let writer_tx = writer_tx.clone();
         task::spawn(async move {
             use AnalyzerMessage::AnalyzeFile;
             tracing::info!(id, "Starting analyzer");
 
             loop {
-                let msg_opt = analyzer_rx.recv();
+                let msg_opt = analyzer_rx.lock().await.recv().await;
                 match msg_opt {
-                    Ok(msg) => match msg {
-                        AnalyzeFile(path) => {
-                            analyze_file(id, path, &conn, &writer_tx).await;
-                        }
-                    },
-                    Err(_) => break,
+                    Some(AnalyzeFile(path)) => {
+                        let decode = (with_loudness, with_waveforms);
+                        analyze_file(id, path, decode, &conn, &writer_tx).await;
+                    }
+                    None => break,
                 }
             }
             tracing::info!(id, "Finished analyzer");
@@ -98,10 +132,10 @@ async fn main() {
     });
 
     let conn1 = Arc::clone(&conn);
-    task::spawn(async move {
+    let writer_task = task::spawn(async move {
         tracing::info!(db_path, "Starting writer");
         let query = "
-            INSERT INTO tracks (
+            INSERT OR REPLACE INTO tracks (
                 path,
                 last_modified,
                 file_size,
@@ -114,13 +148,17 @@ async fn main() {
                 artist,
                 title,
                 album_artist,
-                track
+                track,
+                track_gain,
+                track_peak,
+                album_gain,
+                album_peak
             )
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
         ";
-        while let Ok(msg) = writer_rx.recv() {
+        while let Some(msg) = writer_rx.recv().await {
             match msg {
-                WriterMessage::AddTrack(track) => {
+                WriterMessage::Track(track) => {
                     conn1
                         .call(move |conn| {
                             tracing::debug!(?track, "Adding track");
@@ -143,6 +181,10 @@ async fn main() {
                                 track.title,
                                 track.album_artist,
                                 track.track,
+                                track.replay_gain.track_gain,
+                                track.replay_gain.track_peak,
+                                track.replay_gain.album_gain,
+                                track.replay_gain.album_peak,
                             ])
                             .expect_or_log("Failed to execute statement");
                             Ok(())
@@ -150,21 +192,84 @@ async fn main() {
                         .await
                         .expect_or_log("Failed to add track");
                 }
+                WriterMessage::Loudness { path, loudness } => {
+                    conn1
+                        .call(move |conn| {
+                            tracing::debug!(?path, ?loudness, "Adding loudness");
+
+                            let mut stmt = conn
+                                .prepare_cached(
+                                    "
+                                    INSERT OR REPLACE INTO track_loudness (
+                                        path,
+                                        integrated_lufs,
+                                        loudness_range,
+                                        true_peak
+                                    )
+                                    VALUES (?1, ?2, ?3, ?4)
+                                    ",
+                                )
+                                .expect_or_log("Failed to prepare statement");
+
+                            stmt.execute(params![
+                                path.to_str().unwrap_or_log(),
+                                loudness.integrated,
+                                loudness.range,
+                                loudness.true_peak,
+                            ])
+                            .expect_or_log("Failed to execute statement");
+                            Ok(())
+                        })
+                        .await
+                        .expect_or_log("Failed to add loudness");
+                }
+                WriterMessage::Waveform { path, waveform } => {
+                    conn1
+                        .call(move |conn| {
+                            tracing::debug!(?path, buckets = waveform.len(), "Adding waveform");
+
+                            let mut stmt = conn
+                                .prepare_cached(
+                                    "
+                                    INSERT OR REPLACE INTO track_waveforms (path, buckets)
+                                    VALUES (?1, ?2)
+                                    ",
+                                )
+                                .expect_or_log("Failed to prepare statement");
+
+                            stmt.execute(params![
+                                path.to_str().unwrap_or_log(),
+                                waveform.to_bytes()
+                            ])
+                            .expect_or_log("Failed to execute statement");
+                            Ok(())
+                        })
+                        .await
+                        .expect_or_log("Failed to add waveform");
+                }
             }
         }
         tracing::info!(db_path, "Finished writer");
     });
 
-    let walker_task = tokio::spawn(async move {
-        let root = cli.root;
-
-        tracing::info!(%root, "Starting walker");
+    // walking is all blocking file system calls
+    let walker_task = task::spawn_blocking(move || {
+        // the player looks tracks up by their canonical paths, and walking
+        // from a canonical root gives us those
+        let root = match Path::new(&cli.root).canonicalize() {
+            Ok(root) => root,
+            Err(err) => {
+                tracing::error!(%err, root = cli.root, "Failed to find root");
+                return;
+            }
+        };
+        tracing::info!(root = %root.display(), "Starting walker");
 
         let path_filter = path_filter_from_opt(cli.filter);
         let paths = WalkDir::new(&root)
             .into_iter()
             .filter_map(Result::ok)
-            .filter(is_flac)
+            .filter(is_audio)
             .filter(path_filter)
             .map(DirEntry::into_path)
             .take(cli.limit.unwrap_or(usize::MAX));
@@ -175,18 +280,28 @@ async fn main() {
                 .expect_or_log("Failed to send path for analysis");
         }
 
-        tracing::info!(%root, "Finished walking");
+        tracing::info!(root = %root.display(), "Finished walking");
     });
 
-    // join everything to make sure we don't drop the channels before they're done
-    // don't join the writer, though—we want that to drop once we're done analyzing
-    // otherwise we'll hang on to the writer too long.
-    let mut all_tasks = analyzer_tasks.collect::<Vec<_>>();
-    all_tasks.push(walker_task);
-
-    for result in future::join_all(all_tasks).await {
+    // join everything to make sure we don't drop the channels before they're
+    // done. the writer goes last, once the analyzers have dropped their
+    // senders and it's written everything they sent.
+    let analyzer_tasks = analyzer_tasks.collect::<Vec<_>>();
+    walker_task.await.expect_or_log("Failed to join walker");
+    for result in future::join_all(analyzer_tasks).await {
         result.expect_or_log("Failed to join task");
     }
+    drop(writer_tx);
+    writer_task.await.expect_or_log("Failed to join writer");
+
+    if with_loudness {
+        // album loudness needs every track written first
+        let albums = conn
+            .call(|conn| update_album_loudness(conn))
+            .await
+            .expect_or_log("Failed to update album loudness");
+        tracing::info!(albums, "Updated album loudness");
+    }
 }
 
 fn path_filter_from_opt(filter: Option<String>) -> Box<dyn Fn(&DirEntry) -> bool + Send> {
@@ -218,11 +333,14 @@ fn fuzzy_match_string(needle: &str, haystack: &str) -> bool {
     true
 }
 
+/// Read a file's tags, and decode it if `with_loudness` or `with_waveforms`
+/// needs the audio
 async fn analyze_file(
     id: u32,
     path: PathBuf,
+    (with_loudness, with_waveforms): (bool, bool),
     conn: &AsyncConnection,
-    tx: &channel::Sender<WriterMessage>,
+    tx: &mpsc::UnboundedSender<WriterMessage>,
 ) {
     tracing::debug!(id, path = %path.display(), "Analyzing file");
 
@@ -239,9 +357,11 @@ async fn analyze_file(
 
     let is_up_to_date: bool = {
         let path = Arc::clone(&path);
-        conn.call(move |conn| check_path_is_up_to_date(&path, &last_modified, conn))
-            .await
-            .unwrap_or_log()
+        conn.call(move |conn| {
+            check_path_is_up_to_date(&path, &last_modified, (with_loudness, with_waveforms), conn)
+        })
+        .await
+        .unwrap_or_log()
     };
 
     if is_up_to_date {
@@ -263,14 +383,78 @@ async fn analyze_file(
     };
 
     tracing::debug!(id, ?meta, path = %path.display(), "Got metadata");
-    if let Some(error) = tx.send(WriterMessage::AddTrack(meta)).err() {
+    let total_frames = meta.total_samples;
+    if let Some(error) = tx.send(WriterMessage::Track(meta)).err() {
         tracing::error!(id, %error, path = %path.display(), "Failed to send metadata");
     }
+
+    if !with_loudness && !with_waveforms {
+        return;
+    }
+
+    // decoding keeps a core busy for a while, so it gets a thread of its own
+    // rather than holding up a tokio worker
+    let decoded = {
+        let path = Arc::clone(&path);
+        task::spawn_blocking(move || {
+            decode_file(&path, total_frames, (with_loudness, with_waveforms))
+        })
+        .await
+        .expect_or_log("Failed to join decoder")
+    };
+    let (loudness, waveform) = match decoded {
+        Ok(decoded) => decoded,
+        Err(err) => {
+            tracing::error!(id, %err, path = %path.display(), "Failed to decode");
+            return;
+        }
+    };
+
+    if let Some(loudness) = loudness {
+        tracing::debug!(id, ?loudness, path = %path.display(), "Got loudness");
+        let path = path.to_path_buf();
+        if let Some(error) = tx.send(WriterMessage::Loudness { path, loudness }).err() {
+            tracing::error!(id, %error, "Failed to send loudness");
+        }
+    }
+
+    if let Some(waveform) = waveform {
+        tracing::debug!(id, path = %path.display(), "Got waveform");
+        let path = path.to_path_buf();
+        if let Some(error) = tx.send(WriterMessage::Waveform { path, waveform }).err() {
+            tracing::error!(id, %error, "Failed to send waveform");
+        }
+    }
+}
+
+/// Decode a file `total_frames` long once for whichever of its loudness and
+/// waveform are wanted
+fn decode_file(
+    path: &Path,
+    total_frames: u64,
+    (with_loudness, with_waveforms): (bool, bool),
+) -> Result<(Option<Loudness>, Option<Waveform>), metadata::DecodeError> {
+    let mut meter: Option<Meter> = None;
+    let mut waveform =
+        with_waveforms.then(|| WaveformBuilder::new(total_frames, Waveform::BUCKETS));
+    metadata::decode(path, |samples, spec| {
+        if with_loudness {
+            meter
+                .get_or_insert_with(|| Meter::new(spec.rate, spec.channels))
+                .process(samples);
+        }
+        if let Some(waveform) = &mut waveform {
+            waveform.process(samples, spec.channels.count());
+        }
+    })?;
+    let loudness = with_loudness.then(|| meter.map_or_else(Loudness::default, Meter::finish));
+    Ok((loudness, waveform.map(WaveformBuilder::finish)))
 }
 
 fn check_path_is_up_to_date(
     path: &Path,
     last_modified: &String,
+    (with_loudness, with_waveforms): (bool, bool),
     conn: &Connection,
 ) -> Result<bool, rusqlite::Error> {
     let path = path.to_string_lossy();
@@ -281,13 +465,63 @@ fn check_path_is_up_to_date(
         WHERE 1=1
             AND `path` = ?1
             AND `last_modified` = ?2
+            AND (
+                NOT ?3
+                OR EXISTS (SELECT 1 FROM `track_loudness` l WHERE l.`path` = `tracks`.`path`)
+            )
+            AND (
+                NOT ?4
+                OR EXISTS (SELECT 1 FROM `track_waveforms` w WHERE w.`path` = `tracks`.`path`)
+            )
             ",
     )?;
-    let mut rows = stmt.query(params![path, last_modified])?;
+    let mut rows = stmt.query(params![path, last_modified, with_loudness, with_waveforms])?;
     let n: i64 = rows.next()?.unwrap_or_log().get(0)?;
     Ok(n > 0)
 }
 
-fn is_flac(e: &walkdir::DirEntry) -> bool {
-    e.file_type().is_file() && e.path().extension().unwrap_or_default() == "flac"
+/// Combine the loudness of every album's tracks. Returns how many albums
+/// there are.
+fn update_album_loudness(conn: &Connection) -> Result<usize, rusqlite::Error> {
+    let mut albums: HashMap<(String, String), Vec<(Loudness, f64)>> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "
+        SELECT t.`album_artist`, t.`album`, t.`length_secs`, l.`integrated_lufs`, l.`true_peak`
+        FROM `tracks` t
+        JOIN `track_loudness` l ON l.`path` = t.`path`
+        ",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let loudness = Loudness {
+            integrated: row.get(3)?,
+            range: None,
+            true_peak: row.get(4)?,
+        };
+        albums
+            .entry((row.get(0)?, row.get(1)?))
+            .or_default()
+            .push((loudness, row.get(2)?));
+    }
+
+    let mut stmt = conn.prepare(
+        "
+        INSERT OR REPLACE INTO album_loudness (album_artist, album, integrated_lufs, true_peak)
+        VALUES (?1, ?2, ?3, ?4)
+        ",
+    )?;
+    for ((album_artist, album), tracks) in &albums {
+        let loudness = Loudness::combine(tracks);
+        stmt.execute(params![
+            album_artist,
+            album,
+            loudness.integrated,
+            loudness.true_peak
+        ])?;
+    }
+    Ok(albums.len())
+}
+
+fn is_audio(e: &walkdir::DirEntry) -> bool {
+    e.file_type().is_file() && files::has_supported_extension(e.path())
 }
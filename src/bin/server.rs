@@ -1,7 +1,12 @@
 use std::sync::Arc;
 
 use axum::{routing::get, Router};
-use wigglyair::{configuration, routes, types::AppState};
+use wigglyair::{
+    configuration,
+    database::{Database, Kind},
+    routes,
+    types::AppState,
+};
 
 #[tokio::main]
 async fn main() {
@@ -10,12 +15,17 @@ async fn main() {
         configuration::from_file("configuration.yml").expect("Failed to read configuration.");
     let addr = settings.server.addr();
 
-    let state = AppState { settings };
+    let library = match &settings.music.library {
+        Some(path) => Some(Database::connect(Kind::parse(path)).await.conn),
+        None => None,
+    };
+    let state = AppState { settings, library };
 
     // build our application with a route
     let app = Router::new()
         .route("/", get(routes::root))
         .route("/debug", get(routes::debug))
+        .route("/waveform", get(routes::waveform))
         .with_state(Arc::new(state));
 
     tracing::info!("listening on {addr}");
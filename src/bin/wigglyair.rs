@@ -1,59 +1,478 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    io::{self, Stdout},
-    sync::{atomic::Ordering, Arc},
-    time::Duration,
+    fs::File,
+    io::{self, BufWriter, Stdout},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, MouseButton,
+        MouseEventKind,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{prelude::*, widgets::*};
+use rusqlite::{Connection, OpenFlags};
 use wigglyair::{
-    configuration,
-    types::{AudioParams, PlayState, Player, Track, TrackList},
+    analyzer::Analyzer,
+    configuration::{self, EqualizerSettings},
+    cue,
+    equalizer::Preset,
+    metadata::Probe,
+    resample::{RateConversion, ResampleQuality},
+    sink::{FlacSink, NullSink, PcmSink, SampleFormat, WavSink},
+    speed::{Speed, SpeedMode},
+    types::{
+        AudioParams, PlayState, Player, PlayerCommand, PlayerEvent, PlayerHandle, RepeatMode,
+        ReplayGainMode, ShuffleMode, SkippedFile, Timecode, Track, TrackList, TrackListError,
+        VolumeCurve,
+    },
+    waveform::Waveform,
 };
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    play: PlayArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Mix the queue into a single file as fast as it can be decoded, along
+    /// with a cue sheet marking where each track starts
+    Render(RenderArgs),
+}
+
+// how the queue is put together, whether it's played or rendered
+#[derive(Args)]
+struct MixArgs {
+    #[clap(
+        short,
+        long,
+        help = "Start at a specific time code, e.g. 1:23:45 or 83:12"
+    )]
+    time: Option<Timecode>,
+
+    #[clap(long, value_enum, help = "Shuffle tracks or whole albums", default_value_t = ShuffleMode::Off)]
+    shuffle: ShuffleMode,
+
+    #[clap(long, value_enum, help = "Level tracks or albums using their ReplayGain tags", default_value_t = ReplayGainMode::Off)]
+    replay_gain: ReplayGainMode,
+
+    #[clap(
+        long,
+        help = "Extra gain in dB for tracks with ReplayGain tags",
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    preamp: f32,
+
+    #[clap(
+        long,
+        help = "Seconds to crossfade between tracks from different albums. 0 plays everything gaplessly",
+        default_value = "0",
+        value_parser = seconds
+    )]
+    crossfade: Duration,
+
+    #[clap(long, value_enum, help = "How carefully to resample tracks at other sample rates", default_value_t = ResampleQuality::Balanced)]
+    resample_quality: ResampleQuality,
+
+    #[clap(
+        long,
+        help = "Play multichannel tracks without downmixing them to stereo. The output has to support that many channels",
+        default_value_t = false
+    )]
+    passthrough_channels: bool,
+
+    #[clap(
+        long,
+        help = "Configuration file to read equalizer presets from",
+        default_value = "configuration.yml"
+    )]
+    config: PathBuf,
+
+    #[clap(
+        long,
+        help = "Equalizer preset to start with, or `off`. Defaults to the one the configuration file picks"
+    )]
+    eq: Option<String>,
+
+    #[clap(help = "Files or directories to play")]
+    files: Vec<String>,
+}
+
+/// Parse a number of seconds for flags that take a duration
+fn seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f32 = value
+        .parse()
+        .map_err(|_| format!("`{value}` isn't a number of seconds"))?;
+    Duration::try_from_secs_f32(seconds)
+        .map_err(|_| format!("`{value}` has to be a finite number of seconds, 0 or more"))
+}
+
+impl MixArgs {
+    /// Equalizer presets from the configuration file, if there is one, and
+    /// the one to start with
+    fn equalizer(&self) -> Result<(Vec<Preset>, Option<usize>), String> {
+        let settings = if self.config.exists() {
+            let file = self.config.to_string_lossy();
+            configuration::equalizer_from_file(&file)
+                .map_err(|error| format!("can't read equalizer presets from {file}: {error}"))?
+        } else {
+            EqualizerSettings::default()
+        };
+        let selected = match self.eq.as_ref().or(settings.preset.as_ref()) {
+            None => None,
+            Some(name) if name == "off" => None,
+            Some(name) => {
+                let index = settings.find(name).ok_or_else(|| {
+                    let names = settings.presets.iter().map(|p| p.name.as_str());
+                    format!(
+                        "no equalizer preset called `{name}`. There's {}",
+                        names.chain(["off"]).collect::<Vec<_>>().join(", ")
+                    )
+                })?;
+                Some(index)
+            }
+        };
+        Ok((settings.presets, selected))
+    }
+
+    /// Set up `player` the way these arguments ask for
+    fn apply(&self, mut player: Player) -> Result<Player, String> {
+        let (presets, preset) = self.equalizer()?;
+        player = player
+            .with_preamp(self.preamp)
+            .with_crossfade(self.crossfade)
+            .with_equalizer(presets);
+        if self.passthrough_channels {
+            player = player.with_channel_passthrough();
+        }
+        player.set_shuffle(self.shuffle);
+        player.set_replay_gain(self.replay_gain);
+        if let Some(time) = self.time {
+            tracing::info!(?time, "Starting at time code");
+            player.seek_to(time.as_duration());
+        }
+        player.set_equalizer(preset);
+        Ok(player)
+    }
+}
+
+#[derive(Args)]
+struct PlayArgs {
     #[clap(long, help = "Start paused", default_value_t = false)]
     paused: bool,
 
-    #[clap(short, long, help = "Start at a specific time code")]
-    time: Option<String>,
+    #[clap(long, value_enum, help = "Repeat a track or the whole list", default_value_t = RepeatMode::Off)]
+    repeat: RepeatMode,
 
-    #[clap(help = "Files to play. Must be flac")]
-    files: Vec<String>,
+    #[clap(
+        long,
+        help = "Gain in dB at the lowest volume step above silence",
+        default_value_t = -60.0,
+        allow_negative_numbers = true
+    )]
+    min_db: f32,
+
+    #[clap(
+        long,
+        help = "Gain in dB at full volume. Below 0 caps the volume; above 0 boosts it",
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    max_db: f32,
+
+    #[clap(
+        long,
+        help = "Seconds to fade out when pausing and back in when resuming",
+        default_value = "0.03",
+        value_parser = seconds
+    )]
+    pause_fade: Duration,
+
+    #[clap(
+        long,
+        help = "Play each track at its own sample rate, reopening the audio device as needed, instead of resampling",
+        default_value_t = false
+    )]
+    native_rate: bool,
+
+    #[clap(long, value_enum, help = "Where to play to", default_value_t = PlayOutput::Device)]
+    output: PlayOutput,
+
+    #[clap(
+        long,
+        help = "Play into a WAV or FLAC file instead, going by the extension",
+        conflicts_with = "output"
+    )]
+    output_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Start with the spectrum and level meters showing. `v` toggles them",
+        default_value_t = false
+    )]
+    visualizer: bool,
+
+    #[clap(
+        long,
+        help = "How fast to play, from 0.5 to 2. `[` and `]` change it while playing, and `=` puts it back",
+        default_value_t = Speed::NORMAL
+    )]
+    speed: Speed,
+
+    #[clap(long, value_enum, help = "Whether playing faster or slower changes the pitch", default_value_t = SpeedMode::KeepPitch)]
+    speed_mode: SpeedMode,
+
+    #[clap(
+        long,
+        help = "Library database written by `build-db --waveforms`, for drawing each track's waveform as the seek bar"
+    )]
+    library: Option<PathBuf>,
+
+    #[command(flatten)]
+    mix: MixArgs,
+}
+
+/// Where `play` sends its audio, other than to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PlayOutput {
+    /// The default audio device
+    Device,
+    /// Nowhere, at the speed it would play
+    Null,
+    /// Raw 32-bit float PCM on stdout
+    #[value(name = "-")]
+    Stdout,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+#[derive(Args)]
+struct RenderArgs {
+    #[clap(
+        short,
+        long,
+        help = "WAV or FLAC file to write, going by the extension. The cue sheet goes next to it"
+    )]
+    output: PathBuf,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "How to store samples. Float for WAV and int24 for FLAC, which can't store floats, unless this says otherwise"
+    )]
+    format: Option<SampleFormat>,
+
+    #[command(flatten)]
+    mix: MixArgs,
+}
+
+fn main() -> ExitCode {
     let _guard = configuration::setup_tracing_async("wigglyair".into());
 
     let cli = Cli::parse();
-    let tracks: TrackList = TrackList::unsafe_from_files(&cli.files);
+    let result = match cli.command {
+        Some(Command::Render(args)) => render(&args),
+        None => play(&cli.play),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            tracing::error!(%error, "Exiting");
+            eprintln!("wigglyair: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Read the tracks to play, or explain what's wrong with them
+fn load_tracks(files: &[String]) -> Result<(TrackList, Vec<SkippedFile>), String> {
+    TrackList::try_from_files(files).map_err(|error| match error {
+        TrackListError::Empty if files.is_empty() => {
+            "nothing to play. Pass some audio files, or directories with audio files in them"
+                .to_owned()
+        }
+        TrackListError::Empty => format!("no music found in {}", files.join(", ")),
+        TrackListError::Unsupported { ref paths } => {
+            let examples = paths.iter().take(3).map(|p| p.display().to_string());
+            format!("{error}, e.g. {}", examples.collect::<Vec<_>>().join(", "))
+        }
+        TrackListError::Unreadable { ref files } => {
+            let reasons = files
+                .iter()
+                .map(|file| format!("\n  {}: {}", file.path.display(), file.reason));
+            format!("{error}:{}", reasons.collect::<String>())
+        }
+        TrackListError::IncompatibleParams { .. } => error.to_string(),
+    })
+}
+
+fn play(cli: &PlayArgs) -> Result<(), Box<dyn Error>> {
+    let (tracks, skipped) = load_tracks(&cli.mix.files)?;
     let params: AudioParams = tracks.audio_params();
     let playing = !cli.paused;
 
     tracing::info!("Playing {:?}", tracks);
     tracing::info!("Audio params {:?}", params);
 
-    let mut terminal = setup_terminal()?;
+    let curve = VolumeCurve::new(cli.min_db, cli.max_db)
+        .map_err(|err| format!("Invalid volume range: {err:?}"))?;
     let state = PlayState::with_state(playing);
-    let player = Player::with_state(tracks, state);
-    run_tui(&mut terminal, player)?;
+    let conversion = if cli.native_rate {
+        RateConversion::Native
+    } else {
+        RateConversion::Resample(cli.mix.resample_quality)
+    };
+    let mut player = Player::with_state(tracks, state)
+        .with_skipped_files(skipped)
+        .with_volume_curve(curve)
+        .with_rate_conversion(conversion)
+        .with_pause_fade(cli.pause_fade);
+    let headless = cli.output == PlayOutput::Stdout;
+    player = match (&cli.output_file, cli.output) {
+        (Some(path), _) => with_file_sink(player, path, None)?,
+        (None, PlayOutput::Device) => player,
+        (None, PlayOutput::Null) => player.with_sink(NullSink::realtime()),
+        (None, PlayOutput::Stdout) => player.with_sink(PcmSink::stdout()),
+    };
+    player.set_repeat(cli.repeat);
+    player.set_speed(cli.speed);
+    player.set_speed_mode(cli.speed_mode);
+    let player = cli.mix.apply(player)?;
+    let handle = player.handle();
+
+    // stdout is taken, so there's no TUI. play everything once and stop.
+    if headless {
+        return play_through(player, &handle);
+    }
+
+    let library = match &cli.library {
+        Some(path) => Some(
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|error| format!("can't open the library {}: {error}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let mut terminal = setup_terminal()?;
+    player.start();
+    run_tui(&mut terminal, &handle, cli.visualizer, library.as_ref())?;
     restore_terminal(&mut terminal)?;
     Ok(())
 }
 
+/// Play the whole queue once and stop, or stop early if the output fails
+fn play_through(player: Player, handle: &PlayerHandle) -> Result<(), Box<dyn Error>> {
+    let events = handle.subscribe();
+    let player = player.start();
+    let mut result = Ok(());
+    while let Ok(event) = events.recv() {
+        match event {
+            PlayerEvent::PlaylistFinished => break,
+            PlayerEvent::OutputFailed { error } => {
+                result = Err(error.into());
+                break;
+            }
+            _ => {}
+        }
+    }
+    handle.send(PlayerCommand::Stop);
+    player.join().map_err(|_| "Player thread panicked")?;
+    result
+}
+
+/// Play into a WAV or FLAC file, going by `path`'s extension, with samples
+/// stored as `format` or whatever suits the file best
+fn with_file_sink(
+    player: Player,
+    path: &Path,
+    format: Option<SampleFormat>,
+) -> Result<Player, String> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("wav") => {
+            let format = format.unwrap_or(SampleFormat::Float);
+            Ok(player.with_sink(WavSink::new(path).with_format(format)))
+        }
+        Some("flac") => match format.unwrap_or(SampleFormat::Int24) {
+            SampleFormat::Float => Err(
+                "FLAC can't store float samples; use `--format int16` or `--format int24`".into(),
+            ),
+            format => Ok(player.with_sink(FlacSink::new(path).with_format(format))),
+        },
+        _ => Err(format!(
+            "can't tell what to write to {}; it needs a .wav or .flac extension",
+            path.display()
+        )),
+    }
+}
+
+fn render(cli: &RenderArgs) -> Result<(), Box<dyn Error>> {
+    let (tracks, skipped) = load_tracks(&cli.mix.files)?;
+    for file in &skipped {
+        eprintln!("Skipping {}: {}", file.path.display(), file.reason);
+    }
+    tracing::info!("Rendering {:?}", tracks);
+
+    // native rate would need the file to change rate partway through, and
+    // there's nobody to hear a pause fade
+    let player = Player::new(tracks)
+        .with_skipped_files(skipped.clone())
+        .with_rate_conversion(RateConversion::Resample(cli.mix.resample_quality))
+        .with_pause_fade(Duration::ZERO);
+    let player = with_file_sink(player, &cli.output, cli.format)?;
+    let player = cli.mix.apply(player)?;
+    let handle = player.handle();
+
+    // shuffling and seeking have already happened, so this is what's rendered
+    let tracks = handle.track_list();
+    let from = handle.current_sample();
+
+    play_through(player, &handle)?;
+
+    let starts = cue::track_starts(&tracks, from, cli.mix.crossfade);
+    let given_up = handle.skipped_files().len() - skipped.len();
+    if given_up > 0 {
+        eprintln!(
+            "Gave up on {given_up} files partway through; the cue sheet doesn't account for them"
+        );
+    }
+    let cue_path = cli.output.with_extension("cue");
+    let audio_file = cli
+        .output
+        .file_name()
+        .map_or_else(Default::default, |name| name.to_string_lossy());
+    let mut out = BufWriter::new(File::create(&cue_path)?);
+    cue::write_cue_sheet(&mut out, &audio_file, &tracks, &starts)?;
+
+    // crossfades make the mix shorter than the tracks in it
+    let rendered = Probe::from_path(&cli.output)?;
+    println!(
+        "Rendered {} tracks ({}) to {} and {}",
+        starts.len(),
+        samples_to_duration_string(rendered.sample_rate, rendered.total_samples),
+        cli.output.display(),
+        cue_path.display()
+    );
+    Ok(())
+}
+
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>, Box<dyn Error>> {
     let mut stdout = io::stdout();
     enable_raw_mode()?;
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     Ok(Terminal::new(CrosstermBackend::new(stdout))?)
 }
 
@@ -61,41 +480,74 @@ fn restore_terminal(
     terminal: &mut Terminal<CrosstermBackend<Stdout>>,
 ) -> Result<(), Box<dyn Error>> {
     disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen,)?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
     Ok(terminal.show_cursor()?)
 }
 
 fn run_tui(
     terminal: &mut Terminal<CrosstermBackend<Stdout>>,
-    player: Player,
+    handle: &PlayerHandle,
+    mut show_visualizer: bool,
+    library: Option<&Connection>,
 ) -> Result<(), Box<dyn Error>> {
-    let tracks = Arc::clone(&player.track_list);
-    let current_sample = Arc::clone(&player.current_sample);
-    let volume = Arc::clone(&player.volume);
-    let total_samples = tracks.total_samples;
-    let current_track = Arc::clone(&player.current_track);
-    let play_state = Arc::clone(&player.state);
-    let sample_rate = player.audio_params.sample_rate;
-
-    // safe initial value: there are fewer than 18 quintillion
-    // tracks in the known world
-    let mut last_track = usize::MAX;
+    let sample_rate = handle.audio_params().sample_rate;
 
-    player.start();
+    // every track we've looked up, including the ones the library hasn't got
+    let mut waveforms: HashMap<PathBuf, Option<Waveform>> = HashMap::new();
+    // where the seek bar was last drawn, and the samples it covers
+    let mut seek_bar = (Rect::default(), (0, 0));
+
+    let mut analyzer = Analyzer::new(SPECTRUM_BANDS);
+    let mut last_analyzed = Instant::now();
+
+    let events = handle.subscribe();
+
+    // track highlighted in the track list with `j`/`k`, played with `Enter`
+    let mut selected_track: Option<usize> = None;
 
     loop {
-        let current_sample = current_sample.get();
+        // the queue can change while we're playing, so grab a fresh copy every frame
+        let tracks = handle.track_list();
+        let tracks = tracks.as_ref();
+        let total_samples = tracks.total_samples;
+        let last_index = tracks.tracks.len() - 1;
+        let current_sample = handle.current_sample();
 
         #[allow(clippy::cast_precision_loss)]
         let mut ratio = current_sample as f64 / total_samples as f64;
 
-        let is_paused = play_state.is_paused();
-        let current_track = current_track.load(Ordering::SeqCst);
+        let is_paused = handle.is_paused();
+        let volume = (handle.volume(), handle.volume_db());
+        let current_track = handle.current_track().min(last_index);
+        let modes = (handle.repeat(), handle.shuffle(), handle.replay_gain());
+        let presets = handle.equalizer_presets();
+        let equalizer = handle.equalizer();
+        let speed = (handle.speed(), handle.speed_mode());
         let track = tracks.get_track(current_track);
+        selected_track = selected_track.map(|i| i.min(last_index));
+        let skipped = handle.skipped_files();
 
-        if current_track != last_track {
-            tracing::info!(?track, "Playing next track");
-            last_track = current_track;
+        for event in events.try_iter() {
+            match event {
+                PlayerEvent::TrackStarted { index } => {
+                    tracing::info!(track = ?tracks.tracks.get(index), "Playing next track");
+                }
+                PlayerEvent::PlaylistFinished => {
+                    tracing::info!("Finished playing track list");
+                }
+                PlayerEvent::FileSkipped { path, reason } => {
+                    tracing::warn!(?path, reason, "Skipped file");
+                }
+                PlayerEvent::BufferUnderrun { missing_samples } => {
+                    let buffered = handle.buffered();
+                    tracing::warn!(missing_samples, ?buffered, "Buffer underrun");
+                }
+                _ => {}
+            }
         }
 
         if ratio > 1.0 {
@@ -108,42 +560,213 @@ fn run_tui(
             ratio = ratio.clamp(0.0, 1.0);
         }
 
+        // files that never made it into the queue get a list of their own
+        let left_out = skipped
+            .iter()
+            .filter(|file| !tracks.tracks.iter().any(|t| t.path == file.path))
+            .collect::<Vec<_>>();
+
+        if show_visualizer {
+            analyzer.update(handle.tap(), last_analyzed.elapsed());
+            last_analyzed = Instant::now();
+        }
+
+        let waveform = library.and_then(|library| {
+            waveforms
+                .entry(track.path.clone())
+                .or_insert_with(|| {
+                    Waveform::load(library, &track.path).unwrap_or_else(|error| {
+                        tracing::warn!(%error, path = ?track.path, "Failed to load waveform");
+                        None
+                    })
+                })
+                .as_ref()
+        });
+        let (track_start, track_end) = tracks.get_bounds(current_track);
+
         terminal.draw(|f| {
-            let chunks = main_layout_chunks(f);
-            let volume = build_volume_gauge(is_paused, &volume);
-            let table = build_track_list(&tracks, current_track, is_paused);
-            let progress =
-                build_progress_gauge(is_paused, ratio, sample_rate, current_sample, total_samples);
+            let seek_bar_height = if waveform.is_some() { 4 } else { 1 };
+            let chunks = main_layout_chunks(f, left_out.len(), show_visualizer, seek_bar_height);
+            let volume = build_volume_gauge(is_paused, volume);
+            let preset = equalizer.map(|i| presets[i].name.as_str());
+            let table = build_track_list(
+                tracks,
+                current_track,
+                is_paused,
+                modes,
+                preset,
+                speed,
+                &skipped,
+            );
+            let mut table_state = TableState::default()
+                .with_selected(selected_track.map(|i| track_row_index(tracks, i)));
+            let label = format!(
+                "{} / {}",
+                samples_to_duration_string(sample_rate, current_sample),
+                samples_to_duration_string(sample_rate, total_samples),
+            );
 
             f.render_widget(volume, chunks[0]);
-            f.render_widget(table, chunks[1]);
-            f.render_widget(progress, chunks[2]);
+            f.render_stateful_widget(table, chunks[1], &mut table_state);
+            if show_visualizer {
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::DarkGray));
+                let inner = block.inner(chunks[2]);
+                let meters = u16::try_from(analyzer.meters().len()).unwrap_or(u16::MAX);
+                let parts = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(1), Constraint::Length(meters)].as_ref())
+                    .split(inner);
+                f.render_widget(block, chunks[2]);
+                f.render_widget(build_spectrum(&analyzer, parts[0].width), parts[0]);
+                f.render_widget(build_meters(&analyzer, parts[1].width), parts[1]);
+            }
+            if let Some(waveform) = waveform {
+                #[allow(clippy::cast_precision_loss)]
+                let played = current_sample.saturating_sub(track_start) as f64
+                    / track_end.saturating_sub(track_start).max(1) as f64;
+                let label = format!(
+                    "{} / {} · {label}",
+                    samples_to_duration_string(
+                        sample_rate,
+                        current_sample.saturating_sub(track_start)
+                    ),
+                    samples_to_duration_string(sample_rate, track_end - track_start),
+                );
+                let seek = build_waveform_seek_bar(waveform, chunks[3], is_paused, played, label);
+                f.render_widget(seek, chunks[3]);
+                seek_bar = (chunks[3], (track_start, track_end));
+            } else {
+                f.render_widget(build_progress_gauge(is_paused, ratio, label), chunks[3]);
+                seek_bar = (chunks[3], (0, total_samples));
+            }
+            if !left_out.is_empty() {
+                f.render_widget(build_skipped_list(&left_out), chunks[4]);
+            }
         })?;
 
-        if event::poll(Duration::from_millis(200))? {
-            if let Event::Key(key) = event::read()? {
+        // the visualizer needs redrawing a lot more often to look smooth
+        let redraw = if show_visualizer {
+            Duration::from_millis(33)
+        } else {
+            Duration::from_millis(200)
+        };
+        if event::poll(redraw)? {
+            let event = event::read()?;
+            if let Event::Mouse(mouse) = event {
+                let (area, (start, end)) = seek_bar;
+                let on_seek_bar = area.intersects(Rect::new(mouse.column, mouse.row, 1, 1));
+                if mouse.kind == MouseEventKind::Down(MouseButton::Left) && on_seek_bar {
+                    let offset = u64::from(mouse.column - area.x);
+                    let sample = start + (end - start) * offset / u64::from(area.width.max(1));
+                    handle.send(PlayerCommand::SeekTo(samples_to_milliseconds(
+                        sample_rate,
+                        sample,
+                    )));
+                }
+            }
+            if let Event::Key(key) = event {
                 match key.code {
                     KeyCode::Char('c') if is_holding_ctrl(key) => {
                         tracing::info!(reason = "keypress", "Quitting: `Ctrl-C` pressed");
+                        handle.send(PlayerCommand::Stop);
                         break;
                     }
                     KeyCode::Char('q') => {
                         tracing::info!(reason = "keypress", "Quitting: `q` pressed");
+                        handle.send(PlayerCommand::Stop);
                         break;
                     }
                     KeyCode::Char('p') | KeyCode::Char(' ') => {
-                        let was_playing = play_state.toggle();
-                        if was_playing {
-                            tracing::info!(?track, "Pausing");
-                        } else {
+                        if is_paused {
                             tracing::info!(?track, "Playing");
+                        } else {
+                            tracing::info!(?track, "Pausing");
                         }
+                        handle.send(PlayerCommand::TogglePause);
                     }
                     KeyCode::Up => {
-                        volume.up(volume_modifier(key));
+                        handle.send(PlayerCommand::VolumeUp(volume_modifier(key)));
                     }
                     KeyCode::Down => {
-                        volume.down(volume_modifier(key));
+                        handle.send(PlayerCommand::VolumeDown(volume_modifier(key)));
+                    }
+                    KeyCode::Char('n') => {
+                        handle.send(PlayerCommand::NextTrack);
+                    }
+                    KeyCode::Char('b') => {
+                        handle.send(PlayerCommand::PreviousTrack);
+                    }
+                    KeyCode::Char('j') => {
+                        let next =
+                            selected_track.map_or(current_track, |i| (i + 1).min(last_index));
+                        selected_track = Some(next);
+                    }
+                    KeyCode::Char('k') => {
+                        let next = selected_track.map_or(current_track, |i| i.saturating_sub(1));
+                        selected_track = Some(next);
+                    }
+                    KeyCode::Enter => {
+                        if let Some(index) = selected_track.take() {
+                            handle.send(PlayerCommand::SkipTo(index));
+                        }
+                    }
+                    KeyCode::Esc => {
+                        selected_track = None;
+                    }
+                    KeyCode::Char('d') => {
+                        if let Some(index) = selected_track {
+                            handle.send(PlayerCommand::Remove(index));
+                        }
+                    }
+                    KeyCode::Char('J') => {
+                        if let Some(from) = selected_track.filter(|&i| i < last_index) {
+                            let to = from + 1;
+                            handle.send(PlayerCommand::Move { from, to });
+                            selected_track = Some(to);
+                        }
+                    }
+                    KeyCode::Char('K') => {
+                        if let Some(from) = selected_track.filter(|&i| i > 0) {
+                            let to = from - 1;
+                            handle.send(PlayerCommand::Move { from, to });
+                            selected_track = Some(to);
+                        }
+                    }
+                    KeyCode::Char('r') => {
+                        handle.send(PlayerCommand::SetRepeat(modes.0.next()));
+                    }
+                    KeyCode::Char('s') => {
+                        handle.send(PlayerCommand::SetShuffle(modes.1.next()));
+                    }
+                    KeyCode::Char('g') => {
+                        handle.send(PlayerCommand::SetReplayGain(modes.2.next()));
+                    }
+                    KeyCode::Char('e') => {
+                        // each preset in turn, then bypassed
+                        let next = equalizer.map_or(0, |i| i + 1);
+                        let next = (next < presets.len()).then_some(next);
+                        handle.send(PlayerCommand::SetEqualizer(next));
+                    }
+                    KeyCode::Char('[') => {
+                        handle.send(PlayerCommand::SetSpeed(speed.0.slower(SPEED_STEP)));
+                    }
+                    KeyCode::Char(']') => {
+                        handle.send(PlayerCommand::SetSpeed(speed.0.faster(SPEED_STEP)));
+                    }
+                    KeyCode::Char('=') => {
+                        handle.send(PlayerCommand::SetSpeed(Speed::NORMAL));
+                    }
+                    KeyCode::Char('v') => {
+                        show_visualizer = !show_visualizer;
+                        last_analyzed = Instant::now();
+                    }
+                    KeyCode::Right => {
+                        handle.send(PlayerCommand::SeekForward(seek_modifier(key)));
+                    }
+                    KeyCode::Left => {
+                        handle.send(PlayerCommand::SeekBackward(seek_modifier(key)));
                     }
                     other => {
                         tracing::debug!(?other, "Unhandled key event");
@@ -155,53 +778,229 @@ fn run_tui(
     Ok(())
 }
 
-fn build_progress_gauge<'a>(
-    is_paused: bool,
-    ratio: f64,
-    sample_rate: u32,
-    current_sample: u64,
-    total_samples: u64,
-) -> Gauge<'a> {
+fn build_progress_gauge<'a>(is_paused: bool, ratio: f64, label: String) -> Gauge<'a> {
     let color = if is_paused { Color::Red } else { Color::Yellow };
     let gauge = Gauge::default()
         .gauge_style(Style::default().fg(color).bg(Color::Black))
         .ratio(ratio)
-        .label(format!(
-            "{} / {}",
-            samples_to_duration_string(sample_rate, current_sample),
-            samples_to_duration_string(sample_rate, total_samples),
-        ));
+        .label(label);
     gauge
 }
 
-fn build_track_list(tracks: &TrackList, current_track: usize, is_paused: bool) -> Table {
-    let rows = build_rows(tracks, current_track, is_paused);
+/// The current track's waveform filling `area`, lit up as far as it's been
+/// played, with `label` underneath
+fn build_waveform_seek_bar<'a>(
+    waveform: &Waveform,
+    area: Rect,
+    is_paused: bool,
+    played: f64,
+    label: String,
+) -> Paragraph<'a> {
+    const LEVELS: [&str; 9] = [" ", "▁", "▂", "▃", "▄", "▅", "▆", "▇", "█"];
+    let color = if is_paused { Color::Red } else { Color::Yellow };
+    let rows = area.height.saturating_sub(1);
+    let peaks = waveform.peaks(usize::from(area.width));
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let playhead = (played.clamp(0.0, 1.0) * f64::from(area.width)).round() as usize;
+
+    let mut lines = (0..rows)
+        .map(|row| {
+            // eighths of a cell below this row
+            let below = usize::from(rows - row - 1) * 8;
+            let spans = peaks.iter().enumerate().map(|(column, &peak)| {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let height = (peak * f32::from(rows) * 8.0).round().max(1.0) as usize;
+                let style = if column < playhead {
+                    Style::default().fg(color)
+                } else {
+                    Style::default().fg(Color::DarkGray)
+                };
+                Span::styled(LEVELS[height.saturating_sub(below).min(8)], style)
+            });
+            Line::from(spans.collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>();
+    lines.push(Line::from(label).alignment(Alignment::Center));
+    Paragraph::new(lines)
+}
+
+fn build_track_list<'a>(
+    tracks: &'a TrackList,
+    current_track: usize,
+    is_paused: bool,
+    (repeat, shuffle, replay_gain): (RepeatMode, ShuffleMode, ReplayGainMode),
+    preset: Option<&str>,
+    (speed, speed_mode): (Speed, SpeedMode),
+    skipped: &'a [SkippedFile],
+) -> Table<'a> {
+    let rows = build_rows(tracks, current_track, is_paused, skipped);
     let color = if is_paused { Color::Red } else { Color::White };
+    let mut title = Vec::new();
+    match repeat {
+        RepeatMode::Off => {}
+        RepeatMode::One => title.push("repeat one"),
+        RepeatMode::All => title.push("repeat all"),
+    }
+    match shuffle {
+        ShuffleMode::Off => {}
+        ShuffleMode::Tracks => title.push("shuffle tracks"),
+        ShuffleMode::Albums => title.push("shuffle albums"),
+    }
+    match replay_gain {
+        ReplayGainMode::Off => {}
+        ReplayGainMode::Track => title.push("track gain"),
+        ReplayGainMode::Album => title.push("album gain"),
+    }
+    let preset = preset.map(|name| format!("eq: {name}"));
+    title.extend(preset.as_deref());
+    let speed = match speed_mode {
+        _ if speed == Speed::NORMAL => None,
+        SpeedMode::KeepPitch => Some(speed.to_string()),
+        SpeedMode::Varispeed => Some(format!("{speed} varispeed")),
+    };
+    title.extend(speed.as_deref());
     let table = Table::new(rows)
         .block(
             Block::default()
+                .title(title.join(" · "))
                 .borders(Borders::ALL)
                 .border_style(Style::default().fg(color)),
         )
         .style(Style::default().fg(Color::White))
+        .highlight_style(Style::default().bg(Color::DarkGray))
         .widths(&[Constraint::Max(1000), Constraint::Min(16)]);
     table
 }
 
-fn build_volume_gauge(is_paused: bool, volume: &Arc<wigglyair::types::Volume>) -> Gauge {
+fn build_volume_gauge<'a>(is_paused: bool, (volume, db): (u8, Option<f32>)) -> Gauge<'a> {
     let mut style = Style::default().bg(Color::Black).fg(Color::Magenta);
     if is_paused {
         style = style.fg(Color::Red);
     }
-    let value = u16::from(volume.get());
-    let mut gauge = Gauge::default().gauge_style(style).percent(value);
-    if is_paused {
-        gauge = gauge.label("[paused]");
-    }
-    gauge
+    let value = u16::from(volume);
+    let db = db.map_or_else(|| "muted".to_owned(), |db| format!("{db:.1} dB"));
+    let label = if is_paused {
+        format!("[paused] {db}")
+    } else {
+        db
+    };
+    Gauge::default()
+        .gauge_style(style)
+        .percent(value)
+        .label(label)
+}
+
+/// How many bars the spectrum is split into
+const SPECTRUM_BANDS: usize = 32;
+
+/// How much `[` and `]` change the speed by, in percent
+const SPEED_STEP: u16 = 10;
+
+fn build_spectrum<'a>(analyzer: &Analyzer, width: u16) -> BarChart<'a> {
+    let bars = analyzer
+        .spectrum()
+        .iter()
+        .map(|&db| {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let height = ((1.0 - db / Analyzer::FLOOR_DB) * 100.0).round() as u64;
+            Bar::default().value(height).text_value(String::new())
+        })
+        .collect::<Vec<_>>();
+    let bands = u16::try_from(bars.len()).unwrap_or(u16::MAX).max(1);
+    BarChart::default()
+        .data(BarGroup::default().bars(&bars))
+        .max(100)
+        .bar_gap(1)
+        .bar_width(((width + 1) / bands).saturating_sub(1).max(1))
+        .bar_style(Style::default().fg(Color::Cyan))
 }
 
-fn main_layout_chunks(f: &mut Frame<'_, CrosstermBackend<Stdout>>) -> std::rc::Rc<[Rect]> {
+/// A line for each channel, with RMS filled in solid, peak shaded past that
+/// and the held peak marked
+fn build_meters<'a>(analyzer: &Analyzer, width: u16) -> Paragraph<'a> {
+    let meters = analyzer.meters();
+    let lines = meters
+        .iter()
+        .enumerate()
+        .map(|(channel, meter)| {
+            let name = match (meters.len(), channel) {
+                (2, 0) => "L".to_owned(),
+                (2, 1) => "R".to_owned(),
+                _ => (channel + 1).to_string(),
+            };
+            let readout = format!(" {:>6.1} {:>6.1} dB", meter.rms, meter.held);
+            let cells = usize::from(width).saturating_sub(name.len() + readout.len() + 1);
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let fill = |db: f32| ((1.0 - db / Analyzer::FLOOR_DB) * cells as f32).round() as usize;
+            let (rms, peak) = (fill(meter.rms), fill(meter.peak).max(fill(meter.rms)));
+            let held = fill(meter.held).clamp(1, cells.max(1)) - 1;
+            let held_color = if meter.held >= -0.1 {
+                Color::Red
+            } else {
+                Color::Yellow
+            };
+
+            let bar = (0..cells).map(|cell| {
+                if cell == held && cell >= peak {
+                    Span::styled("▏", Style::default().fg(held_color))
+                } else if cell < rms {
+                    Span::styled("█", Style::default().fg(Color::Green))
+                } else if cell < peak {
+                    Span::styled("▒", Style::default().fg(Color::Green))
+                } else {
+                    Span::raw(" ")
+                }
+            });
+            let mut spans = vec![Span::styled(
+                format!("{name} "),
+                Style::default().fg(Color::White),
+            )];
+            spans.extend(bar);
+            spans.push(Span::styled(readout, Style::default().fg(Color::DarkGray)));
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+    Paragraph::new(lines)
+}
+
+fn build_skipped_list<'a>(files: &[&'a SkippedFile]) -> List<'a> {
+    let items = files
+        .iter()
+        .map(|file| {
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    file.path.to_string_lossy(),
+                    Style::default().fg(Color::White),
+                ),
+                Span::styled(
+                    format!(" ✗ {}", file.reason),
+                    Style::default().fg(Color::Red),
+                ),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    List::new(items).block(
+        Block::default()
+            .title(format!("skipped {} files", files.len()))
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Red)),
+    )
+}
+
+/// Volume, track list, the visualizer if it's showing, the seek bar and, if any
+/// files were left out of the queue, a few lines listing them
+fn main_layout_chunks(
+    f: &mut Frame<'_, CrosstermBackend<Stdout>>,
+    skipped: usize,
+    visualizer: bool,
+    seek_bar: u16,
+) -> std::rc::Rc<[Rect]> {
+    let skipped = if skipped == 0 {
+        0
+    } else {
+        // borders plus up to 5 files
+        u16::try_from(skipped.min(5)).unwrap_or(5) + 2
+    };
     Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
@@ -209,7 +1008,9 @@ fn main_layout_chunks(f: &mut Frame<'_, CrosstermBackend<Stdout>>) -> std::rc::R
             [
                 Constraint::Length(1),
                 Constraint::Min(1),
-                Constraint::Length(1),
+                Constraint::Length(if visualizer { 14 } else { 0 }),
+                Constraint::Length(seek_bar),
+                Constraint::Length(skipped),
             ]
             .as_ref(),
         )
@@ -226,7 +1027,12 @@ pub fn display_track(track: &Track) -> String {
     format!("{:02} {}", track.track, track.title)
 }
 
-fn build_rows(tracks: &TrackList, current_track: usize, is_paused: bool) -> Vec<Row> {
+fn build_rows<'a>(
+    tracks: &'a TrackList,
+    current_track: usize,
+    is_paused: bool,
+    skipped: &'a [SkippedFile],
+) -> Vec<Row<'a>> {
     let list = &tracks.tracks;
     let audio_params = &tracks.audio_params();
     let mut rows = Vec::with_capacity(list.len());
@@ -255,8 +1061,14 @@ fn build_rows(tracks: &TrackList, current_track: usize, is_paused: bool) -> Vec<
             Span::styled(format!("{:02} ", t.track), style)
         };
 
+        let reason = skipped
+            .iter()
+            .find(|file| file.path == t.path)
+            .map(|file| &file.reason);
         let title_span = {
-            let style = if is_current_track {
+            let style = if reason.is_some() {
+                Style::default().fg(Color::DarkGray).crossed_out()
+            } else if is_current_track {
                 let color = if is_paused { Color::Red } else { Color::Green };
                 Style::default().fg(color).bold()
             } else {
@@ -265,7 +1077,12 @@ fn build_rows(tracks: &TrackList, current_track: usize, is_paused: bool) -> Vec<
             Span::styled(&t.title, style)
         };
 
-        let line = Line::from(vec![track_span, title_span]);
+        let mut spans = vec![track_span, title_span];
+        if let Some(reason) = reason {
+            let style = Style::default().fg(Color::Red);
+            spans.push(Span::styled(format!(" ✗ {reason}"), style));
+        }
+        let line = Line::from(spans);
         let track = Cell::from(line);
 
         // time code
@@ -295,6 +1112,17 @@ fn build_rows(tracks: &TrackList, current_track: usize, is_paused: bool) -> Vec<
     rows
 }
 
+/// Row in the table built by `build_rows` that holds the track at `index`,
+/// accounting for the album header and spacer rows above it.
+fn track_row_index(tracks: &TrackList, index: usize) -> usize {
+    let list = &tracks.tracks;
+    let headers = (0..=index)
+        .filter(|&i| i == 0 || list[i].album != list[i - 1].album)
+        .count();
+    // every header but the first has an empty spacer row above it
+    index + headers * 2 - 1
+}
+
 fn volume_modifier(key: KeyEvent) -> u8 {
     if is_holding_shift(key) {
         10
@@ -303,6 +1131,14 @@ fn volume_modifier(key: KeyEvent) -> u8 {
     }
 }
 
+fn seek_modifier(key: KeyEvent) -> Duration {
+    if is_holding_shift(key) {
+        Duration::from_secs(30)
+    } else {
+        Duration::from_secs(5)
+    }
+}
+
 fn is_holding_shift(key: KeyEvent) -> bool {
     key.modifiers.contains(event::KeyModifiers::SHIFT)
 }
@@ -3,7 +3,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use config::{Config, ConfigError};
+use config::{Config, ConfigError, FileFormat};
 use directories::ProjectDirs;
 use serde::Deserialize;
 use tracing::subscriber::set_global_default;
@@ -12,15 +12,39 @@ use tracing_bunyan_formatter::BunyanFormattingLayer;
 use tracing_subscriber::{prelude::*, EnvFilter};
 use tracing_unwrap::*;
 
+use crate::equalizer::Preset;
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub server: ServerSettings,
     pub music: MusicSettings,
+    #[serde(default)]
+    pub equalizer: EqualizerSettings,
+}
+
+/// Equalizer presets, and which one to start with. Without one, the
+/// equalizer starts out bypassed.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EqualizerSettings {
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+}
+
+impl EqualizerSettings {
+    /// Index of the preset called `name`
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.presets.iter().position(|p| p.name == name)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct MusicSettings {
     pub paths: Vec<String>,
+    /// The database `build-db` writes, for anything served from the library
+    #[serde(default)]
+    pub library: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -106,3 +130,75 @@ pub fn from_file(file: &str) -> Result<Settings, ConfigError> {
         .build()?;
     settings.try_deserialize::<Settings>()
 }
+
+/// Read just the equalizer settings from a configuration file, which is all
+/// the player needs from it
+///
+/// # Errors
+///
+/// Returns a `ConfigError` if the file cannot be read, or its equalizer
+/// settings cannot be deserialized. A file without any is fine.
+pub fn equalizer_from_file(file: &str) -> Result<EqualizerSettings, ConfigError> {
+    let settings = Config::builder()
+        .add_source(config::File::new(file, FileFormat::Yaml))
+        .build()?;
+    equalizer_from(&settings)
+}
+
+fn equalizer_from(settings: &Config) -> Result<EqualizerSettings, ConfigError> {
+    match settings.get::<EqualizerSettings>("equalizer") {
+        Err(ConfigError::NotFound(_)) => Ok(EqualizerSettings::default()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equalizer::Band;
+
+    fn parse(yaml: &str) -> Result<EqualizerSettings, ConfigError> {
+        let settings = Config::builder()
+            .add_source(config::File::from_str(yaml, FileFormat::Yaml))
+            .build()?;
+        equalizer_from(&settings)
+    }
+
+    #[test]
+    fn test_equalizer_presets_parse() {
+        let settings = parse(
+            "
+equalizer:
+  preset: headphones
+  presets:
+    - name: headphones
+      preamp: -6
+      bands:
+        - type: low_shelf
+          frequency: 105
+          gain: 5.5
+        - { type: peaking, frequency: 2000, gain: -2, q: 1.4 }
+        - { type: high_pass, frequency: 20 }
+    - name: flat
+      bands: []
+",
+        )
+        .unwrap();
+        assert_eq!(settings.find("flat"), Some(1));
+        let preset = &settings.presets[settings.find("headphones").unwrap()];
+        assert_eq!(preset.preamp, -6.0);
+        assert_eq!(preset.bands.len(), 3);
+        assert_eq!(
+            preset.bands[0],
+            Band::LowShelf {
+                frequency: 105.0,
+                gain: 5.5,
+                q: std::f32::consts::FRAC_1_SQRT_2,
+            }
+        );
+
+        // everything else in the file is none of the player's business
+        let settings = parse("music:\n  paths: []\n").unwrap();
+        assert!(settings.presets.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::types::TrackList;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Where each track starts in a mix of `tracks` rendered from `from`, in
+/// samples at the track list's rate, along with its index in the list
+///
+/// Mixes follow `TrackList::get_bounds`, except that tracks from different
+/// albums overlap by up to `crossfade`, which pulls everything after them in.
+/// A crossfaded track starts where its fade in does.
+#[must_use]
+pub fn track_starts(tracks: &TrackList, from: u64, crossfade: Duration) -> Vec<(usize, u64)> {
+    if tracks.tracks.is_empty() || from >= tracks.total_samples {
+        return vec![];
+    }
+    let rate = u64::from(tracks.sample_rate);
+    let fade =
+        crossfade.as_secs() * rate + u64::from(crossfade.subsec_nanos()) * rate / 1_000_000_000;
+
+    let first = tracks.find_playing(from);
+    let mut starts = vec![(first, 0)];
+    // where the previous track ends in the mix, and how much of it is left
+    // to fade out under once its own fade in is over
+    let mut end = tracks.get_bounds(first).1 - from;
+    let mut free = end;
+    for (index, pair) in tracks.tracks.windows(2).enumerate().skip(first) {
+        let next = index + 1;
+        let length = tracks.get_sample_count(next);
+        let overlap =
+            if (&pair[0].album_artist, &pair[0].album) == (&pair[1].album_artist, &pair[1].album) {
+                0
+            } else {
+                fade.min(free).min(length)
+            };
+        let start = end - overlap;
+        starts.push((next, start));
+        end = start + length;
+        free = length - overlap;
+    }
+    starts
+}
+
+/// Write a cue sheet for `audio_file`, a mix of `tracks` with each one
+/// starting where `starts` says it does
+///
+/// # Errors
+///
+/// Returns an error if writing to `out` fails
+pub fn write_cue_sheet<W: Write>(
+    out: &mut W,
+    audio_file: &str,
+    tracks: &TrackList,
+    starts: &[(usize, u64)],
+) -> io::Result<()> {
+    writeln!(out, "FILE \"{}\" WAVE", quoted(audio_file))?;
+    for (number, &(index, start)) in starts.iter().enumerate() {
+        let track = tracks.get_track(index);
+        writeln!(out, "  TRACK {:02} AUDIO", number + 1)?;
+        writeln!(out, "    TITLE \"{}\"", quoted(&track.title))?;
+        writeln!(out, "    PERFORMER \"{}\"", quoted(&track.album_artist))?;
+        writeln!(out, "    INDEX 01 {}", timestamp(start, tracks.sample_rate))?;
+    }
+    Ok(())
+}
+
+/// Cue sheets have no way of escaping double quotes
+fn quoted(value: &str) -> String {
+    value.replace('"', "'")
+}
+
+/// `minutes:seconds:frames`, with 75 frames to the second like a CD
+fn timestamp(samples: u64, sample_rate: u32) -> String {
+    let frames = samples * 75 / u64::from(sample_rate.max(1));
+    format!(
+        "{:02}:{:02}:{:02}",
+        frames / (75 * 60),
+        frames / 75 % 60,
+        frames % 75
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::ReplayGain;
+    use crate::types::Track;
+    use std::path::PathBuf;
+
+    fn track(album: &str, title: &str, seconds: u64) -> Track {
+        Track {
+            path: PathBuf::from(format!("{title}.flac")),
+            sample_rate: 44_100,
+            samples: 44_100 * seconds,
+            channels: 2,
+            album: album.to_owned(),
+            album_artist: "Artist".to_owned(),
+            title: title.to_owned(),
+            track: 1,
+            replay_gain: ReplayGain::default(),
+        }
+    }
+
+    #[test]
+    fn test_crossfades_pull_tracks_in() {
+        let tracks = TrackList::from(vec![
+            track("A", "One", 60),
+            track("A", "Two", 60),
+            track("B", "Three", 3),
+            track("C", "Four", 60),
+        ]);
+        let seconds = |starts: Vec<(usize, u64)>| {
+            starts
+                .into_iter()
+                .map(|(i, s)| (i, s as f64 / 44_100.0))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            seconds(track_starts(&tracks, 0, Duration::ZERO)),
+            vec![(0, 0.0), (1, 60.0), (2, 120.0), (3, 123.0)]
+        );
+        // the same album stays gapless, and a fade can't be longer than
+        // either track. "Three" is all fade in, so there's none of it left
+        // to fade out.
+        assert_eq!(
+            seconds(track_starts(&tracks, 44_100 * 90, Duration::from_secs(5))),
+            vec![(1, 0.0), (2, 27.0), (3, 30.0)]
+        );
+    }
+
+    #[test]
+    fn test_cue_sheet_format() {
+        let tracks = TrackList::from(vec![track("A", "One", 60), track("A", "Say \"Two\"", 60)]);
+        let mut out = vec![];
+        write_cue_sheet(
+            &mut out,
+            "mix.wav",
+            &tracks,
+            &[(0, 0), (1, 44_100 * 61 + 441)],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "FILE \"mix.wav\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n\
+             \x20   TITLE \"One\"\n\
+             \x20   PERFORMER \"Artist\"\n\
+             \x20   INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n\
+             \x20   TITLE \"Say 'Two'\"\n\
+             \x20   PERFORMER \"Artist\"\n\
+             \x20   INDEX 01 01:01:00\n"
+        );
+    }
+}
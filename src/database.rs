@@ -13,7 +13,7 @@ impl Database {
     /// # Panics
     ///
     /// Panics if the connection cannot be opened.
-    pub async fn connect<'a>(kind: Kind) -> Self {
+    pub async fn connect(kind: Kind) -> Self {
         let conn = match kind {
             Kind::File(path) => {
                 tracing::info!("Opening database at {}", path);
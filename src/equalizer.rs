@@ -0,0 +1,293 @@
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// One filter in an equalizer preset, as it's written in `configuration.yml`
+///
+/// Frequencies are in Hz and gains in dB. `q` defaults to a Butterworth
+/// response, and for shelves it's the slope.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Band {
+    Peaking {
+        frequency: f32,
+        gain: f32,
+        #[serde(default = "butterworth")]
+        q: f32,
+    },
+    LowShelf {
+        frequency: f32,
+        gain: f32,
+        #[serde(default = "butterworth")]
+        q: f32,
+    },
+    HighShelf {
+        frequency: f32,
+        gain: f32,
+        #[serde(default = "butterworth")]
+        q: f32,
+    },
+    LowPass {
+        frequency: f32,
+        #[serde(default = "butterworth")]
+        q: f32,
+    },
+    HighPass {
+        frequency: f32,
+        #[serde(default = "butterworth")]
+        q: f32,
+    },
+}
+
+fn butterworth() -> f32 {
+    std::f32::consts::FRAC_1_SQRT_2
+}
+
+/// A named set of bands, like a correction curve for a pair of headphones
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    /// Gain in dB applied before the bands, usually negative to make room
+    /// for any boosts
+    #[serde(default)]
+    pub preamp: f32,
+    pub bands: Vec<Band>,
+}
+
+/// Biquad coefficients from the Audio EQ Cookbook, normalized so `a0` is 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn new(band: Band, sample_rate: u32) -> Self {
+        let rate = f64::from(sample_rate);
+        let (frequency, gain, q) = match band {
+            Band::Peaking { frequency, gain, q }
+            | Band::LowShelf { frequency, gain, q }
+            | Band::HighShelf { frequency, gain, q } => (frequency, gain, q),
+            Band::LowPass { frequency, q } | Band::HighPass { frequency, q } => (frequency, 0.0, q),
+        };
+        // anything at or past Nyquist would make the filter unstable
+        let frequency = f64::from(frequency).clamp(1.0, rate * 0.49);
+        let q = f64::from(q).max(0.01);
+
+        let w0 = 2.0 * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a = 10f64.powf(f64::from(gain) / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band {
+            Band::Peaking { .. } => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            Band::LowShelf { .. } => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            Band::HighShelf { .. } => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+            Band::LowPass { .. } => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            Band::HighPass { .. } => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// Run one sample through, using transposed direct form II
+    #[inline]
+    fn process(&self, x: f64, state: &mut [f64; 2]) -> f64 {
+        let y = self.b0 * x + state[0];
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Runs interleaved audio through whichever preset is selected
+///
+/// Coefficients for every preset are worked out up front for the output's
+/// sample rate, so switching presets while playing doesn't allocate.
+#[derive(Debug, Clone)]
+pub struct Equalizer {
+    presets: Vec<Preset>,
+    channels: usize,
+    /// Coefficients for each band of each preset
+    banks: Vec<Vec<Coefficients>>,
+    /// Filter state for each band and channel
+    state: Vec<[f64; 2]>,
+    /// Which preset the filter state belongs to
+    active: Option<usize>,
+}
+
+impl Equalizer {
+    #[must_use]
+    pub fn new(presets: Vec<Preset>, channels: usize, sample_rate: u32) -> Self {
+        let most_bands = presets.iter().map(|p| p.bands.len()).max().unwrap_or(0);
+        let mut equalizer = Self {
+            presets,
+            channels: channels.max(1),
+            banks: Vec::new(),
+            state: vec![[0.0; 2]; most_bands * channels.max(1)],
+            active: None,
+        };
+        equalizer.set_sample_rate(sample_rate);
+        equalizer
+    }
+
+    /// Work the coefficients out again for a new sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.banks = self
+            .presets
+            .iter()
+            .map(|preset| {
+                preset
+                    .bands
+                    .iter()
+                    .map(|&band| Coefficients::new(band, sample_rate))
+                    .collect()
+            })
+            .collect();
+        self.active = None;
+    }
+
+    /// Filter `data` in place with the preset at `selected`, or leave it
+    /// alone if there isn't one
+    pub fn process(&mut self, data: &mut [f32], selected: Option<usize>) {
+        let Some(index) = selected.filter(|&i| i < self.banks.len()) else {
+            self.active = None;
+            return;
+        };
+        if self.active != Some(index) {
+            // what's left in there was shaped by other filters
+            self.state.fill([0.0; 2]);
+            self.active = Some(index);
+        }
+
+        let bank = &self.banks[index];
+        let preamp = 10f64.powf(f64::from(self.presets[index].preamp) / 20.0);
+        for frame in data.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut value = f64::from(*sample) * preamp;
+                for (band, coefficients) in bank.iter().enumerate() {
+                    let state = &mut self.state[band * self.channels + channel];
+                    value = coefficients.process(value, state);
+                }
+                #[allow(clippy::cast_possible_truncation)]
+                {
+                    *sample = value as f32;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Level in dB of a sine at `frequency` after it's settled through
+    /// `preset`
+    fn level_after(preset: &Preset, frequency: f32) -> f32 {
+        let rate = 48_000;
+        let mut equalizer = Equalizer::new(vec![preset.clone()], 1, rate);
+        let mut data = (0..rate)
+            .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / rate as f32).sin())
+            .collect::<Vec<_>>();
+        equalizer.process(&mut data, Some(0));
+        let peak = data[data.len() / 2..]
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        20.0 * peak.log10()
+    }
+
+    #[test]
+    fn test_bands_shape_the_response() {
+        let preset = |bands| Preset {
+            name: "test".to_owned(),
+            preamp: 0.0,
+            bands,
+        };
+        let peak = preset(vec![Band::Peaking {
+            frequency: 1000.0,
+            gain: 6.0,
+            q: 1.0,
+        }]);
+        assert!((level_after(&peak, 1000.0) - 6.0).abs() < 0.1);
+        assert!(level_after(&peak, 50.0).abs() < 0.1);
+
+        let shelf = preset(vec![Band::LowShelf {
+            frequency: 200.0,
+            gain: -6.0,
+            q: 0.707,
+        }]);
+        assert!((level_after(&shelf, 30.0) + 6.0).abs() < 0.3);
+        assert!(level_after(&shelf, 10_000.0).abs() < 0.1);
+
+        let high_pass = preset(vec![Band::HighPass {
+            frequency: 1000.0,
+            q: 0.707,
+        }]);
+        assert!(level_after(&high_pass, 100.0) < -35.0);
+        assert!(level_after(&high_pass, 10_000.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_bypass_leaves_samples_alone() {
+        let preset = Preset {
+            name: "loud".to_owned(),
+            preamp: -3.0,
+            bands: vec![Band::HighShelf {
+                frequency: 5000.0,
+                gain: 3.0,
+                q: 0.707,
+            }],
+        };
+        let mut equalizer = Equalizer::new(vec![preset], 2, 44_100);
+        let mut data = vec![0.5, -0.5, 0.25, -0.25];
+        equalizer.process(&mut data, None);
+        assert_eq!(data, vec![0.5, -0.5, 0.25, -0.25]);
+        // a preset that doesn't exist counts as bypassed too
+        equalizer.process(&mut data, Some(1));
+        assert_eq!(data, vec![0.5, -0.5, 0.25, -0.25]);
+    }
+}
@@ -1,31 +1,69 @@
 use std::path::{Path, PathBuf};
 
 use itertools::Itertools;
-use tracing_unwrap::ResultExt;
 use walkdir::WalkDir;
 
-/// Returns true if the path exists and is a supported audio file.
+/// Extensions of the audio files we can play and scan
 ///
-/// Currently only flac is supported.
+/// Ogg files holding Opus get through this, since symphonia reads the
+/// container but has no Opus decoder; probing them fails with "Opus isn't
+/// supported", so they're skipped rather than failing when they come up.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "flac", "mp3", "ogg", "oga", "wav", "aif", "aiff", "aifc", "m4a", "mp4",
+];
+
+/// A file that was left out of the queue or given up on partway through,
+/// and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Returns true if the path has the extension of a supported audio file
+pub fn has_supported_extension<P: AsRef<Path>>(p: P) -> bool {
+    p.as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| {
+            SUPPORTED_EXTENSIONS
+                .iter()
+                .any(|supported| e.eq_ignore_ascii_case(supported))
+        })
+}
+
+/// Returns true if the path exists and is a supported audio file.
 pub fn is_supported_audio_file<P: AsRef<Path>>(p: P) -> bool {
     let p = p.as_ref();
-    p.exists() && p.extension().unwrap_or_default() == "flac"
+    p.exists() && has_supported_extension(p)
 }
 
-/// Walk directories and filter down to only flac files
+/// Walk directories and filter down to only audio files
 ///
 /// When an entry is a directory, it will be walked and all audio files
 /// will be included. When it's a file, it will be included if it's audio.
 ///
-/// The returned paths will be canonicalized.
-///
-/// # Panics
-///
-/// Panics if a path cannot be canonicalized
-pub fn only_audio(filenames: &[String]) -> Vec<PathBuf> {
+/// The returned paths will be canonicalized, and any that can't be are
+/// skipped.
+pub fn only_audio<P: AsRef<Path>>(filenames: &[P]) -> Vec<Result<PathBuf, SkippedFile>> {
+    walk(filenames)
+        .into_iter()
+        .filter(|p| is_supported_audio_file(p))
+        .map(|p| {
+            p.canonicalize().map_err(|error| SkippedFile {
+                reason: format!("couldn't resolve the path: {error}"),
+                path: p,
+            })
+        })
+        .collect_vec()
+}
+
+/// Walk directories for the files in them, keeping everything else as is,
+/// whether it exists or not
+pub fn walk<P: AsRef<Path>>(filenames: &[P]) -> Vec<PathBuf> {
     filenames
         .iter()
-        .map(Path::new)
+        .map(AsRef::as_ref)
         .flat_map(|p| {
             if p.is_dir() {
                 WalkDir::new(p)
@@ -38,10 +76,5 @@ pub fn only_audio(filenames: &[String]) -> Vec<PathBuf> {
                 vec![p.to_owned()]
             }
         })
-        .filter(|p| is_supported_audio_file(p))
-        .map(|p| {
-            p.canonicalize()
-                .expect_or_log("Failed to canonicalize path")
-        })
         .collect_vec()
 }
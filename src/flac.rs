@@ -0,0 +1,515 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Writes integer samples to a FLAC stream
+///
+/// Each block is predicted with whichever of FLAC's fixed polynomial
+/// predictors leaves the smallest residual, which is Rice coded, or stored
+/// verbatim when nothing helps. That's nowhere near as small as the reference
+/// encoder, but it's lossless and a good deal smaller than WAV.
+///
+/// STREAMINFO gets written again with the final length once the stream is
+/// finalized, so the output has to be seekable. The MD5 signature is left
+/// unset, which decoders take to mean it wasn't worked out.
+pub struct FlacWriter<W: Write + Seek> {
+    out: W,
+    channels: u8,
+    sample_rate: u32,
+    bits_per_sample: u8,
+    /// Interleaved samples waiting to fill a block
+    pending: Vec<i32>,
+    frames_written: u64,
+    blocks_written: u64,
+    /// Smallest and largest encoded frame, in bytes
+    frame_sizes: Option<(usize, usize)>,
+    bits: BitWriter,
+    channel: Vec<i32>,
+    residual: Vec<i32>,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// Frames in each block, except maybe the last one
+    const BLOCK_SIZE: usize = 4096;
+    /// Where STREAMINFO goes, after the `fLaC` marker and its block header
+    const STREAMINFO_OFFSET: u64 = 8;
+
+    /// Start a stream of `channels` channels (1 to 8) of
+    /// `bits_per_sample`-bit samples (4 to 32)
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be stored in FLAC, or if the
+    /// header can't be written
+    pub fn new(out: W, channels: u8, sample_rate: u32, bits_per_sample: u8) -> io::Result<Self> {
+        if !(1..=8).contains(&channels)
+            || !(4..=32).contains(&bits_per_sample)
+            || !(1..1 << 20).contains(&sample_rate)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "FLAC can't store {channels} channels of {bits_per_sample}-bit samples at {sample_rate}Hz"
+                ),
+            ));
+        }
+
+        let mut writer = Self {
+            out,
+            channels,
+            sample_rate,
+            bits_per_sample,
+            pending: Vec::with_capacity(Self::BLOCK_SIZE * usize::from(channels)),
+            frames_written: 0,
+            blocks_written: 0,
+            frame_sizes: None,
+            bits: BitWriter::default(),
+            channel: Vec::with_capacity(Self::BLOCK_SIZE),
+            residual: Vec::with_capacity(Self::BLOCK_SIZE),
+        };
+        writer.out.write_all(b"fLaC")?;
+        // the last metadata block is STREAMINFO, which is 34 bytes long
+        writer.out.write_all(&[0x80, 0, 0, 34])?;
+        let streaminfo = writer.streaminfo();
+        writer.out.write_all(&streaminfo)?;
+        Ok(writer)
+    }
+
+    #[must_use]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Add the next interleaved sample
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a full block couldn't be written
+    pub fn write_sample(&mut self, sample: i32) -> io::Result<()> {
+        self.pending.push(sample);
+        if self.pending.len() == Self::BLOCK_SIZE * usize::from(self.channels) {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write out whatever's left and fill in the stream's length
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the output couldn't be written
+    pub fn finalize(mut self) -> io::Result<W> {
+        // a partial frame at the end can't be played, so it's dropped
+        let whole = self.pending.len() / usize::from(self.channels) * usize::from(self.channels);
+        self.pending.truncate(whole);
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+        self.out.seek(SeekFrom::Start(Self::STREAMINFO_OFFSET))?;
+        let streaminfo = self.streaminfo();
+        self.out.write_all(&streaminfo)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn streaminfo(&self) -> [u8; 34] {
+        let mut bits = BitWriter::default();
+        // the last block is allowed to be shorter than the minimum
+        bits.write(Self::BLOCK_SIZE as u64, 16);
+        bits.write(Self::BLOCK_SIZE as u64, 16);
+        let (smallest, largest) = self.frame_sizes.unwrap_or((0, 0));
+        bits.write(smallest as u64, 24);
+        bits.write(largest as u64, 24);
+        bits.write(u64::from(self.sample_rate), 20);
+        bits.write(u64::from(self.channels - 1), 3);
+        bits.write(u64::from(self.bits_per_sample - 1), 5);
+        bits.write(self.frames_written, 36);
+        // no MD5 signature
+        bits.write(0, 64);
+        bits.write(0, 64);
+
+        let mut streaminfo = [0; 34];
+        streaminfo.copy_from_slice(bits.bytes());
+        streaminfo
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let channels = usize::from(self.channels);
+        let frames = self.pending.len() / channels;
+        let mut bits = std::mem::take(&mut self.bits);
+        bits.clear();
+
+        // frame header, with the sample rate and size left to STREAMINFO
+        bits.write(0xfff8, 16);
+        bits.write(0b0111, 4);
+        bits.write(0, 4);
+        bits.write(u64::from(self.channels - 1), 4);
+        bits.write(0, 4);
+        for byte in coded_number(self.blocks_written) {
+            bits.write(u64::from(byte), 8);
+        }
+        bits.write(frames as u64 - 1, 16);
+        bits.write(u64::from(crc8(bits.bytes())), 8);
+
+        for channel in 0..channels {
+            self.channel.clear();
+            self.channel
+                .extend(self.pending.iter().skip(channel).step_by(channels));
+            self.write_subframe(&mut bits);
+        }
+        bits.pad_to_byte();
+        let crc = crc16(bits.bytes());
+        bits.write(u64::from(crc), 16);
+
+        self.out.write_all(bits.bytes())?;
+        let size = bits.bytes().len();
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((smallest, largest)) => (smallest.min(size), largest.max(size)),
+            None => (size, size),
+        });
+        self.frames_written += frames as u64;
+        self.blocks_written += 1;
+        self.pending.clear();
+        self.bits = bits;
+        Ok(())
+    }
+
+    /// Write `self.channel` as whichever subframe comes out smallest
+    fn write_subframe(&mut self, bits: &mut BitWriter) {
+        let bps = u32::from(self.bits_per_sample);
+        let samples = &self.channel;
+        let verbatim = samples.len() as u64 * u64::from(bps);
+
+        // the order, Rice parameter and size of the best fixed predictor
+        let mut best: Option<(usize, u32, u64)> = None;
+        for order in 0..=4.min(samples.len() - 1) {
+            if !residual(samples, order, &mut self.residual) {
+                continue;
+            }
+            let (parameter, size) = rice_parameter(&self.residual);
+            let size = size + order as u64 * u64::from(bps) + 10;
+            if best.is_none_or(|(.., smallest)| size < smallest) {
+                best = Some((order, parameter, size));
+            }
+        }
+
+        match best {
+            Some((order, parameter, size)) if size < verbatim => {
+                bits.write(0b0001_0000 | (order as u64) << 1, 8);
+                for &sample in &samples[..order] {
+                    bits.write_signed(sample, bps);
+                }
+                residual(samples, order, &mut self.residual);
+                // Rice coded with 4-bit parameters, all in one partition
+                bits.write(0, 2);
+                bits.write(0, 4);
+                bits.write(u64::from(parameter), 4);
+                for &r in &self.residual {
+                    let folded = zigzag(r);
+                    bits.write_unary(folded >> parameter);
+                    bits.write(u64::from(folded) & ((1 << parameter) - 1), parameter);
+                }
+            }
+            _ => {
+                bits.write(0b0000_0010, 8);
+                for &sample in samples {
+                    bits.write_signed(sample, bps);
+                }
+            }
+        }
+    }
+}
+
+/// The largest parameter that fits in a 4-bit Rice partition; 15 means the
+/// partition isn't Rice coded at all
+const HIGHEST_RICE_PARAMETER: u32 = 14;
+
+/// What's left over after predicting `samples` from the `order` samples
+/// before each one. Returns false if a residual doesn't fit in 32 bits,
+/// which FLAC doesn't allow.
+fn residual(samples: &[i32], order: usize, out: &mut Vec<i32>) -> bool {
+    out.clear();
+    for i in order..samples.len() {
+        let s = |back: usize| i64::from(samples[i - back]);
+        let predicted = match order {
+            0 => 0,
+            1 => s(1),
+            2 => 2 * s(1) - s(2),
+            3 => 3 * s(1) - 3 * s(2) + s(3),
+            _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+        };
+        match i32::try_from(s(0) - predicted) {
+            Ok(r) => out.push(r),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// The Rice parameter that codes `residual` smallest, and how many bits that
+/// takes
+fn rice_parameter(residual: &[i32]) -> (u32, u64) {
+    (0..=HIGHEST_RICE_PARAMETER)
+        .map(|parameter| {
+            let quotients: u64 = residual
+                .iter()
+                .map(|&r| u64::from(zigzag(r) >> parameter))
+                .sum();
+            let size = quotients + residual.len() as u64 * u64::from(parameter + 1);
+            (parameter, size)
+        })
+        .min_by_key(|&(_, size)| size)
+        .unwrap_or((0, 0))
+}
+
+/// Fold signed values into unsigned ones, small either way staying small
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// A frame number, coded like UTF-8 but with room for up to 36 bits
+fn coded_number(n: u64) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    // each extra byte holds 6 bits, and the first one loses a bit for each
+    let len = (2..=7u32).find(|&len| n < 1 << (5 * len + 1)).unwrap_or(7);
+    let shift = 6 * (len - 1);
+    let mut bytes = vec![((0xff00u32 >> len) as u8) | (n >> shift) as u8];
+    bytes.extend(
+        (0..len - 1)
+            .rev()
+            .map(|i| 0x80 | ((n >> (6 * i)) & 0x3f) as u8),
+    );
+    bytes
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| {
+            if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x8005
+            }
+        })
+    })
+}
+
+/// Packs values into bytes, most significant bit first
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits that don't make up a whole byte yet, in the low end
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.pending = 0;
+        self.pending_bits = 0;
+    }
+
+    /// Write the low `bits` bits of `value`, up to 64 of them
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xffff_ffff, 32);
+            return;
+        }
+        if bits == 0 {
+            return;
+        }
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(i64::from(value) as u64, bits);
+    }
+
+    /// `n` zeroes and then a one
+    fn write_unary(&mut self, mut n: u32) {
+        while n >= 32 {
+            self.write(0, 32);
+            n -= 32;
+        }
+        self.write(1, n + 1);
+    }
+
+    fn pad_to_byte(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    /// Everything written so far that makes up whole bytes
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{decode, Probe};
+    use std::io::BufWriter;
+
+    /// Write `frames` frames of `channels` channels at `bits`, check the file
+    /// with the reference decoder if it's installed, then decode it again.
+    /// Returns what was written and what came back.
+    fn round_trip(bits: u8, channels: u8, frames: u32) -> (Probe, Vec<i32>, Vec<i32>) {
+        let path = std::env::temp_dir().join(format!(
+            "wigglyair-flac-{bits}-{channels}-{frames}-{}.flac",
+            std::process::id()
+        ));
+        let samples = signal(bits, channels, frames);
+        let file = BufWriter::new(std::fs::File::create(&path).unwrap());
+        let mut writer = FlacWriter::new(file, channels, 44_100, bits).unwrap();
+        for &sample in &samples {
+            writer.write_sample(sample).unwrap();
+        }
+        if channels > 1 {
+            // part of a frame at the end gets dropped
+            writer.write_sample(1).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let verified = verify_with_flac(&path);
+        let probe = Probe::from_path(&path);
+        let mut decoded = Vec::new();
+        let scale = f64::from(1 << (bits - 1));
+        let result = decode(&path, |samples, _| {
+            #[allow(clippy::cast_possible_truncation)]
+            decoded.extend(
+                samples
+                    .iter()
+                    .map(|&s| (f64::from(s) * scale).round() as i32),
+            );
+        });
+        std::fs::remove_file(&path).unwrap();
+        verified.unwrap();
+        result.unwrap();
+        (probe.unwrap(), samples, decoded)
+    }
+
+    /// Decode `path` with the reference `flac` tool, which checks every
+    /// frame's CRCs and the stream's length. Passes if it isn't installed.
+    fn verify_with_flac(path: &std::path::Path) -> Result<(), String> {
+        let output = match std::process::Command::new("flac")
+            .args(["--test", "--silent"])
+            .arg(path)
+            .output()
+        {
+            Ok(output) => output,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                eprintln!("flac isn't installed, so only symphonia has decoded this");
+                return Ok(());
+            }
+            Err(error) => return Err(error.to_string()),
+        };
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).into_owned())
+        }
+    }
+
+    /// Interleaved samples with something different in each channel: a
+    /// smooth sweep, noise that only stores verbatim, silence, and full scale
+    /// square waves
+    fn signal(bits: u8, channels: u8, frames: u32) -> Vec<i32> {
+        let max = (1 << (bits - 1)) - 1;
+        let mut noise = 12_345u32;
+        let mut samples = Vec::new();
+        for i in 0..frames {
+            for channel in 0..channels {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                #[allow(clippy::cast_possible_truncation)]
+                let sample = match channel % 4 {
+                    0 => ((f64::from(i) * f64::from(i) * 1e-5).sin() * f64::from(max)) as i32,
+                    1 => i32::from_ne_bytes(noise.to_ne_bytes()) >> (32 - bits),
+                    2 => 0,
+                    _ if (i / (u32::from(channel) + 1)) % 2 == 0 => max,
+                    _ => -max - 1,
+                };
+                samples.push(sample);
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_flac_round_trips_16_bit() {
+        let (probe, written, decoded) = round_trip(16, 2, 10_000);
+        assert_eq!(probe.sample_rate, 44_100);
+        assert_eq!(probe.channels, 2);
+        assert_eq!(probe.total_samples, 10_000);
+        assert_eq!(decoded, written);
+    }
+
+    #[test]
+    fn test_flac_round_trips_24_bit() {
+        let (probe, written, decoded) = round_trip(24, 2, 10_000);
+        assert_eq!(probe.total_samples, 10_000);
+        assert_eq!(decoded, written);
+    }
+
+    #[test]
+    fn test_flac_round_trips_mono_and_eight_channels() {
+        for channels in [1, 8] {
+            let (probe, written, decoded) = round_trip(16, channels, 5_000);
+            assert_eq!(probe.channels, channels);
+            assert_eq!(probe.total_samples, 5_000);
+            assert_eq!(decoded, written, "{channels} channels");
+        }
+    }
+
+    #[test]
+    fn test_flac_round_trips_exactly_one_block() {
+        let frames = u32::try_from(FlacWriter::<io::Cursor<Vec<u8>>>::BLOCK_SIZE).unwrap();
+        let (probe, written, decoded) = round_trip(24, 2, frames);
+        assert_eq!(probe.total_samples, u64::from(frames));
+        assert_eq!(decoded, written);
+    }
+
+    #[test]
+    fn test_crcs_match_their_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn test_frame_numbers_are_coded_like_utf8() {
+        for n in [0, 0x7f, 0x80, 0x7ff, 0x800, 0xffff, 0x1_0000, 0x10_ffff] {
+            let c = char::from_u32(n).unwrap();
+            let mut utf8 = [0; 4];
+            assert_eq!(
+                coded_number(u64::from(n)),
+                c.encode_utf8(&mut utf8).as_bytes()
+            );
+        }
+        assert_eq!(
+            coded_number((1 << 36) - 1),
+            [0xfe, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf]
+        );
+    }
+}
@@ -1,6 +1,21 @@
+pub mod analyzer;
 pub mod configuration;
+pub mod cue;
 pub mod database;
+pub mod equalizer;
 pub mod files;
+pub mod flac;
+pub mod loudness;
 pub mod metadata;
+mod output;
+mod queue;
+mod reader;
+pub mod remix;
+pub mod resample;
+pub mod ring;
 pub mod routes;
+pub mod sink;
+pub mod speed;
+pub mod track_list;
 pub mod types;
+pub mod waveform;
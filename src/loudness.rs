@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use symphonia::core::audio::Channels;
+
+/// Loudness of a track or an album, as EBU R128 measures it
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS. `None` if it's all silence.
+    pub integrated: Option<f64>,
+    /// Loudness range in LU
+    pub range: Option<f64>,
+    /// True peak, where 1.0 is full scale
+    pub true_peak: f32,
+}
+
+impl Loudness {
+    /// Combine the loudness of an album's tracks, each weighted by its length
+    /// in seconds
+    ///
+    /// This is the power mean of each track's integrated loudness rather than
+    /// a measurement of the whole album, since we only keep the numbers for
+    /// each track. Loudness range can't be combined like that, so albums don't
+    /// get one.
+    #[must_use]
+    pub fn combine(tracks: &[(Self, f64)]) -> Self {
+        let (energy, weight) = tracks
+            .iter()
+            .filter_map(|(loudness, weight)| Some((energy(loudness.integrated?), *weight)))
+            .fold((0.0, 0.0), |(sum, total), (energy, weight)| {
+                (sum + energy * weight, total + weight)
+            });
+        Self {
+            integrated: (weight > 0.0).then(|| loudness(energy / weight)),
+            range: None,
+            true_peak: tracks
+                .iter()
+                .map(|(loudness, _)| loudness.true_peak)
+                .fold(0.0, f32::max),
+        }
+    }
+}
+
+/// Blocks quieter than this don't count towards anything
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Loudness in LUFS of a block's mean square
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Mean square of a block at `loudness` LUFS
+fn energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+/// Blocks that are above the absolute gate and no more than `relative` LU
+/// below the mean of those
+fn gate(blocks: &[f64], relative: f64) -> Vec<f64> {
+    let absolute = energy(ABSOLUTE_GATE);
+    let loud = blocks
+        .iter()
+        .copied()
+        .filter(|&e| e > absolute)
+        .collect::<Vec<_>>();
+    if loud.is_empty() {
+        return loud;
+    }
+    let threshold = loud.iter().sum::<f64>() / loud.len() as f64 * 10f64.powf(relative / 10.0);
+    loud.into_iter().filter(|&e| e > threshold).collect()
+}
+
+/// Measures loudness as ITU-R BS.1770-4 describes it
+///
+/// Samples are K-weighted, then chopped into 400ms blocks every 100ms for
+/// integrated loudness and 3s blocks every 100ms for loudness range. True
+/// peak comes from oversampling.
+pub struct Meter {
+    channels: Vec<Channel>,
+    step_frames: usize,
+    step_energy: f64,
+    step_filled: usize,
+    /// Weighted sums of squares for the last 3 seconds of steps
+    steps: VecDeque<f64>,
+    momentary: Vec<f64>,
+    short_term: Vec<f64>,
+    true_peak: TruePeak,
+}
+
+impl Meter {
+    const MOMENTARY_STEPS: usize = 4;
+    const SHORT_TERM_STEPS: usize = 30;
+
+    #[must_use]
+    pub fn new(sample_rate: u32, channels: Channels) -> Self {
+        let surround = Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+            | Channels::SIDE_LEFT
+            | Channels::SIDE_RIGHT
+            | Channels::REAR_CENTRE;
+        let lfe = Channels::LFE1 | Channels::LFE2;
+        let weight = |channel: Channels| {
+            if lfe.contains(channel) {
+                0.0
+            } else if surround.contains(channel) {
+                1.41
+            } else {
+                1.0
+            }
+        };
+
+        let rate = f64::from(sample_rate);
+        let channels = channels
+            .iter()
+            .map(|channel| Channel {
+                weight: weight(channel),
+                filters: k_weighting(rate),
+            })
+            .collect::<Vec<_>>();
+        let true_peak = TruePeak::new(sample_rate, channels.len());
+        Self {
+            channels,
+            step_frames: (sample_rate as usize / 10).max(1),
+            step_energy: 0.0,
+            step_filled: 0,
+            steps: VecDeque::with_capacity(Self::SHORT_TERM_STEPS),
+            momentary: Vec::new(),
+            short_term: Vec::new(),
+            true_peak,
+        }
+    }
+
+    /// Measure interleaved samples
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels.len()) {
+            self.true_peak.process(frame);
+            self.step_energy += self
+                .channels
+                .iter_mut()
+                .zip(frame)
+                .map(|(channel, &sample)| {
+                    let filtered = channel
+                        .filters
+                        .iter_mut()
+                        .fold(f64::from(sample), |x, filter| filter.process(x));
+                    channel.weight * filtered * filtered
+                })
+                .sum::<f64>();
+            self.step_filled += 1;
+            if self.step_filled == self.step_frames {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == Self::SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_filled = 0;
+
+        let mean = |steps: usize| {
+            self.steps.iter().rev().take(steps).sum::<f64>() / (steps * self.step_frames) as f64
+        };
+        if self.steps.len() >= Self::MOMENTARY_STEPS {
+            self.momentary.push(mean(Self::MOMENTARY_STEPS));
+        }
+        if self.steps.len() == Self::SHORT_TERM_STEPS {
+            self.short_term.push(mean(Self::SHORT_TERM_STEPS));
+        }
+    }
+
+    /// Work out the loudness of everything measured
+    #[must_use]
+    pub fn finish(self) -> Loudness {
+        let integrated = gate(&self.momentary, -10.0);
+        let integrated = (!integrated.is_empty())
+            .then(|| loudness(integrated.iter().sum::<f64>() / integrated.len() as f64));
+
+        let mut short_term = gate(&self.short_term, -20.0)
+            .into_iter()
+            .map(loudness)
+            .collect::<Vec<_>>();
+        short_term.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let index = ((short_term.len() - 1) as f64 * p).round() as usize;
+            short_term[index]
+        };
+        let range = (!short_term.is_empty()).then(|| percentile(0.95) - percentile(0.10));
+
+        #[allow(clippy::cast_possible_truncation)]
+        let true_peak = self.true_peak.peak as f32;
+        Loudness {
+            integrated,
+            range,
+            true_peak,
+        }
+    }
+}
+
+struct Channel {
+    weight: f64,
+    filters: [Biquad; 2],
+}
+
+/// Transposed direct form II biquad, normalised so `a[0]` is 1
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting pre-filter (a high shelf for the head) and RLB high pass,
+/// worked out for any sample rate rather than just the 48kHz coefficients in
+/// the spec
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let shelf = {
+        let f0 = 1_681.974_450_955_533;
+        let gain = 3.999_843_853_973_347;
+        let q = 0.707_175_236_955_419_6;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    };
+    let high_pass = {
+        let f0 = 38.135_470_876_024_44;
+        let q = 0.500_327_037_323_877_3;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    };
+    [shelf, high_pass]
+}
+
+/// Finds peaks between samples by oversampling with a polyphase FIR filter
+struct TruePeak {
+    /// One set of taps for each point between two samples
+    phases: Vec<Vec<f64>>,
+    /// The last few samples of each channel, newest first
+    history: Vec<VecDeque<f64>>,
+    peak: f64,
+}
+
+impl TruePeak {
+    const TAPS_PER_PHASE: usize = 12;
+
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        // high rates already have most of the detail
+        let factor = match sample_rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+        let taps = factor * Self::TAPS_PER_PHASE;
+        let centre = (taps - 1) as f64 / 2.0;
+        let phases = (0..factor)
+            .map(|phase| {
+                let taps = (0..Self::TAPS_PER_PHASE)
+                    .map(|k| {
+                        let n = (phase + k * factor) as f64;
+                        let x = (n - centre) / factor as f64;
+                        let sinc = if x == 0.0 {
+                            1.0
+                        } else {
+                            (PI * x).sin() / (PI * x)
+                        };
+                        let hann = 0.5 - 0.5 * (2.0 * PI * (n + 0.5) / taps as f64).cos();
+                        sinc * hann
+                    })
+                    .collect::<Vec<_>>();
+                let sum = taps.iter().sum::<f64>();
+                taps.into_iter().map(|tap| tap / sum).collect()
+            })
+            .collect();
+        Self {
+            phases,
+            history: vec![VecDeque::from(vec![0.0; Self::TAPS_PER_PHASE]); channels],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, frame: &[f32]) {
+        for (history, &sample) in self.history.iter_mut().zip(frame) {
+            history.pop_back();
+            history.push_front(f64::from(sample));
+            self.peak = self.peak.max(f64::from(sample).abs());
+            for taps in &self.phases {
+                let value = taps
+                    .iter()
+                    .zip(history.iter())
+                    .map(|(t, x)| t * x)
+                    .sum::<f64>();
+                self.peak = self.peak.max(value.abs());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo_sine(rate: u32, freq: f64, amplitude: f64, phase: f64, secs: u32) -> Vec<f32> {
+        (0..rate * secs)
+            .flat_map(|n| {
+                let t = f64::from(n) / f64::from(rate);
+                #[allow(clippy::cast_possible_truncation)]
+                let s = (amplitude * (2.0 * PI * freq * t + phase).sin()) as f32;
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reference_sine_measures_minus_23() {
+        // EBU Tech 3341 test 1: a 1kHz sine at -23dBFS in both channels
+        for rate in [44_100, 48_000] {
+            let mut meter = Meter::new(rate, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+            meter.process(&stereo_sine(
+                rate,
+                1_000.0,
+                10f64.powf(-23.0 / 20.0),
+                0.0,
+                5,
+            ));
+            let loudness = meter.finish();
+            assert!((loudness.integrated.unwrap() + 23.0).abs() < 0.1);
+            assert!(loudness.range.unwrap() < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // a quarter of the sample rate, with every sample landing at 45°
+        let rate = 48_000;
+        let samples = stereo_sine(rate, 12_000.0, 1.0, PI / 4.0, 1);
+        let sample_peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(sample_peak < 0.71);
+
+        let mut meter = Meter::new(rate, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        meter.process(&samples);
+        assert!((meter.finish().true_peak - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_silence_has_no_loudness() {
+        let mut meter = Meter::new(48_000, Channels::FRONT_LEFT);
+        meter.process(&[0.0; 48_000 * 5]);
+        assert_eq!(meter.finish(), Loudness::default());
+    }
+}
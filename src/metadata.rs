@@ -1,13 +1,18 @@
 use chrono::{DateTime, SecondsFormat, Utc};
-use metaflac::block::{StreamInfo, VorbisComment};
-use metaflac::Tag;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::Metadata;
+use std::fs::{File, Metadata};
 use std::io;
 use std::path::{Path, PathBuf};
+use symphonia::core::audio::{Channels, SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{CodecType, DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::TimeBase;
 use thiserror::Error;
-use tracing_unwrap::ResultExt;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Track {
@@ -24,12 +29,70 @@ pub struct Track {
     pub title: String,
     pub album_artist: String,
     pub track: u32,
+    #[serde(flatten)]
+    pub replay_gain: ReplayGain,
+}
+
+/// ReplayGain adjustments from a file's tags, in dB relative to the
+/// ReplayGain 2.0 reference level of -18 LUFS
+///
+/// Peaks are linear sample values, where 1.0 is full scale.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// R128 gains (as in Opus files) are relative to -23 LUFS
+    const R128_OFFSET_DB: f32 = 5.0;
+
+    /// Fill in a value from a tag if we don't have it yet. Returns false if
+    /// the tag isn't a gain tag.
+    fn add_tag(&mut self, key: &str, std_key: Option<StandardTagKey>, value: &str) -> bool {
+        // "-6.54 dB", "0.988831"
+        let number = || value.split_whitespace().next()?.parse::<f32>().ok();
+        let key = key.to_ascii_lowercase();
+        let (field, parsed) = match std_key {
+            Some(StandardTagKey::ReplayGainTrackGain) => (&mut self.track_gain, number()),
+            Some(StandardTagKey::ReplayGainTrackPeak) => (&mut self.track_peak, number()),
+            Some(StandardTagKey::ReplayGainAlbumGain) => (&mut self.album_gain, number()),
+            Some(StandardTagKey::ReplayGainAlbumPeak) => (&mut self.album_peak, number()),
+            // MP4 freeform atoms like `com.apple.iTunes:replaygain_track_gain`
+            _ if key.ends_with("replaygain_track_gain") => (&mut self.track_gain, number()),
+            _ if key.ends_with("replaygain_track_peak") => (&mut self.track_peak, number()),
+            _ if key.ends_with("replaygain_album_gain") => (&mut self.album_gain, number()),
+            _ if key.ends_with("replaygain_album_peak") => (&mut self.album_peak, number()),
+            // Q7.8 fixed point
+            _ if key == "r128_track_gain" => (
+                &mut self.track_gain,
+                value
+                    .parse::<i16>()
+                    .ok()
+                    .map(|q| f32::from(q) / 256.0 + Self::R128_OFFSET_DB),
+            ),
+            _ if key == "r128_album_gain" => (
+                &mut self.album_gain,
+                value
+                    .parse::<i16>()
+                    .ok()
+                    .map(|q| f32::from(q) / 256.0 + Self::R128_OFFSET_DB),
+            ),
+            _ => return false,
+        };
+        if field.is_none() {
+            *field = parsed;
+        }
+        true
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum TrackMetadataError {
-    #[error("could not read from path")]
-    ReadFailed(#[from] metaflac::Error),
+    #[error("could not read file: {0}")]
+    ReadFailed(#[from] SymphoniaError),
 
     #[error("could not read from path")]
     IoFailed {
@@ -40,8 +103,8 @@ pub enum TrackMetadataError {
     #[error("invalid streaminfo")]
     InvalidStreamInfo { path: PathBuf },
 
-    #[error("file missing comment")]
-    MissingComment { path: PathBuf },
+    #[error("{codec} isn't supported")]
+    UnsupportedCodec { path: PathBuf, codec: String },
 
     #[error("file missing album")]
     MissingAlbum { path: PathBuf },
@@ -88,49 +151,34 @@ impl Track {
         })?;
 
         let file_size: u64 = stat.len();
-        let tag = read_tag_from_path(path)?;
-        let streaminfo = tag
-            .get_streaminfo()
-            .ok_or(TrackMetadataError::InvalidStreamInfo {
-                path: path.to_path_buf(),
-            })?;
+        let probe = Probe::from_path(path)?;
 
-        let length_secs = calc_length_secs(streaminfo);
-        let max_block_size = streaminfo.max_block_size;
-        let total_samples = streaminfo.total_samples;
-        let sample_rate = streaminfo.sample_rate;
-        let channels = streaminfo.num_channels;
+        let length_secs = probe.length_secs();
+        let max_block_size = probe.max_block_size;
+        let total_samples = probe.total_samples;
+        let sample_rate = probe.sample_rate;
+        let channels = probe.channels;
+        let replay_gain = probe.replay_gain;
 
-        let comments = read_comments(&tag).ok_or(TrackMetadataError::MissingComment {
+        let album = probe.album.ok_or(TrackMetadataError::MissingAlbum {
             path: path.to_path_buf(),
         })?;
 
-        let album = comments.album().and_then(|s| s.first().cloned()).ok_or(
-            TrackMetadataError::MissingAlbum {
-                path: path.to_path_buf(),
-            },
-        )?;
-
-        let artist = comments.artist().and_then(|s| s.first().cloned()).ok_or(
-            TrackMetadataError::MissingArtist {
-                path: path.to_path_buf(),
-            },
-        )?;
+        let artist = probe.artist.ok_or(TrackMetadataError::MissingArtist {
+            path: path.to_path_buf(),
+        })?;
 
-        let title = comments.title().and_then(|s| s.first().cloned()).ok_or(
-            TrackMetadataError::MissingTitle {
-                path: path.to_path_buf(),
-            },
-        )?;
+        let title = probe.title.ok_or(TrackMetadataError::MissingTitle {
+            path: path.to_path_buf(),
+        })?;
 
-        let album_artist = comments
-            .album_artist()
-            .and_then(|s| s.first().cloned())
+        let album_artist = probe
+            .album_artist
             .ok_or(TrackMetadataError::MissingAlbumArtist {
                 path: path.to_path_buf(),
             })?;
 
-        let track = comments.track().ok_or(TrackMetadataError::MissingTrack {
+        let track = probe.track.ok_or(TrackMetadataError::MissingTrack {
             path: path.to_path_buf(),
         })?;
 
@@ -149,21 +197,308 @@ impl Track {
             title,
             album_artist,
             track,
+            replay_gain,
         })
     }
 }
 
-fn read_tag_from_path(path: &Path) -> Result<Tag, TrackMetadataError> {
-    Tag::read_from_path(path).map_err(TrackMetadataError::ReadFailed)
+/// Stream info and tags for any file symphonia can read
+///
+/// Tags come from whatever the file has: Vorbis comments, ID3v2, MP4 atoms
+/// or RIFF INFO chunks. The album artist falls back to the artist, since
+/// plenty of files only have the one. ReplayGain comes from either
+/// `REPLAYGAIN_*` or `R128_*` tags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub sample_rate: u32,
+    pub total_samples: u64,
+    pub channels: u8,
+    /// Most samples (per channel) in one packet, or 0 if the format doesn't say
+    pub max_block_size: u16,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub replay_gain: ReplayGain,
+}
+
+impl Probe {
+    /// Read stream info and tags from a file
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be read,
+    /// doesn't have an audio track, or is in a codec we can't decode
+    pub fn from_path(path: &Path) -> Result<Self, TrackMetadataError> {
+        let invalid = || TrackMetadataError::InvalidStreamInfo {
+            path: path.to_path_buf(),
+        };
+
+        let mut probed = open(path)?;
+        let track = probed.format.default_track().ok_or_else(invalid)?.clone();
+        let params = &track.codec_params;
+
+        // the container can be one we read while the codec inside isn't, like
+        // Opus in Ogg. better to find out now than when it comes up to play.
+        if symphonia::default::get_codecs()
+            .get_codec(params.codec)
+            .is_none()
+        {
+            return Err(TrackMetadataError::UnsupportedCodec {
+                path: path.to_path_buf(),
+                codec: codec_name(params.codec),
+            });
+        }
+
+        // some containers only know the sample rate and channels once
+        // something's been decoded
+        let (sample_rate, channels) = match (params.sample_rate, params.channels) {
+            (Some(rate), Some(channels)) => (rate, channels),
+            _ => decoded_spec(&mut probed, track.id)?.ok_or_else(invalid)?,
+        };
+
+        // without a frame count in the header, add up the packets. this starts
+        // over from the top, since finding the spec might have used some up.
+        let timeline = Timeline::new(params.time_base, sample_rate);
+        let total_samples = timeline.to_frames(match params.n_frames {
+            Some(frames) => frames,
+            None => {
+                let mut format = open(path)?.format;
+                let mut frames = 0;
+                while let Ok(packet) = format.next_packet() {
+                    if packet.track_id() == track.id {
+                        frames += packet.dur();
+                    }
+                }
+                frames
+            }
+        });
+
+        let mut probe = Self {
+            sample_rate,
+            total_samples,
+            channels: u8::try_from(channels.count()).map_err(|_| invalid())?,
+            max_block_size: params
+                .max_frames_per_packet
+                .and_then(|n| u16::try_from(n).ok())
+                .unwrap_or(0),
+            album: None,
+            artist: None,
+            title: None,
+            album_artist: None,
+            track: None,
+            replay_gain: ReplayGain::default(),
+        };
+
+        // tags in the container win over ones tacked on in front of it, like
+        // ID3v2 on a FLAC file
+        if let Some(revision) = probed.format.metadata().current() {
+            probe.add_tags(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            probe.add_tags(revision);
+        }
+        probe.album_artist = probe.album_artist.or_else(|| probe.artist.clone());
+
+        Ok(probe)
+    }
+
+    /// Fill in whatever tags we don't have yet from a metadata revision
+    fn add_tags(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            // RIFF INFO strings come with their NUL terminators
+            let value = tag.value.to_string();
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            if value.is_empty() || self.replay_gain.add_tag(&tag.key, tag.std_key, value) {
+                continue;
+            }
+            // plenty of WAV taggers use ITRK, which symphonia doesn't know
+            let key = match tag.std_key {
+                None if tag.key.eq_ignore_ascii_case("itrk") => Some(StandardTagKey::TrackNumber),
+                key => key,
+            };
+            let field = match key {
+                Some(StandardTagKey::Album) => &mut self.album,
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::AlbumArtist) => &mut self.album_artist,
+                Some(StandardTagKey::TrackNumber) => {
+                    // "3" or "3/12"
+                    let number = value.split('/').next().unwrap_or_default();
+                    self.track = self.track.or_else(|| number.trim().parse().ok());
+                    continue;
+                }
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = Some(value.to_owned());
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn length_secs(&self) -> u32 {
+        u32::try_from(self.total_samples / u64::from(self.sample_rate.max(1))).unwrap_or(u32::MAX)
+    }
+}
+
+/// Converts between a track's timestamps and frames at its sample rate
+///
+/// Timestamps are in the track's time base, which is usually one tick per
+/// frame but doesn't have to be. MP4 files count in the timescale of their
+/// track, which is often something else entirely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeline {
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+}
+
+impl Timeline {
+    #[must_use]
+    pub fn new(time_base: Option<TimeBase>, sample_rate: u32) -> Self {
+        Self {
+            time_base,
+            sample_rate,
+        }
+    }
+
+    /// The frame a timestamp falls on
+    #[must_use]
+    pub fn to_frames(self, ts: u64) -> u64 {
+        match self.time_base {
+            Some(TimeBase { numer, denom }) if denom > 0 => {
+                let frames = u128::from(ts) * u128::from(numer) * u128::from(self.sample_rate)
+                    / u128::from(denom);
+                u64::try_from(frames).unwrap_or(u64::MAX)
+            }
+            _ => ts,
+        }
+    }
+
+    /// The timestamp a frame falls on, rounded down
+    #[must_use]
+    pub fn to_timestamp(self, frames: u64) -> u64 {
+        match self.time_base {
+            Some(TimeBase { numer, denom }) if numer > 0 && self.sample_rate > 0 => {
+                let ts = u128::from(frames) * u128::from(denom)
+                    / (u128::from(numer) * u128::from(self.sample_rate));
+                u64::try_from(ts).unwrap_or(u64::MAX)
+            }
+            _ => frames,
+        }
+    }
+}
+
+/// Open a file with symphonia, using its extension as a hint for the format
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be opened or isn't
+/// in a format symphonia supports
+/// What to call a codec we have no decoder for
+fn codec_name(codec: CodecType) -> String {
+    match codec {
+        CODEC_TYPE_OPUS => "Opus".to_string(),
+        CODEC_TYPE_NULL => "An unknown codec".to_string(),
+        codec => format!("Codec {codec}"),
+    }
 }
 
-fn calc_length_secs(si: &StreamInfo) -> u32 {
-    u32::try_from(si.total_samples / u64::from(si.sample_rate))
-        .expect_or_log("calc_length_secs: overflow")
+pub fn open(path: &Path) -> Result<ProbeResult, SymphoniaError> {
+    let file = Box::new(File::open(path)?);
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    symphonia::default::get_probe().format(
+        &hint,
+        MediaSourceStream::new(file, MediaSourceStreamOptions::default()),
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )
 }
 
-fn read_comments(tag: &Tag) -> Option<VorbisComment> {
-    tag.vorbis_comments().cloned()
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("file has no audio track")]
+    NoTrack,
+
+    #[error("failed to decode audio: {0}")]
+    DecodeFailed(#[from] SymphoniaError),
+}
+
+/// Decode a whole file, handing each packet's interleaved samples to
+/// `process` along with their rate and channels
+///
+/// # Errors
+///
+/// Returns an error if the file can't be opened or decoded. Packets that
+/// fail to decode are skipped.
+pub fn decode<F>(path: &Path, mut process: F) -> Result<(), DecodeError>
+where
+    F: FnMut(&[f32], SignalSpec),
+{
+    let mut format = open(path)?.format;
+    let track = format.default_track().ok_or(DecodeError::NoTrack)?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(err)) => {
+                tracing::warn!(?path, err, "Skipping packet that failed to decode");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let spec = *decoded.spec();
+        let buf = match &mut sample_buf {
+            Some(buf) if buf.capacity() >= decoded.capacity() * spec.channels.count() => buf,
+            buf => buf.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        process(buf.samples(), spec);
+    }
+}
+
+/// Decode the first packet of a track to find its sample rate and channels
+fn decoded_spec(
+    probed: &mut ProbeResult,
+    track_id: u32,
+) -> Result<Option<(u32, Channels)>, SymphoniaError> {
+    let params = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.id == track_id && t.codec_params.codec != CODEC_TYPE_NULL)
+        .map(|t| t.codec_params.clone());
+    let Some(params) = params else {
+        return Ok(None);
+    };
+    let mut decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+    loop {
+        let packet = probed.format.next_packet()?;
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let spec = *decoder.decode(&packet)?.spec();
+        return Ok(Some((spec.rate, spec.channels)));
+    }
 }
 
 /// Returns the metadata for a file
@@ -189,3 +524,162 @@ pub fn last_modified(stat: &Metadata) -> Result<String, io::Error> {
     stat.modified()
         .map(|t| DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Secs, true))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tiny 16-bit PCM WAV file with a RIFF INFO chunk
+    fn wav_with_tags(frames: u32, tags: &[(&[u8; 4], &str)]) -> Vec<u8> {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in tags {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            info.extend_from_slice(*id);
+            info.extend_from_slice(&u32::try_from(value.len()).unwrap().to_le_bytes());
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            info.extend(value);
+        }
+
+        let data_len = frames * 4;
+        let mut body = b"WAVEfmt ".to_vec();
+        body.extend_from_slice(&16u32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        body.extend_from_slice(&2u16.to_le_bytes()); // channels
+        body.extend_from_slice(&48_000u32.to_le_bytes());
+        body.extend_from_slice(&(48_000u32 * 4).to_le_bytes());
+        body.extend_from_slice(&4u16.to_le_bytes());
+        body.extend_from_slice(&16u16.to_le_bytes());
+        body.extend_from_slice(b"LIST");
+        body.extend_from_slice(&u32::try_from(info.len()).unwrap().to_le_bytes());
+        body.extend(info);
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&data_len.to_le_bytes());
+        body.resize(body.len() + data_len as usize, 0);
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&u32::try_from(body.len()).unwrap().to_le_bytes());
+        wav.extend(body);
+        wav
+    }
+
+    #[test]
+    fn test_probe_reads_wav_info_tags() {
+        let path = std::env::temp_dir().join(format!("wigglyair-probe-{}.wav", std::process::id()));
+        let wav = wav_with_tags(
+            4800,
+            &[
+                (b"INAM", "Song"),
+                (b"IPRD", "Record"),
+                (b"IART", "Band"),
+                (b"ITRK", "3/10"),
+            ],
+        );
+        std::fs::write(&path, wav).unwrap();
+        let probe = Probe::from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let probe = probe.unwrap();
+        assert_eq!(probe.sample_rate, 48_000);
+        assert_eq!(probe.channels, 2);
+        assert_eq!(probe.total_samples, 4800);
+        assert_eq!(probe.title.as_deref(), Some("Song"));
+        assert_eq!(probe.album.as_deref(), Some("Record"));
+        assert_eq!(probe.artist.as_deref(), Some("Band"));
+        // no album artist tag, so it's the artist
+        assert_eq!(probe.album_artist.as_deref(), Some("Band"));
+        assert_eq!(probe.track, Some(3));
+    }
+
+    /// An Ogg page holding one whole packet, checksummed
+    fn ogg_page(flags: u8, granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes()); // serial
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // crc, filled in below
+        let lacing = packet.len() / 255 + 1;
+        page.push(u8::try_from(lacing).unwrap());
+        page.extend(std::iter::repeat_n(255, lacing - 1));
+        page.push(u8::try_from(packet.len() % 255).unwrap());
+        page.extend_from_slice(packet);
+
+        let crc = page.iter().fold(0u32, |crc, &byte| {
+            (0..8).fold(crc ^ (u32::from(byte) << 24), |crc, _| {
+                if crc & 0x8000_0000 == 0 {
+                    crc << 1
+                } else {
+                    (crc << 1) ^ 0x04c1_1db7
+                }
+            })
+        });
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn test_probe_rejects_opus() {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(2); // channels
+        head.extend_from_slice(&312u16.to_le_bytes()); // pre-skip
+        head.extend_from_slice(&44_100u32.to_le_bytes());
+        head.extend_from_slice(&0u16.to_le_bytes()); // gain
+        head.push(0); // channel mapping
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&4u32.to_le_bytes());
+        tags.extend_from_slice(b"test");
+        tags.extend_from_slice(&0u32.to_le_bytes());
+
+        let path = std::env::temp_dir().join(format!("wigglyair-opus-{}.ogg", std::process::id()));
+        // and one 20ms packet of silence to end the stream
+        let pages = [
+            ogg_page(0x02, 0, 0, &head),
+            ogg_page(0, 0, 1, &tags),
+            ogg_page(0x04, 960, 2, &[0xf8, 0xff, 0xfe]),
+        ];
+        std::fs::write(&path, pages.concat()).unwrap();
+        let probe = Probe::from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let error = probe.unwrap_err();
+        assert!(matches!(error, TrackMetadataError::UnsupportedCodec { .. }));
+        assert_eq!(error.to_string(), "Opus isn't supported");
+    }
+
+    #[test]
+    fn test_replay_gain_tags() {
+        let mut gain = ReplayGain::default();
+        for (key, std_key, value) in [
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                Some(StandardTagKey::ReplayGainTrackGain),
+                "-6.54 dB",
+            ),
+            (
+                "----:com.apple.iTunes:replaygain_track_peak",
+                None,
+                "0.988831",
+            ),
+            ("R128_ALBUM_GAIN", None, "-1280"),
+        ] {
+            assert!(gain.add_tag(key, std_key, value));
+        }
+        assert!(!gain.add_tag("TITLE", Some(StandardTagKey::TrackTitle), "Song"));
+        // the first one wins
+        assert!(gain.add_tag("R128_TRACK_GAIN", None, "0"));
+
+        assert_eq!(
+            gain,
+            ReplayGain {
+                track_gain: Some(-6.54),
+                track_peak: Some(0.988_831),
+                album_gain: Some(0.0),
+                album_peak: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use crate::analyzer::AudioTap;
+use crate::equalizer::Equalizer;
+use crate::queue::Queue;
+use crate::reader::{position_after, Decoded};
+use crate::ring::ChunkConsumer;
+use crate::sink::SinkError;
+use crate::speed::SpeedChanger;
+use crate::track_list::TrackList;
+use crate::types::{
+    AudioParams, BufferFill, CurrentSample, EqualizerState, PlayState, PlayerEvent,
+    ReplayGainState, SpeedState, Volume,
+};
+use audio_thread_priority::promote_current_thread_to_real_time;
+use crossbeam::channel::Sender;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_unwrap::*;
+
+/// Output gain that follows pauses and volume changes a frame at a time
+/// instead of jumping, so they don't click
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GainRamp {
+    fade_time: Duration,
+    /// 0 when paused, 1 when playing, and in between while fading
+    fade: f32,
+    volume: f32,
+}
+
+impl GainRamp {
+    pub(crate) const DEFAULT_FADE: Duration = Duration::from_millis(30);
+    /// Time constant for following volume changes
+    const VOLUME_SMOOTHING: Duration = Duration::from_millis(10);
+
+    /// Starts silent, so playback fades in
+    pub(crate) fn new(fade_time: Duration, volume: f32) -> Self {
+        Self {
+            fade_time,
+            fade: 0.0,
+            volume,
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.fade == 0.0
+    }
+
+    fn fade_step(&self, sample_rate: u32) -> f32 {
+        let frames = self.fade_time.as_secs_f32() * sample_rate as f32;
+        if frames < 1.0 {
+            1.0
+        } else {
+            frames.recip()
+        }
+    }
+
+    /// How many frames it takes to fade out from where we are
+    fn frames_to_silence(&self, sample_rate: u32) -> usize {
+        (self.fade / self.fade_step(sample_rate)).ceil() as usize
+    }
+
+    /// Scale interleaved samples, moving towards silence or full volume
+    fn apply(
+        &mut self,
+        data: &mut [f32],
+        channels: usize,
+        sample_rate: u32,
+        playing: bool,
+        volume: f32,
+    ) {
+        let step = self.fade_step(sample_rate);
+        let smoothing =
+            1.0 - (-1.0 / (Self::VOLUME_SMOOTHING.as_secs_f32() * sample_rate as f32)).exp();
+        for frame in data.chunks_exact_mut(channels) {
+            self.fade = if playing {
+                (self.fade + step).min(1.0)
+            } else {
+                (self.fade - step).max(0.0)
+            };
+            self.volume += (volume - self.volume) * smoothing;
+            if (volume - self.volume).abs() < 1e-5 {
+                self.volume = volume;
+            }
+            let gain = self.fade * self.volume;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// Why the output thread needs to wake up
+#[derive(Debug)]
+pub(crate) enum OutputSignal {
+    /// Playback is over, so the device can be shut down
+    Done,
+    /// The next samples are at a different rate; reopen the device at it
+    Reopen(u32),
+    /// The sink couldn't take any more audio
+    Failed(SinkError),
+}
+
+/// What the audio callback hands to the dispatcher thread
+pub(crate) enum CallbackMessage {
+    Event(PlayerEvent),
+    /// A queue snapshot that's been replaced, to be freed off the audio thread
+    Retire(Arc<TrackList>),
+}
+
+/// Everything the audio callback works with. It lives outside the callback so
+/// it survives the device being reopened at a new rate.
+///
+/// Once the audio thread has been promoted, rendering never allocates or
+/// waits on a lock. The locks it does touch are only ever tried, and it
+/// carries on with what it has if they're taken.
+pub(crate) struct Output {
+    pub(crate) params: AudioParams,
+    pub(crate) device_rate: u32,
+    pub(crate) queue: Arc<Queue>,
+    pub(crate) current_sample: Arc<CurrentSample>,
+    pub(crate) buffer_fill: Arc<BufferFill>,
+    pub(crate) current_track: Arc<AtomicUsize>,
+    pub(crate) play_state: Arc<PlayState>,
+    pub(crate) volume: Arc<Volume>,
+    pub(crate) replay_gain: Arc<ReplayGainState>,
+    pub(crate) preamp_db: f32,
+    /// Coefficients for every preset at `device_rate`, so switching doesn't
+    /// allocate in the callback
+    pub(crate) equalizer: Equalizer,
+    pub(crate) equalizer_preset: Arc<EqualizerState>,
+    pub(crate) speed: Arc<SpeedState>,
+    /// Sized for `device_rate`, so changing speed doesn't allocate in the
+    /// callback
+    pub(crate) speed_changer: SpeedChanger,
+    /// Gets a copy of everything that's rendered, for visualizers
+    pub(crate) tap: Arc<AudioTap>,
+    pub(crate) ramp: GainRamp,
+    pub(crate) generation: Arc<AtomicU64>,
+    pub(crate) stopped: Arc<AtomicBool>,
+    /// Whether the sink plays audio as it's rendered, so running out means
+    /// padding with silence, rather than waiting for more
+    pub(crate) realtime: bool,
+    pub(crate) samples: ChunkConsumer<Decoded>,
+    pub(crate) signals: Sender<OutputSignal>,
+    pub(crate) messages: Sender<CallbackMessage>,
+
+    pub(crate) initialized: bool,
+    pub(crate) is_done: bool,
+    pub(crate) reopening: bool,
+    pub(crate) last_generation: u64,
+
+    // our own snapshot of the queue, swapped for a fresh one after edits
+    pub(crate) track_list: Arc<TrackList>,
+    pub(crate) queue_version: u64,
+
+    // the reader has sent everything in the track list, and whether
+    // we've played all of it and told everyone about it.
+    pub(crate) at_end: bool,
+    pub(crate) finished: bool,
+
+    // the track we last announced with `TrackStarted`, and whether we got
+    // to the current one by seeking rather than by finishing the last one.
+    pub(crate) playing_track: Option<usize>,
+    pub(crate) last_position: u64,
+    pub(crate) seeked: bool,
+    /// Where the last sample that went into the speed changer came from, as
+    /// the end of the chunk it was read up to, the chunk's length and its
+    /// number of samples
+    pub(crate) pushed: Option<(u64, u64, usize)>,
+}
+
+impl Output {
+    /// Fill `data` with what's up next, returning how much of it is meant to
+    /// be played
+    pub(crate) fn render(&mut self, data: &mut [f32]) -> usize {
+        if self.stopped.load(Ordering::SeqCst) && !self.is_done {
+            self.finish();
+        }
+        if self.is_done {
+            return self.silence(data);
+        }
+
+        // a seek happened since the last callback, so whatever's buffered is
+        // from the old position.
+        let current_generation = self.generation.load(Ordering::SeqCst);
+        if current_generation != self.last_generation {
+            self.restart(current_generation);
+        }
+
+        let paused = self.play_state.is_paused();
+        if paused && self.ramp.is_silent() {
+            // keep clearing out stale samples, so the reader has room to
+            // decode from wherever we've seeked to
+            while self
+                .samples
+                .current()
+                .is_some_and(|(header, ..)| header.generation() < self.last_generation)
+            {
+                self.samples.skip();
+            }
+            return self.silence(data);
+        }
+
+        let channel_count = usize::from(self.params.channel_count);
+        // when pausing, only take as much as it takes to fade out. the rest
+        // stays buffered for when we resume.
+        let size = if paused {
+            data.len()
+                .min(self.ramp.frames_to_silence(self.device_rate) * channel_count)
+        } else {
+            data.len()
+        };
+
+        if !self.initialized {
+            if self.realtime {
+                let _tid = promote_current_thread_to_real_time(
+                    self.params.audio_buffer_frames(),
+                    self.device_rate,
+                )
+                .unwrap_or_log();
+            }
+            self.initialized = true;
+        }
+
+        let version = self.queue.version();
+        if version != self.queue_version {
+            if let Some(snapshot) = self.queue.try_snapshot() {
+                let old = std::mem::replace(&mut self.track_list, snapshot);
+                // if the dispatcher's backed up, the snapshot gets freed here
+                // instead, which beats holding on to it
+                let _ = self.messages.try_send(CallbackMessage::Retire(old));
+                self.queue_version = version;
+            }
+        }
+
+        let replay_gain = self.replay_gain.get();
+        let (speed, speed_mode) = (self.speed.get(), self.speed.mode());
+        self.speed_changer.set(speed, speed_mode);
+
+        let mut filled = 0;
+        let mut played = None;
+        let mut new_rate = None;
+        while filled < size {
+            if !self.speed_changer.is_bypassed() {
+                let n = self.speed_changer.read(&mut data[filled..size]);
+                if n > 0 {
+                    filled += n;
+                    played = self.changer_position().or(played);
+                }
+                if filled == size {
+                    break;
+                }
+            }
+            // whatever was buffered before a switch has played out by now, so
+            // the new setting can take over
+            self.speed_changer.set(speed, speed_mode);
+            let stretching = !self.speed_changer.is_bypassed();
+            let Some((&header, read, len)) = self.samples.current() else {
+                if self.samples.is_abandoned() {
+                    self.finish();
+                }
+                break;
+            };
+
+            if header.generation() < self.last_generation {
+                self.samples.skip();
+                continue;
+            }
+            if header.generation() > self.last_generation {
+                self.restart(header.generation());
+            }
+
+            match header {
+                Decoded::Samples { sample_rate, .. } if sample_rate != self.device_rate => {
+                    // everything at the old rate has been played, so leave
+                    // these for once the device has been reopened
+                    self.speed_changer.finish();
+                    let n = self.speed_changer.read(&mut data[filled..size]);
+                    if n > 0 {
+                        filled += n;
+                        played = self.changer_position().or(played);
+                    }
+                    if self.speed_changer.is_empty() {
+                        new_rate = Some(sample_rate);
+                    }
+                    break;
+                }
+                Decoded::Samples {
+                    position,
+                    length,
+                    gain,
+                    ..
+                } => {
+                    let scale = replay_gain.factor(&gain, self.preamp_db);
+                    if stretching {
+                        // only take about as much as is still needed, so the
+                        // changer doesn't hold on to much more than it must
+                        let (samples, wanted) = (&mut self.samples, size - filled);
+                        let n = self.speed_changer.push_with(|input| {
+                            let end = input.len().min(wanted);
+                            let (n, _) = samples.read(&mut input[..end]);
+                            for sample in &mut input[..n] {
+                                *sample *= scale;
+                            }
+                            n
+                        });
+                        if n == 0 {
+                            break;
+                        }
+                        // what's heard is whatever comes out of the changer,
+                        // which is behind what goes in
+                        let end = position_after(position, length, read + n, len);
+                        self.pushed = Some((end, length, len));
+                    } else {
+                        let (n, _) = self.samples.read(&mut data[filled..size]);
+                        for sample in &mut data[filled..filled + n] {
+                            *sample *= scale;
+                        }
+                        filled += n;
+                        played = Some(position_after(position, length, read + n, len));
+                    }
+                    self.at_end = false;
+                    self.finished = false;
+                }
+                Decoded::EndOfList { .. } => {
+                    self.samples.skip();
+                    self.speed_changer.finish();
+                    self.at_end = true;
+                }
+            }
+        }
+
+        self.buffer_fill
+            .set(self.samples.buffered() / channel_count, self.device_rate);
+
+        // the last buffer is unlikely to be perfectly full, so whatever we
+        // don't have gets padded with zeroes.
+        data[filled..].fill(0.0);
+        let underrun = filled < size && !self.is_done && !self.at_end && new_rate.is_none();
+        if underrun && self.realtime {
+            self.publish(PlayerEvent::BufferUnderrun {
+                missing_samples: size - filled,
+            });
+        }
+
+        self.equalizer
+            .process(&mut data[..filled], self.equalizer_preset.get());
+
+        // anything that isn't played in real time can wait for the rest
+        let rendered = if self.realtime { data.len() } else { filled };
+        let volume = self.volume.gain();
+        self.ramp.apply(
+            &mut data[..rendered],
+            channel_count,
+            self.device_rate,
+            !paused,
+            volume,
+        );
+
+        if let Some(rate) = new_rate {
+            if !self.reopening {
+                self.reopening = true;
+                // the output thread is already being told something if this
+                // fails, so there's nothing more to do
+                let _ = self.signals.try_send(OutputSignal::Reopen(rate));
+            }
+        }
+
+        // don't move the position if a seek came in while we were
+        // filling the buffer; the seek already set it.
+        if self.generation.load(Ordering::SeqCst) == self.last_generation {
+            let position = played.unwrap_or_else(|| self.current_sample.get());
+            self.current_sample.set(position);
+
+            if self.at_end {
+                if !self.finished && self.speed_changer.is_empty() {
+                    if let Some(index) = self.playing_track.take() {
+                        self.publish(PlayerEvent::TrackFinished { index });
+                    }
+                    self.publish(PlayerEvent::PlaylistFinished);
+                    self.finished = true;
+                }
+                return rendered;
+            }
+
+            let track = self.track_list.find_playing(position);
+            self.current_track.store(track, Ordering::SeqCst);
+
+            // going backwards without a seek means we're repeating
+            let repeated = position < self.last_position && !self.seeked;
+            if self.playing_track != Some(track) || repeated {
+                if let (Some(index), false) = (self.playing_track, self.seeked) {
+                    self.publish(PlayerEvent::TrackFinished { index });
+                }
+                self.publish(PlayerEvent::TrackStarted { index: track });
+                self.playing_track = Some(track);
+            }
+            self.last_position = position;
+            self.seeked = false;
+        }
+        rendered
+    }
+
+    /// Where the last sample read out of the speed changer came from, going
+    /// back from the last one that went in by however much it's still holding
+    fn changer_position(&self) -> Option<u64> {
+        let (end, length, len) = self.pushed?;
+        let held = position_after(0, length, self.speed_changer.buffered(), len);
+        Some(end.saturating_sub(held))
+    }
+
+    fn silence(&self, data: &mut [f32]) -> usize {
+        data.fill(0.0);
+        if self.realtime {
+            data.len()
+        } else {
+            0
+        }
+    }
+
+    /// Throw away everything from before a seek
+    fn restart(&mut self, generation: u64) {
+        self.last_generation = generation;
+        self.seeked = true;
+        self.at_end = false;
+        self.finished = false;
+        self.speed_changer.reset();
+        self.pushed = None;
+    }
+
+    fn finish(&mut self) {
+        let _ = self.signals.try_send(OutputSignal::Done);
+        self.is_done = true;
+    }
+
+    /// Events get dropped if the dispatcher's too far behind
+    fn publish(&self, event: PlayerEvent) {
+        let _ = self.messages.try_send(CallbackMessage::Event(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gain_ramps_instead_of_jumping() {
+        let rate = 1_000;
+        let mut ramp = GainRamp::new(Duration::from_millis(10), 1.0);
+        let max_jump = |data: &[f32]| {
+            data.windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0.0, f32::max)
+        };
+
+        // fading in from the start
+        let mut data = vec![1.0; 20];
+        ramp.apply(&mut data, 1, rate, true, 1.0);
+        assert!(data[0] <= 0.1 && data[19] == 1.0);
+        assert!(max_jump(&data) <= 0.1 + 1e-6);
+
+        // pausing takes exactly as long as the fade
+        assert_eq!(ramp.frames_to_silence(rate), 10);
+        let mut data = vec![1.0; 20];
+        ramp.apply(&mut data, 1, rate, false, 1.0);
+        assert!(ramp.is_silent() && data[9] == 0.0);
+
+        // a big volume change gets smoothed out
+        ramp.apply(&mut [1.0; 20], 1, rate, true, 1.0);
+        let mut data = vec![1.0; 100];
+        ramp.apply(&mut data, 1, rate, true, 0.1);
+        assert!(data[0] > 0.8 && (data[99] - 0.1).abs() < 1e-3);
+        assert!(max_jump(&data) < 0.1);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::track_list::{Track, TrackList};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tracing_unwrap::*;
+
+#[derive(Error, Debug)]
+pub(crate) enum QueueError {
+    #[error("no track at index {0}")]
+    OutOfBounds(usize),
+
+    #[error("can't remove the last track from the queue")]
+    WouldBeEmpty,
+
+    #[error("new order isn't a permutation of the queue")]
+    InvalidOrder,
+}
+
+/// A change to the track list while the player is running
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum QueueEdit {
+    Append(Vec<Track>),
+    Remove(usize),
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// Remove every track except the one at `keep`
+    Clear {
+        keep: usize,
+    },
+    /// Rearrange the whole queue so the track at `order[i]` ends up at `i`
+    Reorder(Vec<usize>),
+}
+
+impl QueueEdit {
+    pub(crate) fn apply_to(&self, list: &mut TrackList) -> Result<(), QueueError> {
+        let len = list.tracks.len();
+        let check = |index: usize| {
+            if index < len {
+                Ok(())
+            } else {
+                Err(QueueError::OutOfBounds(index))
+            }
+        };
+
+        match self {
+            Self::Append(tracks) => list.add_tracks(tracks.clone()),
+            Self::Remove(index) => {
+                check(*index)?;
+                if len == 1 {
+                    return Err(QueueError::WouldBeEmpty);
+                }
+                list.remove_track(*index);
+            }
+            Self::Move { from, to } => {
+                check(*from)?;
+                check(*to)?;
+                list.move_track(*from, *to);
+            }
+            Self::Clear { keep } => {
+                check(*keep)?;
+                list.tracks = vec![list.tracks[*keep].clone()];
+                list.recount();
+            }
+            Self::Reorder(order) => {
+                if order.len() != len || order.iter().collect::<HashSet<_>>().len() != len {
+                    return Err(QueueError::InvalidOrder);
+                }
+                check(order.iter().copied().max().unwrap_or_default())?;
+                list.tracks = order.iter().map(|&i| list.tracks[i].clone()).collect();
+                list.recount();
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the track at `index` ends up after this edit, or `None` if it
+    /// was removed
+    pub(crate) fn map_index(&self, index: usize) -> Option<usize> {
+        match *self {
+            Self::Append(_) => Some(index),
+            Self::Remove(removed) => match index.cmp(&removed) {
+                std::cmp::Ordering::Less => Some(index),
+                std::cmp::Ordering::Equal => None,
+                std::cmp::Ordering::Greater => Some(index - 1),
+            },
+            Self::Move { from, to } => {
+                if index == from {
+                    return Some(to);
+                }
+                let index = if index > from { index - 1 } else { index };
+                Some(if index >= to { index + 1 } else { index })
+            }
+            Self::Clear { keep } => (index == keep).then_some(0),
+            Self::Reorder(ref order) => order.iter().position(|&i| i == index),
+        }
+    }
+}
+
+/// What an edit did to the queue
+pub(crate) struct QueueEdited {
+    pub(crate) old: Arc<TrackList>,
+    pub(crate) new: Arc<TrackList>,
+    /// Whether anything the reader already decoded moved or went away. If
+    /// not, the audio in flight is still right and nothing needs flushing.
+    pub(crate) needs_seek: bool,
+}
+
+/// The track list shared by the player, its reader and the output callback
+///
+/// Everyone works from an `Arc<TrackList>` snapshot, and edits swap in a new
+/// one so readers never see a half-edited list.
+pub(crate) struct Queue {
+    tracks: RwLock<Arc<TrackList>>,
+    version: AtomicU64,
+    /// Index of the track the reader is decoding. Only written while holding
+    /// the read lock, so an edit holding the write lock sees a stable value.
+    decoding: AtomicUsize,
+}
+
+impl Queue {
+    pub(crate) fn new(track_list: TrackList) -> Self {
+        Self {
+            tracks: RwLock::new(Arc::new(track_list)),
+            version: AtomicU64::new(0),
+            decoding: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Arc<TrackList> {
+        self.tracks.read().unwrap_or_log().clone()
+    }
+
+    /// Like `snapshot`, but gives up instead of waiting on an edit in progress
+    pub(crate) fn try_snapshot(&self) -> Option<Arc<TrackList>> {
+        self.tracks.try_read().ok().map(|tracks| tracks.clone())
+    }
+
+    /// Bumped every time the queue is edited
+    pub(crate) fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Index of the track the reader is decoding
+    pub(crate) fn decoding(&self) -> usize {
+        self.decoding.load(Ordering::SeqCst)
+    }
+
+    /// Find the track the reader should decode at `position`, and record that
+    /// it's decoding it. Returns `None` once `position` is past the end.
+    pub(crate) fn begin_decoding(&self, position: u64) -> Option<(usize, Track, (u64, u64))> {
+        let tracks = self.tracks.read().unwrap_or_log();
+        if position >= tracks.total_samples {
+            return None;
+        }
+        let index = tracks.find_playing(position);
+        self.decoding.store(index, Ordering::SeqCst);
+        Some((
+            index,
+            tracks.get_track(index).clone(),
+            tracks.get_bounds(index),
+        ))
+    }
+
+    pub(crate) fn edit(&self, edit: &QueueEdit) -> Result<QueueEdited, QueueError> {
+        let mut tracks = self.tracks.write().unwrap_or_log();
+        let old = tracks.clone();
+        let mut new = TrackList::clone(&old);
+        edit.apply_to(&mut new)?;
+
+        // everything up to and including the track being decoded has already
+        // been sent to the output, so it has to be exactly where it was.
+        let decoding = self
+            .decoding
+            .load(Ordering::SeqCst)
+            .min(old.tracks.len() - 1);
+        let needs_seek = new.tracks.get(..=decoding) != Some(&old.tracks[..=decoding]);
+
+        let new = Arc::new(new);
+        *tracks = new.clone();
+        self.version.fetch_add(1, Ordering::SeqCst);
+        Ok(QueueEdited {
+            old,
+            new,
+            needs_seek,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track_list::tests::test_track;
+    use itertools::Itertools;
+    use proptest::prelude::*;
+
+    /// How `find_playing` used to work, adding up tracks until one ends
+    /// after `sample`
+    fn linear_find_playing(list: &TrackList, sample: u64) -> usize {
+        let mut end = 0;
+        for (i, track) in list.tracks.iter().enumerate() {
+            end += track.samples_at(list.sample_rate);
+            if end > sample {
+                return i;
+            }
+        }
+        list.tracks.len().saturating_sub(1)
+    }
+
+    fn linear_start_point(list: &TrackList, index: usize) -> u64 {
+        list.tracks
+            .iter()
+            .take(index)
+            .map(|t| t.samples_at(list.sample_rate))
+            .sum()
+    }
+
+    proptest! {
+        #[test]
+        fn test_queue_edit_map_index_follows_tracks(
+            (len, a, b) in (2usize..20).prop_flat_map(|len| (Just(len), 0..len, 0..len))
+        ) {
+            let old = TrackList::from((0..len).map(|i| test_track("A", i as u32, 100)).collect_vec());
+            let edits = [
+                QueueEdit::Move { from: a, to: b },
+                QueueEdit::Remove(a),
+                QueueEdit::Clear { keep: a },
+                QueueEdit::Append(vec![test_track("B", 1, 100)]),
+                QueueEdit::Reorder((0..len).rev().collect()),
+            ];
+            for edit in edits {
+                let mut new = old.clone();
+                edit.apply_to(&mut new).unwrap();
+                prop_assert_eq!(new.total_samples, new.tracks.iter().map(|t| t.samples).sum::<u64>());
+                for (i, track) in old.tracks.iter().enumerate() {
+                    if let Some(j) = edit.map_index(i) {
+                        prop_assert_eq!(&new.tracks[j], track);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_offsets_match_linear_lookups(
+            tracks in proptest::collection::vec((0u64..500, any::<bool>()), 1..30),
+            edits in proptest::collection::vec((0u8..4, any::<usize>(), any::<usize>()), 0..10),
+        ) {
+            // lengths at both rates, including empty tracks
+            let track = |i: usize, (samples, high): (u64, bool)| {
+                let mut track = test_track("A", i as u32, samples);
+                track.sample_rate = if high { 48_000 } else { 44_100 };
+                track
+            };
+            let mut list = TrackList::from(tracks.iter().enumerate().map(|(i, &t)| track(i, t)).collect_vec());
+            let check = |list: &TrackList| {
+                let len = list.tracks.len();
+                let total = (0..len).map(|i| list.get_sample_count(i)).sum::<u64>();
+                prop_assert_eq!(list.total_samples, total);
+                for index in 0..=len + 1 {
+                    prop_assert_eq!(list.get_start_point(index), linear_start_point(list, index));
+                }
+                let near_edges = (0..=len).flat_map(|i| {
+                    let edge = linear_start_point(list, i);
+                    [edge.saturating_sub(1), edge, edge + 1]
+                });
+                for sample in near_edges {
+                    prop_assert_eq!(list.find_playing(sample), linear_find_playing(list, sample));
+                }
+                Ok(())
+            };
+            check(&list)?;
+
+            for (kind, a, b) in edits {
+                let len = list.tracks.len();
+                let edit = match kind {
+                    0 => QueueEdit::Append(vec![track(len, (a as u64 % 500, b % 2 == 0))]),
+                    1 if len > 1 => QueueEdit::Remove(a % len),
+                    2 => QueueEdit::Move { from: a % len, to: b % len },
+                    _ => QueueEdit::Reorder((0..len).rev().collect()),
+                };
+                edit.apply_to(&mut list).unwrap();
+                check(&list)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,874 @@
+use crate::files::SkippedFile;
+use crate::metadata::{self, ReplayGain, Timeline};
+use crate::queue::Queue;
+use crate::remix::Remix;
+use crate::resample::{self, RateConversion, Resampler};
+use crate::ring::ChunkProducer;
+use crate::track_list::Track;
+use crate::types::{
+    AudioParams, PlayerEvent, PlayerEvents, RepeatMode, RepeatState, ReplayGainState, SkippedFiles,
+};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use std::f32::consts::FRAC_PI_2;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
+use symphonia::core::units::TimeBase;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ReaderCommand {
+    Seek {
+        generation: u64,
+        sample: u64,
+    },
+    /// Something the reader depends on changed, like the queue or the repeat
+    /// mode, without touching anything already decoded
+    Wake,
+    Stop,
+}
+
+/// The header of each chunk the reader puts in the ring buffer for the
+/// output callback, tagged with the seek generation it was decoded for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Decoded {
+    /// Interleaved samples at `sample_rate`, covering `length` samples of
+    /// the track list from `position`, from a track with the given `gain`
+    Samples {
+        generation: u64,
+        position: u64,
+        length: u64,
+        sample_rate: u32,
+        gain: ReplayGain,
+    },
+    /// Everything in the track list has been sent. Comes with no samples.
+    EndOfList { generation: u64 },
+}
+
+impl Decoded {
+    pub(crate) fn generation(&self) -> u64 {
+        match self {
+            Self::Samples { generation, .. } | Self::EndOfList { generation } => *generation,
+        }
+    }
+}
+
+/// Where in the track list we are once `read` of the `len` samples in a
+/// chunk covering `length` from `position` have been played. The two lengths
+/// differ when the device isn't running at the track list's rate.
+pub(crate) fn position_after(position: u64, length: u64, read: usize, len: usize) -> u64 {
+    let covered = u128::from(length) * read as u128 / len.max(1) as u128;
+    position + u64::try_from(covered).unwrap_or(length)
+}
+
+/// The end of a track, held back from the output in case it gets crossfaded
+/// into the next one
+pub(crate) struct Tail {
+    /// Where the held back samples start in the track list
+    position: u64,
+    /// Where the track they're from ends in the track list
+    end: u64,
+    sample_rate: u32,
+    gain: ReplayGain,
+    samples: Vec<f32>,
+}
+
+/// A tail waiting for enough of the next track to be mixed with it
+pub(crate) struct Fade {
+    tail: Tail,
+    /// The start of the next track, at the tail's sample rate
+    incoming: Vec<f32>,
+    /// Where the incoming samples start and their track ends in the track list
+    start: u64,
+    end: u64,
+    gain: ReplayGain,
+}
+
+/// Decodes the queue into the ring buffer, starting at `position` and
+/// following commands as they come in.
+///
+/// Once everything is decoded it waits for a seek or for more tracks, so it
+/// keeps running until it's told to stop.
+pub(crate) struct FileReader {
+    pub(crate) queue: Arc<Queue>,
+    pub(crate) repeat: Arc<RepeatState>,
+    pub(crate) params: AudioParams,
+    pub(crate) conversion: RateConversion,
+    pub(crate) crossfade: Duration,
+    /// How little and how much decoded audio to keep buffered
+    pub(crate) watermarks: (Duration, Duration),
+    pub(crate) replay_gain: Arc<ReplayGainState>,
+    pub(crate) preamp_db: f32,
+    pub(crate) tail: Option<Tail>,
+    pub(crate) fading: Option<Fade>,
+    pub(crate) position: u64,
+    pub(crate) generation: u64,
+    pub(crate) samples: ChunkProducer<Decoded>,
+    pub(crate) commands_rx: Receiver<ReaderCommand>,
+    pub(crate) events: PlayerEvents,
+    pub(crate) skipped: SkippedFiles,
+    pub(crate) stopped: bool,
+    /// A command came in while waiting for room in the ring buffer, so
+    /// whatever was being decoded is out of date
+    pub(crate) interrupted: bool,
+}
+
+impl FileReader {
+    /// Room for more chunks than could ever fit in the ring buffer, since
+    /// decoders hand back hundreds of frames at a time
+    pub(crate) const MAX_CHUNKS: usize = 4096;
+    /// Stop decoding once 6 seconds are buffered, and start again once it's
+    /// down to 3
+    pub(crate) const DEFAULT_WATERMARKS: (Duration, Duration) =
+        (Duration::from_secs(3), Duration::from_secs(6));
+    /// How often to check on the buffer while waiting for it to drain
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+    /// How many packets in a row can fail to decode before the rest of the
+    /// file is given up on
+    const MAX_DECODE_ERRORS: usize = 10;
+
+    pub(crate) fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+
+    fn run(mut self) {
+        // whether we've sent anything since starting from the top, so a queue
+        // full of broken files doesn't spin forever on repeat.
+        let mut sent_this_pass = false;
+
+        'tracks: while !self.stopped {
+            if std::mem::take(&mut self.interrupted) {
+                // anything held back since the command is from before it
+                self.tail = None;
+                self.fading = None;
+            }
+
+            let Some((index, track, (start, end))) = self.queue.begin_decoding(self.position)
+            else {
+                // there's nothing left to fade into
+                self.finish_fade();
+                if self.interrupted {
+                    continue;
+                }
+
+                if self.repeat.get() == RepeatMode::All && sent_this_pass {
+                    tracing::info!("Repeating track list");
+                    self.position = 0;
+                    sent_this_pass = false;
+                    continue;
+                }
+
+                tracing::info!(position = self.position, "Reached end of track list");
+                self.send(
+                    Decoded::EndOfList {
+                        generation: self.generation,
+                    },
+                    &[],
+                );
+                if self.interrupted {
+                    continue;
+                }
+                match self.commands_rx.recv() {
+                    Ok(command) => self.handle_command(command),
+                    Err(_) => break,
+                };
+                continue 'tracks;
+            };
+            let path = track.path.clone();
+            if self.skipped.contains(&path) {
+                tracing::debug!(?path, "Skipping broken file");
+                self.position = end;
+                continue;
+            }
+
+            // positions in the track list are at its sample rate, which might
+            // not be the file's
+            let rate = self.params.sample_rate;
+            let native_rate = track.sample_rate;
+            let offset = resample::convert_frames(self.position - start, rate, native_rate);
+            tracing::info!(?path, index, offset, native_rate, "Reading audio file");
+
+            let outputs = usize::from(self.params.channel_count);
+            let mut resampler = match self.conversion {
+                RateConversion::Resample(quality) if native_rate != rate => {
+                    match Resampler::new(native_rate, rate, outputs, quality) {
+                        Ok(resampler) => Some(resampler),
+                        Err(error) => {
+                            self.give_up_on(&path, format!("can't resample: {error}"));
+                            self.position = end;
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+            // the file's frame where resampled output starts, and how much
+            // output there's been since
+            let mut resampled_from = None;
+            let mut resampled = 0u64;
+
+            let (mut format, mut decoder, track_id, time_base) = match open_file(&path) {
+                Ok(opened) => opened,
+                Err(error) => {
+                    self.give_up_on(&path, format!("can't open: {error}"));
+                    self.position = end;
+                    continue;
+                }
+            };
+
+            // frames before `skip_until` get decoded but not sent. accurate seeks
+            // land on the packet containing the timestamp we asked for, so we
+            // trim the front of that packet off.
+            let timeline = Timeline::new(time_base, native_rate);
+            let skip_until = offset;
+            if offset > 0 {
+                let to = SeekTo::TimeStamp {
+                    ts: timeline.to_timestamp(offset),
+                    track_id,
+                };
+                match format.seek(SeekMode::Accurate, to) {
+                    Ok(seeked) => {
+                        tracing::debug!(?seeked, "Seeked in file");
+                        decoder.reset();
+                    }
+                    Err(err) => {
+                        tracing::warn!(%err, ?path, "Seek failed; decoding from start");
+                    }
+                }
+            }
+
+            let mut sample_buf = None;
+            let mut sent_samples = 0u64;
+            let mut decode_errors = 0;
+            loop {
+                if self.interrupted {
+                    continue 'tracks;
+                }
+                if let Ok(command) = self.commands_rx.try_recv() {
+                    if self.handle_command(command) {
+                        continue 'tracks;
+                    }
+                }
+
+                let packet = match format.next_packet() {
+                    Ok(packet) => packet,
+                    Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => {
+                        self.give_up_on(&path, format!("can't read: {err}"));
+                        break;
+                    }
+                };
+
+                if packet.track_id() != track_id {
+                    continue;
+                }
+
+                match decoder.decode(&packet) {
+                    Ok(audio_buf) => {
+                        decode_errors = 0;
+                        if sample_buf.is_none() {
+                            let spec = *audio_buf.spec();
+                            let duration = audio_buf.capacity();
+                            let remix = Remix::new(spec.channels, outputs);
+                            tracing::info!(?spec, ?remix, "Decoded audio buffer");
+                            sample_buf =
+                                Some((spec, SampleBuffer::new(duration as u64, spec), remix));
+                        }
+
+                        if let Some((spec, buf, remix)) = &mut sample_buf {
+                            buf.copy_interleaved_ref(audio_buf);
+
+                            let channels = spec.channels.count();
+                            let packet_frame = timeline.to_frames(packet.ts());
+                            let skip = skip_until.saturating_sub(packet_frame);
+                            let skip = usize::try_from(skip).unwrap_or(usize::MAX);
+                            let skip = skip.saturating_mul(channels).min(buf.len());
+                            if skip == buf.len() {
+                                continue;
+                            }
+
+                            let samples = &buf.samples()[skip..];
+                            let remixed;
+                            let samples = if remix.is_passthrough() {
+                                samples
+                            } else {
+                                remixed = remix.apply(samples);
+                                &remixed
+                            };
+                            let frame = packet_frame.max(skip_until);
+                            let (position, sample_rate, samples) = match &mut resampler {
+                                Some(resampler) => {
+                                    let from = *resampled_from.get_or_insert(frame);
+                                    let position =
+                                        resample::convert_frames(from, native_rate, rate)
+                                            + resampled;
+                                    let samples = resampler.process(samples);
+                                    resampled += (samples.len() / outputs) as u64;
+                                    (position, rate, samples)
+                                }
+                                None => (
+                                    resample::convert_frames(frame, native_rate, rate),
+                                    native_rate,
+                                    samples.to_owned(),
+                                ),
+                            };
+                            if samples.is_empty() {
+                                continue;
+                            }
+
+                            sent_samples += samples.len() as u64;
+                            sent_this_pass = true;
+                            self.emit(&track, position, sample_rate, samples, (start, end));
+                        }
+                    }
+                    Err(err @ Error::DecodeError(_)) => {
+                        tracing::error!(%err, "Audio loop: decode error");
+                        self.publish_decode_error(&path, &err);
+                        decode_errors += 1;
+                        if decode_errors == Self::MAX_DECODE_ERRORS {
+                            self.give_up_on(&path, format!("can't decode: {err}"));
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        self.give_up_on(&path, format!("can't decode: {err}"));
+                        break;
+                    }
+                }
+            }
+            // push out whatever the resampler is still holding on to
+            if let (Some(resampler), Some(from)) = (resampler, resampled_from) {
+                let samples = resampler.finish();
+                if !samples.is_empty() {
+                    let position = resample::convert_frames(from, native_rate, rate) + resampled;
+                    sent_samples += samples.len() as u64;
+                    self.emit(&track, position, rate, samples, (start, end));
+                }
+            }
+
+            self.finish_track(index, &track);
+            if self.interrupted {
+                // the command already decided where to go next
+                continue;
+            }
+
+            tracing::info!(sent_samples, ?path, "Finished reading file");
+            self.position = if self.repeat.get() == RepeatMode::One && sent_samples > 0 {
+                start
+            } else {
+                end
+            };
+        }
+    }
+
+    /// Returns whether the file being decoded should be abandoned
+    fn handle_command(&mut self, command: ReaderCommand) -> bool {
+        tracing::debug!(?command, "Reader command");
+        match command {
+            ReaderCommand::Seek { generation, sample } => {
+                // the output skips anything left over from the old generation
+                self.tail = None;
+                self.fading = None;
+                self.generation = generation;
+                self.position = sample;
+                true
+            }
+            // the next lookup in the queue picks up the changes
+            ReaderCommand::Wake => false,
+            ReaderCommand::Stop => {
+                self.stopped = true;
+                true
+            }
+        }
+    }
+
+    /// Skip over the rest of `path`, and over it altogether from now on
+    fn give_up_on(&self, path: &Path, reason: String) {
+        tracing::error!(?path, reason, "Skipping file");
+        let file = SkippedFile {
+            path: path.to_owned(),
+            reason,
+        };
+        self.skipped.publish(file, &self.events);
+    }
+
+    fn publish_decode_error(&self, path: &Path, error: &Error) {
+        self.events.publish(PlayerEvent::DecodeError {
+            path: path.to_owned(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Send samples starting `position` samples into `track`, which has the
+    /// given bounds in the track list
+    ///
+    /// When crossfading, the end of the track is held back until we know
+    /// whether it gets mixed with the next one, and the start of a track
+    /// that's being faded into is held back until there's enough to mix.
+    fn emit(
+        &mut self,
+        track: &Track,
+        position: u64,
+        sample_rate: u32,
+        samples: Vec<f32>,
+        (start, end): (u64, u64),
+    ) {
+        let position = (start + position).min(end);
+        if self.crossfade.is_zero() {
+            self.send_chunk(position, end, sample_rate, track.replay_gain, &samples);
+            return;
+        }
+
+        if let Some(fade) = &mut self.fading {
+            if position >= fade.tail.end && sample_rate == fade.tail.sample_rate {
+                if fade.incoming.is_empty() {
+                    fade.start = position;
+                    fade.end = end;
+                    fade.gain = track.replay_gain;
+                }
+                fade.incoming.extend(samples);
+                if fade.incoming.len() >= fade.tail.samples.len() {
+                    self.finish_fade();
+                }
+                return;
+            }
+            // whatever we were going to fade into isn't what came next
+            self.finish_fade();
+        }
+        self.hold(position, end, sample_rate, track.replay_gain, samples);
+    }
+
+    /// Add samples to the tail, sending on anything that's too far from the
+    /// end of the track to be part of a crossfade
+    fn hold(
+        &mut self,
+        position: u64,
+        end: u64,
+        sample_rate: u32,
+        gain: ReplayGain,
+        samples: Vec<f32>,
+    ) {
+        let mut tail = match self.tail.take() {
+            Some(tail) if tail.sample_rate == sample_rate && tail.end == end => tail,
+            other => {
+                if let Some(tail) = other {
+                    self.send_tail(tail);
+                }
+                Tail {
+                    position,
+                    end,
+                    sample_rate,
+                    gain,
+                    samples: Vec::new(),
+                }
+            }
+        };
+        tail.samples.extend(samples);
+
+        let channels = usize::from(self.params.channel_count);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let keep = (self.crossfade.as_secs_f64() * f64::from(sample_rate)) as usize * channels;
+        if tail.samples.len() > keep {
+            let excess = tail.samples.len() - keep;
+            let samples = &tail.samples[..excess];
+            tail.position += self.send_chunk(tail.position, end, sample_rate, gain, samples);
+            tail.samples.drain(..excess);
+        }
+        self.tail = Some(tail);
+    }
+
+    /// Decide what to do with the tail of `track`, at `index` in the queue,
+    /// once it's all been decoded
+    fn finish_track(&mut self, index: usize, track: &Track) {
+        // a track shorter than the crossfade is done being mixed in. if
+        // nothing came out of it, the fade carries on into the next one.
+        if self.fading.as_ref().is_some_and(|f| !f.incoming.is_empty()) {
+            self.finish_fade();
+        }
+        if let Some(tail) = self.tail.take() {
+            if !tail.samples.is_empty() && self.fades_into_next(index, track, tail.sample_rate) {
+                self.fading = Some(Fade {
+                    start: tail.end,
+                    end: tail.end,
+                    tail,
+                    incoming: Vec::new(),
+                    gain: ReplayGain::default(),
+                });
+            } else {
+                self.send_tail(tail);
+            }
+        }
+    }
+
+    /// Whether the end of `track`, at `index` in the queue, gets crossfaded
+    /// into the track after it. Only tracks from different albums do, so
+    /// albums stay gapless.
+    fn fades_into_next(&self, index: usize, track: &Track, sample_rate: u32) -> bool {
+        if self.repeat.get() == RepeatMode::One {
+            return false;
+        }
+        // not when wrapping around to the start, either, since the positions
+        // would go backwards halfway through the fade
+        let tracks = self.queue.snapshot();
+        let Some(next) = tracks.tracks.get(index + 1) else {
+            return false;
+        };
+        let next_rate = match self.conversion {
+            RateConversion::Resample(_) => self.params.sample_rate,
+            RateConversion::Native => next.sample_rate,
+        };
+        next_rate == sample_rate
+            && (&next.album_artist, &next.album) != (&track.album_artist, &track.album)
+    }
+
+    /// Mix the tail we're fading out of with whatever we've got of the next
+    /// track, using equal-power curves, and send it
+    ///
+    /// If the next track was too short, the rest of the fade is against
+    /// silence. If nothing came of the next track at all, the tail goes out
+    /// as it is.
+    fn finish_fade(&mut self) {
+        let Some(Fade {
+            tail,
+            incoming,
+            start,
+            end,
+            gain,
+        }) = self.fading.take()
+        else {
+            return;
+        };
+        if incoming.is_empty() {
+            self.send_tail(tail);
+            return;
+        }
+
+        // the output scales the mix by the incoming track's gain, so scale
+        // the outgoing one by the difference
+        let mode = self.replay_gain.get();
+        let ratio = mode.factor(&tail.gain, self.preamp_db) / mode.factor(&gain, self.preamp_db);
+
+        let channels = usize::from(self.params.channel_count);
+        let frames = tail.samples.len() / channels;
+        let mixed = incoming.len().min(tail.samples.len());
+        let mut samples = tail.samples;
+        for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+            let (fade_in, fade_out) = ((i as f32 + 0.5) / frames as f32 * FRAC_PI_2).sin_cos();
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let next = incoming.get(i * channels + c).copied().unwrap_or(0.0);
+                *sample = *sample * ratio * fade_out + next * fade_in;
+            }
+        }
+
+        let mixed_frames = (mixed / channels) as u64;
+        let rest =
+            resample::convert_frames(mixed_frames, tail.sample_rate, self.params.sample_rate);
+        let rest = (start + rest).min(end);
+        self.send(
+            Decoded::Samples {
+                generation: self.generation,
+                position: tail.position,
+                length: rest - tail.position,
+                sample_rate: tail.sample_rate,
+                gain,
+            },
+            &samples,
+        );
+        if incoming.len() > mixed {
+            self.hold(
+                rest,
+                end,
+                tail.sample_rate,
+                gain,
+                incoming[mixed..].to_vec(),
+            );
+        }
+    }
+
+    fn send_tail(&mut self, tail: Tail) {
+        if tail.samples.is_empty() {
+            return;
+        }
+        self.send_chunk(
+            tail.position,
+            tail.end,
+            tail.sample_rate,
+            tail.gain,
+            &tail.samples,
+        );
+    }
+
+    /// Send samples starting at `position` in the track list, from a track
+    /// that ends at `end`. Returns how much of the track list they cover.
+    fn send_chunk(
+        &mut self,
+        position: u64,
+        end: u64,
+        sample_rate: u32,
+        gain: ReplayGain,
+        samples: &[f32],
+    ) -> u64 {
+        let frames = (samples.len() / usize::from(self.params.channel_count)) as u64;
+        let length = resample::convert_frames(frames, sample_rate, self.params.sample_rate)
+            .min(end - position);
+        self.send(
+            Decoded::Samples {
+                generation: self.generation,
+                position,
+                length,
+                sample_rate,
+                gain,
+            },
+            samples,
+        );
+        length
+    }
+
+    /// Put a chunk in the ring buffer, splitting it up if it's too big to
+    /// ever fit
+    fn send(&mut self, header: Decoded, samples: &[f32]) {
+        let channels = usize::from(self.params.channel_count);
+        let most = (self.samples.capacity() / 4 / channels).max(1) * channels;
+        let Decoded::Samples {
+            generation,
+            position,
+            length,
+            sample_rate,
+            gain,
+        } = header
+        else {
+            self.push(header, samples);
+            return;
+        };
+
+        let mut from = 0;
+        for piece in samples.chunks(most) {
+            let start = position_after(position, length, from, samples.len());
+            from += piece.len();
+            let end = position_after(position, length, from, samples.len());
+            let header = Decoded::Samples {
+                generation,
+                position: start,
+                length: end - start,
+                sample_rate,
+                gain,
+            };
+            if !self.push(header, piece) {
+                return;
+            }
+        }
+    }
+
+    /// Returns whether the chunk was pushed, rather than a command or the
+    /// output going away getting in the way
+    fn push(&mut self, mut header: Decoded, samples: &[f32]) -> bool {
+        let high = self.params.interleaved_samples_in(self.watermarks.1);
+        loop {
+            if self.interrupted || self.stopped {
+                return false;
+            }
+            if self.samples.buffered() < high {
+                match self.samples.push(header, samples) {
+                    Ok(()) => {
+                        tracing::trace!("Sent samples");
+                        return true;
+                    }
+                    Err(returned) => header = returned,
+                }
+            }
+            self.wait_for_room();
+        }
+    }
+
+    /// Wait for the ring buffer to drain down to the low watermark, so reads
+    /// get batched up, handling any commands that come in meanwhile
+    fn wait_for_room(&mut self) {
+        let low = self.params.interleaved_samples_in(self.watermarks.0);
+        tracing::trace!(buffered = self.samples.buffered(), "Waiting for room");
+        while self.samples.buffered() > low && !self.interrupted && !self.stopped {
+            if self.samples.is_abandoned() {
+                tracing::info!("Output is gone; stopping");
+                self.stopped = true;
+                return;
+            }
+            match self.commands_rx.recv_timeout(Self::POLL_INTERVAL) {
+                Ok(command) => self.interrupted = self.handle_command(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => self.stopped = true,
+            }
+        }
+    }
+}
+
+/// A file's demuxer and decoder, and the id of the track they're for
+type OpenFile = (
+    Box<dyn FormatReader>,
+    Box<dyn Decoder>,
+    u32,
+    Option<TimeBase>,
+);
+
+fn open_file(path: &Path) -> Result<OpenFile, Error> {
+    let probed = metadata::open(path)?;
+
+    let format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or(Error::Unsupported("no audio track"))?;
+
+    let decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    Ok((format, decoder, track_id, time_base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring::chunk_ring;
+    use crate::track_list::{tests::test_track, TrackList};
+    use crossbeam::channel;
+    use itertools::Itertools;
+
+    /// A reader for `tracks` with everything else at its defaults
+    fn test_reader(
+        tracks: &TrackList,
+        samples: ChunkProducer<Decoded>,
+        commands_rx: Receiver<ReaderCommand>,
+    ) -> FileReader {
+        FileReader {
+            queue: Arc::new(Queue::new(tracks.clone())),
+            repeat: Arc::new(RepeatState::default()),
+            params: tracks.audio_params(),
+            conversion: RateConversion::default(),
+            crossfade: Duration::ZERO,
+            watermarks: FileReader::DEFAULT_WATERMARKS,
+            replay_gain: Arc::new(ReplayGainState::default()),
+            preamp_db: 0.0,
+            tail: None,
+            fading: None,
+            position: 0,
+            generation: 0,
+            samples,
+            commands_rx,
+            events: PlayerEvents::default(),
+            skipped: SkippedFiles::default(),
+            stopped: false,
+            interrupted: false,
+        }
+    }
+
+    #[test]
+    fn test_crossfade_between_albums() {
+        let track = |album, track| Track {
+            sample_rate: 10,
+            ..test_track(album, track, 100)
+        };
+        let tracks = TrackList::from(vec![track("A", 1), track("B", 1), track("B", 2)]);
+        let (samples_tx, mut samples_rx) = chunk_ring(1024, 16);
+        let mut reader = FileReader {
+            crossfade: Duration::from_secs(1),
+            // plenty of room, since nothing's reading
+            watermarks: (Duration::ZERO, Duration::from_secs(60)),
+            ..test_reader(&tracks, samples_tx, channel::never())
+        };
+        let mut play = |index: usize, value: f32| {
+            let track = tracks.get_track(index);
+            reader.emit(track, 0, 10, vec![value; 200], tracks.get_bounds(index));
+            reader.finish_track(index, track);
+        };
+        play(0, 1.0);
+        play(1, 0.5);
+        play(2, 0.25);
+        reader.finish_fade();
+
+        let mut chunks = vec![];
+        while let Some((&decoded, _, len)) = samples_rx.current() {
+            let Decoded::Samples {
+                position, length, ..
+            } = decoded
+            else {
+                unreachable!()
+            };
+            let mut samples = vec![0.0; len];
+            samples_rx.read(&mut samples);
+            chunks.push((position, length, samples));
+        }
+        let bounds = chunks
+            .iter()
+            .map(|(position, length, samples)| (*position, *length, samples.len() / 2))
+            .collect_vec();
+        // A's last second is mixed with B's first, then B goes straight into
+        // the next track on the same album
+        assert_eq!(
+            bounds,
+            vec![
+                (0, 90, 90),
+                (90, 20, 10),
+                (110, 80, 80),
+                (190, 10, 10),
+                (200, 90, 90),
+                (290, 10, 10)
+            ]
+        );
+
+        let (_, _, mixed) = &chunks[1];
+        // equal-power curves: cosine going out, sine coming in
+        let mix = |a: f32, b: f32, i: usize| {
+            let (fade_in, fade_out) = ((i as f32 + 0.5) / 10.0 * FRAC_PI_2).sin_cos();
+            a * fade_out + b * fade_in
+        };
+        for i in 0..10 {
+            assert!((mixed[i * 2] - mix(1.0, 0.5, i)).abs() < 1e-6);
+        }
+
+        // the current track changes halfway through the fade
+        assert_eq!(tracks.find_playing(position_after(90, 20, 8, 20)), 0);
+        assert_eq!(tracks.find_playing(position_after(90, 20, 10, 20)), 1);
+    }
+
+    #[test]
+    fn test_reader_waits_for_room_but_not_for_commands() {
+        let track = Track {
+            sample_rate: 10,
+            ..test_track("A", 1, 1_000)
+        };
+        let tracks = TrackList::from(vec![track.clone()]);
+        let (samples_tx, samples_rx) = chunk_ring(1024, 16);
+        let (commands_tx, commands_rx) = channel::unbounded();
+        let mut reader = test_reader(&tracks, samples_tx, commands_rx);
+
+        // 10 seconds is over the high watermark, so the next chunk waits
+        // until the seek that's already queued up
+        reader.emit(&track, 0, 10, vec![0.0; 200], (0, 1_000));
+        commands_tx
+            .send(ReaderCommand::Seek {
+                generation: 1,
+                sample: 500,
+            })
+            .unwrap();
+        reader.emit(&track, 100, 10, vec![0.0; 200], (0, 1_000));
+
+        assert!(reader.interrupted);
+        assert_eq!((reader.generation, reader.position), (1, 500));
+        assert_eq!(samples_rx.buffered(), 200);
+    }
+
+    #[test]
+    fn test_position_follows_chunks() {
+        assert_eq!(position_after(90, 10, 5, 10), 95);
+        assert_eq!(position_after(90, 10, 10, 10), 100);
+        assert_eq!(position_after(0, 0, 0, 0), 0);
+
+        // twice as many samples as the track list covers
+        assert_eq!(position_after(50, 10, 5, 20), 52);
+        assert_eq!(position_after(50, 10, 20, 20), 60);
+    }
+}
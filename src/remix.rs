@@ -0,0 +1,175 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use symphonia::core::audio::Channels;
+
+/// Maps decoded channels onto the output's channel layout
+///
+/// Mono gets copied to both front speakers. Anything with more channels than
+/// the output gets folded down to stereo with the usual ITU-R BS.775
+/// coefficients: centre and surrounds at -3dB, LFE dropped. The result is
+/// scaled down so a full-scale signal in every channel can't clip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remix {
+    inputs: usize,
+    /// One row of input gains per output channel
+    gains: Vec<Vec<f32>>,
+}
+
+impl Remix {
+    #[must_use]
+    pub fn new(channels: Channels, outputs: usize) -> Self {
+        let inputs = channels.count();
+        let passthrough = inputs == outputs;
+        let gains = if passthrough {
+            (0..outputs)
+                .map(|o| {
+                    (0..inputs)
+                        .map(|i| if i == o { 1.0 } else { 0.0 })
+                        .collect()
+                })
+                .collect()
+        } else {
+            let stereo = stereo_gains(channels);
+            (0..outputs)
+                .map(|o| match (outputs, o) {
+                    (1, _) => stereo.iter().map(|[l, r]| (l + r) / 2.0).collect(),
+                    (_, 0 | 1) => stereo.iter().map(|gains| gains[o]).collect(),
+                    _ => vec![0.0; inputs],
+                })
+                .collect()
+        };
+        Self { inputs, gains }
+    }
+
+    /// Whether samples come out exactly as they went in
+    #[must_use]
+    pub fn is_passthrough(&self) -> bool {
+        self.gains.len() == self.inputs
+            && self.gains.iter().enumerate().all(|(o, row)| {
+                row.iter()
+                    .enumerate()
+                    .all(|(i, &gain)| gain == if i == o { 1.0 } else { 0.0 })
+            })
+    }
+
+    /// Remix interleaved samples
+    #[must_use]
+    pub fn apply(&self, samples: &[f32]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(samples.len() / self.inputs * self.gains.len());
+        for frame in samples.chunks_exact(self.inputs) {
+            out.extend(
+                self.gains
+                    .iter()
+                    .map(|row| row.iter().zip(frame).map(|(gain, s)| gain * s).sum::<f32>()),
+            );
+        }
+        out
+    }
+}
+
+/// Left and right gains for each input channel when folding down to stereo
+fn stereo_gains(channels: Channels) -> Vec<[f32; 2]> {
+    if channels.count() == 1 {
+        return vec![[1.0, 1.0]];
+    }
+
+    let left = Channels::REAR_LEFT
+        | Channels::SIDE_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE;
+    let right = Channels::REAR_RIGHT
+        | Channels::SIDE_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE;
+    let centre = Channels::FRONT_CENTRE
+        | Channels::TOP_CENTRE
+        | Channels::TOP_FRONT_CENTRE
+        | Channels::FRONT_CENTRE_HIGH;
+    let rear_centre = Channels::REAR_CENTRE | Channels::TOP_REAR_CENTRE;
+
+    let gains = channels
+        .iter()
+        .map(|channel| {
+            if channel == Channels::FRONT_LEFT {
+                [1.0, 0.0]
+            } else if channel == Channels::FRONT_RIGHT {
+                [0.0, 1.0]
+            } else if left.contains(channel) {
+                [FRAC_1_SQRT_2, 0.0]
+            } else if right.contains(channel) {
+                [0.0, FRAC_1_SQRT_2]
+            } else if centre.contains(channel) {
+                [FRAC_1_SQRT_2, FRAC_1_SQRT_2]
+            } else if rear_centre.contains(channel) {
+                [0.5, 0.5]
+            } else {
+                // LFE
+                [0.0, 0.0]
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let loudest = (0..2)
+        .map(|side| gains.iter().map(|g| g[side]).sum::<f32>())
+        .fold(1.0, f32::max);
+    gains
+        .into_iter()
+        .map(|[l, r]| [l / loudest, r / loudest])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn surround_5_1() -> Channels {
+        Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+    }
+
+    #[test]
+    fn test_mono_is_copied_to_both_sides() {
+        let remix = Remix::new(Channels::FRONT_LEFT, 2);
+        assert_eq!(remix.apply(&[0.5, -0.25]), vec![0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_5_1_downmix_drops_lfe_and_keeps_sides_apart() {
+        let remix = Remix::new(surround_5_1(), 2);
+        assert!(!remix.is_passthrough());
+
+        let left = remix.apply(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert!(left[0] > 0.0 && left[1] == 0.0);
+        let lfe = remix.apply(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(lfe, vec![0.0, 0.0]);
+        let centre = remix.apply(&[0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+        assert!((centre[0] - centre[1]).abs() < f32::EPSILON);
+
+        assert!(Remix::new(surround_5_1(), 6).is_passthrough());
+    }
+
+    proptest! {
+        #[test]
+        fn test_downmix_never_clips(frame in prop::collection::vec(-1.0f32..=1.0, 8)) {
+            let channels = surround_5_1() | Channels::SIDE_LEFT | Channels::SIDE_RIGHT;
+            for outputs in [1, 2] {
+                for sample in Remix::new(channels, outputs).apply(&frame) {
+                    prop_assert!(sample.abs() <= 1.0 + 1e-6);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use rubato::{
+    FastFixedIn, PolynomialDegree, ResamplerConstructionError, SincFixedIn,
+    SincInterpolationParameters, SincInterpolationType, VecResampler, WindowFunction,
+};
+
+/// How much CPU to spend on sample rate conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ResampleQuality {
+    /// Polynomial interpolation. Cheap, but rolls off the top end.
+    Fast,
+    /// Short sinc filter, good enough for most listening
+    #[default]
+    Balanced,
+    /// Long sinc filter with cubic interpolation
+    Best,
+}
+
+/// What to do with tracks that aren't at the track list's sample rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateConversion {
+    /// Resample everything to the track list's rate
+    Resample(ResampleQuality),
+    /// Reopen the output device at each track's own rate
+    Native,
+}
+
+impl Default for RateConversion {
+    fn default() -> Self {
+        Self::Resample(ResampleQuality::default())
+    }
+}
+
+/// Convert a number of frames at one sample rate to the same length of time
+/// at another
+#[must_use]
+pub fn convert_frames(frames: u64, from: u32, to: u32) -> u64 {
+    if from == to || from == 0 {
+        return frames;
+    }
+    let frames = u128::from(frames) * u128::from(to) / u128::from(from);
+    u64::try_from(frames).unwrap_or(u64::MAX)
+}
+
+/// Resamples interleaved audio from one fixed sample rate to another
+///
+/// Input can come in any size; it's buffered until there's enough for the
+/// underlying resampler. The resampler's delay is trimmed off the front, and
+/// `finish` pads out the end, so a whole stream comes out exactly as long as
+/// it went in. Make a new one for each stream, including after seeking.
+pub struct Resampler {
+    inner: Box<dyn VecResampler<f32>>,
+    channels: usize,
+    from: u32,
+    to: u32,
+    /// Deinterleaved input waiting for a full chunk
+    pending: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+    /// Output frames still to drop from the front to make up for the delay
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl Resampler {
+    const CHUNK_FRAMES: usize = 1024;
+
+    /// Create a resampler for `channels` channels of audio
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the ratio between the rates is out of range
+    pub fn new(
+        from: u32,
+        to: u32,
+        channels: usize,
+        quality: ResampleQuality,
+    ) -> Result<Self, ResamplerConstructionError> {
+        let ratio = f64::from(to) / f64::from(from);
+        let sinc = |sinc_len, interpolation, oversampling_factor| SincInterpolationParameters {
+            sinc_len,
+            f_cutoff: 0.95,
+            interpolation,
+            oversampling_factor,
+            window: WindowFunction::BlackmanHarris2,
+        };
+
+        let inner: Box<dyn VecResampler<f32>> = match quality {
+            ResampleQuality::Fast => Box::new(FastFixedIn::new(
+                ratio,
+                1.0,
+                PolynomialDegree::Cubic,
+                Self::CHUNK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::Balanced => Box::new(SincFixedIn::new(
+                ratio,
+                1.0,
+                sinc(128, SincInterpolationType::Linear, 128),
+                Self::CHUNK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::Best => Box::new(SincFixedIn::new(
+                ratio,
+                1.0,
+                sinc(256, SincInterpolationType::Cubic, 256),
+                Self::CHUNK_FRAMES,
+                channels,
+            )?),
+        };
+
+        let delay = inner.output_delay();
+        let output = inner.output_buffer_allocate(true);
+        Ok(Self {
+            inner,
+            channels,
+            from,
+            to,
+            pending: vec![Vec::with_capacity(Self::CHUNK_FRAMES * 2); channels],
+            output,
+            delay,
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Feed interleaved samples in and get whatever's ready back out
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in self.pending.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+        }
+        self.frames_in += (samples.len() / self.channels) as u64;
+
+        let mut out = Vec::new();
+        while self.pending[0].len() >= self.inner.input_frames_next() {
+            match self
+                .inner
+                .process_into_buffer(&self.pending, &mut self.output, None)
+            {
+                Ok((read, written)) => {
+                    for channel in &mut self.pending {
+                        channel.drain(..read);
+                    }
+                    self.interleave_into(written, &mut out);
+                }
+                Err(error) => {
+                    tracing::error!(?error, "Error resampling");
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    /// Flush what's left at the end of a stream
+    pub fn finish(mut self) -> Vec<f32> {
+        let expected = convert_frames(self.frames_in, self.from, self.to);
+        let mut out = Vec::new();
+        // rubato treats empty channels as inactive rather than as silence
+        let mut input = Some(std::mem::take(&mut self.pending)).filter(|p| !p[0].is_empty());
+
+        while self.frames_out < expected {
+            let result =
+                self.inner
+                    .process_partial_into_buffer(input.as_deref(), &mut self.output, None);
+            input = None;
+            match result {
+                Ok((_, written)) if written > 0 => self.interleave_into(written, &mut out),
+                Ok(_) => break,
+                Err(error) => {
+                    tracing::error!(?error, "Error flushing resampler");
+                    break;
+                }
+            }
+        }
+
+        let extra = self.frames_out.saturating_sub(expected);
+        let extra = usize::try_from(extra).unwrap_or(usize::MAX) * self.channels;
+        out.truncate(out.len().saturating_sub(extra));
+        out
+    }
+
+    fn interleave_into(&mut self, written: usize, out: &mut Vec<f32>) {
+        let skip = self.delay.min(written);
+        self.delay -= skip;
+        out.reserve((written - skip) * self.channels);
+        for i in skip..written {
+            out.extend(self.output.iter().map(|channel| channel[i]));
+        }
+        self.frames_out += (written - skip) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_resampled_length_matches_duration(
+            frames in prop_oneof![0usize..20_000, (0usize..20).prop_map(|n| n * 1024)],
+            chunk in 1usize..5000,
+            (from, to) in prop_oneof![
+                Just((44_100, 48_000)),
+                Just((96_000, 44_100)),
+                Just((48_000, 88_200)),
+            ],
+        ) {
+            let mut resampler = Resampler::new(from, to, 2, ResampleQuality::Fast).unwrap();
+            let input = vec![0.25; frames * 2];
+            let mut output = Vec::new();
+            for samples in input.chunks(chunk * 2) {
+                output.extend(resampler.process(samples));
+            }
+            output.extend(resampler.finish());
+            prop_assert_eq!(output.len() as u64, convert_frames(frames as u64, from, to) * 2);
+        }
+    }
+}
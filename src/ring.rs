@@ -0,0 +1,187 @@
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
+
+/// Create a single-producer, single-consumer queue of audio chunks, each a
+/// run of interleaved samples with a header describing them
+///
+/// Nothing allocates, locks or blocks after this, so the consumer is safe to
+/// use from an audio callback.
+#[must_use]
+pub fn chunk_ring<H>(samples: usize, chunks: usize) -> (ChunkProducer<H>, ChunkConsumer<H>) {
+    let (samples_tx, samples_rx) = RingBuffer::new(samples);
+    let (headers_tx, headers_rx) = RingBuffer::new(chunks);
+    (
+        ChunkProducer {
+            samples: samples_tx,
+            headers: headers_tx,
+        },
+        ChunkConsumer {
+            samples: samples_rx,
+            headers: headers_rx,
+            read: 0,
+        },
+    )
+}
+
+struct Header<H> {
+    header: H,
+    len: usize,
+}
+
+pub struct ChunkProducer<H> {
+    samples: Producer<f32>,
+    headers: Producer<Header<H>>,
+}
+
+impl<H> ChunkProducer<H> {
+    /// The most samples there's ever room for
+    pub fn capacity(&self) -> usize {
+        self.samples.buffer().capacity()
+    }
+
+    /// How many samples are waiting to be read
+    pub fn buffered(&self) -> usize {
+        self.capacity() - self.samples.slots()
+    }
+
+    /// Whether a chunk of `len` samples fits right now
+    pub fn has_room(&self, len: usize) -> bool {
+        self.samples.slots() >= len && !self.headers.is_full()
+    }
+
+    /// Whether the consumer has been dropped
+    pub fn is_abandoned(&self) -> bool {
+        self.headers.is_abandoned()
+    }
+
+    /// Add a chunk to the queue
+    ///
+    /// # Errors
+    ///
+    /// Hands the header back if there isn't room for the chunk
+    pub fn push(&mut self, header: H, samples: &[f32]) -> Result<(), H> {
+        if !self.has_room(samples.len()) {
+            return Err(header);
+        }
+        let Ok(chunk) = self.samples.write_chunk_uninit(samples.len()) else {
+            return Err(header);
+        };
+        chunk.fill_from_iter(samples.iter().copied());
+
+        // the header goes in last, so the consumer never sees a chunk before
+        // its samples
+        let header = Header {
+            header,
+            len: samples.len(),
+        };
+        self.headers
+            .push(header)
+            .map_err(|PushError::Full(header)| header.header)
+    }
+}
+
+pub struct ChunkConsumer<H> {
+    samples: Consumer<f32>,
+    headers: Consumer<Header<H>>,
+    /// How much of the current chunk has been read
+    read: usize,
+}
+
+impl<H> ChunkConsumer<H> {
+    /// The header of the chunk being read, how many of its samples have been
+    /// read and how many it has altogether
+    pub fn current(&self) -> Option<(&H, usize, usize)> {
+        self.headers
+            .peek()
+            .ok()
+            .map(|header| (&header.header, self.read, header.len))
+    }
+
+    /// Copy samples from the current chunk into `out`, returning how many
+    /// were copied. Moves on to the next chunk once this one is used up,
+    /// handing back its header.
+    pub fn read(&mut self, out: &mut [f32]) -> (usize, Option<H>) {
+        let Ok(header) = self.headers.peek() else {
+            return (0, None);
+        };
+        let n = out.len().min(header.len - self.read);
+        if let Ok(chunk) = self.samples.read_chunk(n) {
+            let (first, second) = chunk.as_slices();
+            out[..first.len()].copy_from_slice(first);
+            out[first.len()..n].copy_from_slice(second);
+            chunk.commit_all();
+        }
+        self.read += n;
+        (n, self.finish_chunk())
+    }
+
+    /// How many samples are waiting to be read
+    pub fn buffered(&self) -> usize {
+        self.samples.slots()
+    }
+
+    /// Whether the producer has been dropped
+    pub fn is_abandoned(&self) -> bool {
+        self.headers.is_abandoned()
+    }
+
+    /// Throw away the rest of the current chunk, handing back its header
+    pub fn skip(&mut self) -> Option<H> {
+        let header = self.headers.peek().ok()?;
+        let n = header.len - self.read;
+        if let Ok(chunk) = self.samples.read_chunk(n) {
+            chunk.commit_all();
+        }
+        self.read += n;
+        self.finish_chunk()
+    }
+
+    fn finish_chunk(&mut self) -> Option<H> {
+        let len = self.headers.peek().ok()?.len;
+        if self.read < len {
+            return None;
+        }
+        self.read = 0;
+        self.headers.pop().ok().map(|header| header.header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_chunks_come_out_as_they_went_in(
+            chunks in prop::collection::vec(0usize..40, 1..20),
+            reads in prop::collection::vec(1usize..30, 1..20),
+        ) {
+            let (mut producer, mut consumer) = chunk_ring::<usize>(64, 4);
+            let mut expected = chunks.iter().enumerate().flat_map(|(i, &len)| {
+                (0..len).map(move |j| (i, j))
+            });
+            let mut pending = chunks.iter().enumerate().peekable();
+            let mut out = [0.0; 30];
+
+            for &n in reads.iter().cycle().take(2000) {
+                while let Some(&(i, &len)) = pending.peek() {
+                    let samples = (0..len).map(|j| j as f32).collect::<Vec<_>>();
+                    if producer.push(i, &samples).is_err() {
+                        break;
+                    }
+                    pending.next();
+                }
+
+                let header = consumer.current().map(|(&header, ..)| header);
+                let (read, finished) = consumer.read(&mut out[..n]);
+                for &sample in &out[..read] {
+                    prop_assert_eq!(Some((header.unwrap(), sample as usize)), expected.next());
+                }
+                if finished.is_some() {
+                    prop_assert_eq!(finished, header);
+                }
+            }
+            prop_assert_eq!(expected.next(), None);
+        }
+    }
+}
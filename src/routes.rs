@@ -1,7 +1,12 @@
 use crate::types::DebugResponse;
 use crate::types::SharedState;
-use axum::extract::State;
+use crate::types::WaveformResponse;
+use crate::waveform::Waveform;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
 use axum::Json;
+use serde::Deserialize;
+use std::path::Path;
 
 // basic handler that responds with a static string
 #[tracing::instrument]
@@ -16,3 +21,32 @@ pub async fn debug(State(state): State<SharedState>) -> Json<DebugResponse> {
     let paths = state.settings.music.paths.clone();
     Json(DebugResponse { paths })
 }
+
+#[derive(Debug, Deserialize)]
+pub struct WaveformQuery {
+    pub path: String,
+}
+
+/// The waveform `build-db` stored for a track, for drawing a seek bar
+#[tracing::instrument(skip(state))]
+pub async fn waveform(
+    State(state): State<SharedState>,
+    Query(query): Query<WaveformQuery>,
+) -> Result<Json<WaveformResponse>, StatusCode> {
+    let Some(library) = &state.library else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let path = query.path.clone();
+    let waveform = library
+        .call(move |conn| Waveform::load(conn, Path::new(&path)))
+        .await
+        .map_err(|error| {
+            tracing::error!(%error, "Failed to load waveform");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(WaveformResponse {
+        path: query.path,
+        waveform,
+    }))
+}
@@ -0,0 +1,532 @@
+use crate::flac::FlacWriter;
+use crate::types::AudioParams;
+use clap::ValueEnum;
+use hound::{WavSpec, WavWriter};
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tinyaudio::run_output_device;
+use tracing_unwrap::*;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("couldn't open the audio device: {0}")]
+    Device(String),
+
+    #[error("the output is already at {from}Hz and can't change to {to}Hz")]
+    RateChanged { from: u32, to: u32 },
+
+    #[error("couldn't write the output file {path}: {error}")]
+    WavFailed { path: PathBuf, error: hound::Error },
+
+    #[error("FLAC files can't store float samples")]
+    FlacFloat,
+
+    #[error("couldn't write the output file {path}: {error}")]
+    FlacFailed { path: PathBuf, error: io::Error },
+
+    #[error(transparent)]
+    IoFailed(#[from] io::Error),
+}
+
+/// Fills a buffer with interleaved samples and returns how many of them are
+/// meant to be played. That's all of them for real-time sinks, which get
+/// silence when the player can't keep up, and only what's been decoded so far
+/// for the rest.
+pub type Render = Box<dyn FnMut(&mut [f32]) -> usize + Send>;
+
+/// Tells the player a sink can't take any more audio, so it stops rather
+/// than waiting on audio that's never going to be written
+pub type Failed = Box<dyn FnOnce(SinkError) + Send>;
+
+/// Somewhere for the player's audio to go
+///
+/// The sink calls `render` for more audio whenever it's ready for it, from a
+/// thread of its own, until the returned stream is dropped or it calls
+/// `failed`. The player opens the sink again if the sample rate changes
+/// partway through.
+pub trait AudioSink: Send {
+    /// Start pulling audio at `params`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the output can't take audio at `params`
+    fn open(
+        &mut self,
+        params: AudioParams,
+        render: Render,
+        failed: Failed,
+    ) -> Result<Stream, SinkError>;
+
+    /// Whether audio gets played as it's rendered. If not, the sink goes as
+    /// fast as the player can decode, and waits for it rather than filling
+    /// in with silence.
+    fn is_realtime(&self) -> bool;
+
+    /// Flush anything that's still buffered, once playback is over
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the output couldn't be written
+    fn finish(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Keeps an opened sink running until it's dropped
+pub struct Stream {
+    _running: Box<dyn Any>,
+}
+
+impl Stream {
+    /// A stream that keeps `running` alive, for sinks that aren't in here
+    #[must_use]
+    pub fn new(running: impl Any) -> Self {
+        Self {
+            _running: Box::new(running),
+        }
+    }
+}
+
+//
+// Device
+//
+
+/// The system's default audio device
+#[derive(Debug, Default)]
+pub struct DeviceSink;
+
+impl AudioSink for DeviceSink {
+    fn open(
+        &mut self,
+        params: AudioParams,
+        mut render: Render,
+        _failed: Failed,
+    ) -> Result<Stream, SinkError> {
+        let device = run_output_device(params.output_device_parameters(), move |data| {
+            render(data);
+        })
+        .map_err(|error| SinkError::Device(error.to_string()))?;
+        Ok(Stream {
+            _running: Box::new(device),
+        })
+    }
+
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+//
+// Null
+//
+
+/// Throws the audio away, either at the rate it would be played or as fast
+/// as it can be decoded
+#[derive(Debug, Default)]
+pub struct NullSink {
+    realtime: bool,
+}
+
+impl NullSink {
+    #[must_use]
+    pub fn realtime() -> Self {
+        Self { realtime: true }
+    }
+
+    #[must_use]
+    pub fn max_speed() -> Self {
+        Self { realtime: false }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn open(
+        &mut self,
+        params: AudioParams,
+        render: Render,
+        failed: Failed,
+    ) -> Result<Stream, SinkError> {
+        Ok(Pump::spawn(params, render, failed, self.realtime, |_| {
+            Ok(())
+        }))
+    }
+
+    fn is_realtime(&self) -> bool {
+        self.realtime
+    }
+}
+
+/// How samples are stored in an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SampleFormat {
+    /// 32-bit float, exactly what the player renders. WAV only.
+    #[default]
+    Float,
+    /// 16-bit integer, like a CD
+    Int16,
+    /// 24-bit integer
+    Int24,
+}
+
+//
+// WAV
+//
+
+impl SampleFormat {
+    fn spec(self, params: AudioParams) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            Self::Float => (32, hound::SampleFormat::Float),
+            Self::Int16 => (16, hound::SampleFormat::Int),
+            Self::Int24 => (24, hound::SampleFormat::Int),
+        };
+        WavSpec {
+            channels: u16::from(params.channel_count),
+            sample_rate: params.sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    fn write<W: io::Write + io::Seek>(
+        self,
+        writer: &mut WavWriter<W>,
+        sample: f32,
+    ) -> hound::Result<()> {
+        match self {
+            Self::Float => writer.write_sample(sample),
+            #[allow(clippy::cast_possible_truncation)]
+            Self::Int16 => writer.write_sample(to_int(sample, 16) as i16),
+            Self::Int24 => writer.write_sample(to_int(sample, 24)),
+        }
+    }
+}
+
+/// Scale a sample to a `bits`-bit integer, clipping rather than wrapping
+/// around anything that's gone over full scale
+#[allow(clippy::cast_possible_truncation)]
+fn to_int(sample: f32, bits: u8) -> i32 {
+    let max = ((1 << (bits - 1)) - 1) as f32;
+    (sample.clamp(-1.0, 1.0) * max).round() as i32
+}
+
+/// Writes WAV files as fast as the audio can be decoded
+pub struct WavSink {
+    path: PathBuf,
+    format: SampleFormat,
+    writer: Arc<Mutex<Option<WavWriter<BufWriter<File>>>>>,
+}
+
+impl WavSink {
+    /// The file gets created when playback starts, at whatever rate and
+    /// channel count it starts with
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            format: SampleFormat::default(),
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    #[must_use]
+    pub fn with_format(mut self, format: SampleFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn wav_failed(&self, error: hound::Error) -> SinkError {
+        SinkError::WavFailed {
+            path: self.path.clone(),
+            error,
+        }
+    }
+}
+
+impl AudioSink for WavSink {
+    fn open(
+        &mut self,
+        params: AudioParams,
+        render: Render,
+        failed: Failed,
+    ) -> Result<Stream, SinkError> {
+        let mut writer = self.writer.lock().unwrap_or_log();
+        match &*writer {
+            Some(existing) if existing.spec().sample_rate != params.sample_rate => {
+                return Err(SinkError::RateChanged {
+                    from: existing.spec().sample_rate,
+                    to: params.sample_rate,
+                });
+            }
+            Some(_) => {}
+            None => {
+                let spec = self.format.spec(params);
+                let created =
+                    WavWriter::create(&self.path, spec).map_err(|e| self.wav_failed(e))?;
+                *writer = Some(created);
+            }
+        }
+        drop(writer);
+
+        let writer = self.writer.clone();
+        let format = self.format;
+        let path = self.path.clone();
+        Ok(Pump::spawn(params, render, failed, false, move |samples| {
+            let mut writer = writer.lock().unwrap_or_log();
+            let Some(writer) = writer.as_mut() else {
+                return Ok(());
+            };
+            for &sample in samples {
+                format
+                    .write(writer, sample)
+                    .map_err(|error| SinkError::WavFailed {
+                        path: path.clone(),
+                        error,
+                    })?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        match self.writer.lock().unwrap_or_log().take() {
+            Some(writer) => writer.finalize().map_err(|e| self.wav_failed(e)),
+            None => Ok(()),
+        }
+    }
+}
+
+//
+// FLAC
+//
+
+/// Writes FLAC files as fast as the audio can be decoded
+pub struct FlacSink {
+    path: PathBuf,
+    format: SampleFormat,
+    writer: Arc<Mutex<Option<FlacWriter<BufWriter<File>>>>>,
+}
+
+impl FlacSink {
+    /// The file gets created when playback starts, at whatever rate and
+    /// channel count it starts with
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            format: SampleFormat::Int24,
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// FLAC only stores integers, so opening fails if this is `Float`
+    #[must_use]
+    pub fn with_format(mut self, format: SampleFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn flac_failed(&self, error: io::Error) -> SinkError {
+        SinkError::FlacFailed {
+            path: self.path.clone(),
+            error,
+        }
+    }
+}
+
+impl AudioSink for FlacSink {
+    fn open(
+        &mut self,
+        params: AudioParams,
+        render: Render,
+        failed: Failed,
+    ) -> Result<Stream, SinkError> {
+        let bits = match self.format {
+            SampleFormat::Float => return Err(SinkError::FlacFloat),
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+        };
+        let mut writer = self.writer.lock().unwrap_or_log();
+        match &*writer {
+            Some(existing) if existing.sample_rate() != params.sample_rate => {
+                return Err(SinkError::RateChanged {
+                    from: existing.sample_rate(),
+                    to: params.sample_rate,
+                });
+            }
+            Some(_) => {}
+            None => {
+                let created = File::create(&self.path)
+                    .and_then(|file| {
+                        FlacWriter::new(
+                            BufWriter::new(file),
+                            params.channel_count,
+                            params.sample_rate,
+                            bits,
+                        )
+                    })
+                    .map_err(|e| self.flac_failed(e))?;
+                *writer = Some(created);
+            }
+        }
+        drop(writer);
+
+        let writer = self.writer.clone();
+        let path = self.path.clone();
+        Ok(Pump::spawn(params, render, failed, false, move |samples| {
+            let mut writer = writer.lock().unwrap_or_log();
+            let Some(writer) = writer.as_mut() else {
+                return Ok(());
+            };
+            for &sample in samples {
+                writer.write_sample(to_int(sample, bits)).map_err(|error| {
+                    SinkError::FlacFailed {
+                        path: path.clone(),
+                        error,
+                    }
+                })?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        match self.writer.lock().unwrap_or_log().take() {
+            Some(writer) => writer.finalize().map(drop).map_err(|e| self.flac_failed(e)),
+            None => Ok(()),
+        }
+    }
+}
+
+//
+// Raw PCM
+//
+
+/// Writes raw interleaved 32-bit float little-endian samples, for piping into
+/// other tools. How fast it goes is up to whatever's reading them.
+pub struct PcmSink<W> {
+    out: Arc<Mutex<W>>,
+}
+
+impl PcmSink<io::Stdout> {
+    #[must_use]
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send + 'static> PcmSink<W> {
+    #[must_use]
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> AudioSink for PcmSink<W> {
+    fn open(
+        &mut self,
+        params: AudioParams,
+        render: Render,
+        failed: Failed,
+    ) -> Result<Stream, SinkError> {
+        let out = self.out.clone();
+        let mut bytes = Vec::new();
+        Ok(Pump::spawn(params, render, failed, false, move |samples| {
+            bytes.clear();
+            bytes.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+            Ok(out.lock().unwrap_or_log().write_all(&bytes)?)
+        }))
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        Ok(self.out.lock().unwrap_or_log().flush()?)
+    }
+}
+
+//
+// Pump
+//
+
+/// A thread pulling audio for sinks that aren't a device, until it's dropped
+struct Pump {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pump {
+    /// How long to wait for the player when it has nothing for us
+    const IDLE: Duration = Duration::from_millis(1);
+
+    fn spawn<F>(
+        params: AudioParams,
+        mut render: Render,
+        failed: Failed,
+        realtime: bool,
+        mut write: F,
+    ) -> Stream
+    where
+        F: FnMut(&[f32]) -> Result<(), SinkError> + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let device = params.output_device_parameters();
+        let frames = device.channel_sample_count;
+        let mut buf = vec![0.0; frames * device.channels_count];
+        let period = Duration::from_secs_f64(frames as f64 / f64::from(params.sample_rate));
+
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut deadline = Instant::now();
+                while !stopped.load(Ordering::SeqCst) {
+                    let rendered = render(&mut buf);
+                    if let Err(error) = write(&buf[..rendered]) {
+                        tracing::error!(%error, "Error writing audio; stopping");
+                        failed(error);
+                        break;
+                    }
+                    if realtime {
+                        deadline += period;
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    } else if rendered == 0 {
+                        thread::sleep(Self::IDLE);
+                    }
+                }
+            })
+        };
+        Stream {
+            _running: Box::new(Self {
+                stopped,
+                thread: Some(thread),
+            }),
+        }
+    }
+}
+
+impl Drop for Pump {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect_or_log("Error joining sink thread");
+        }
+    }
+}
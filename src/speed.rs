@@ -0,0 +1,548 @@
+use clap::ValueEnum;
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SpeedError {
+    #[error("invalid speed `{0}`, expected something like `1.5` or `0.75x`")]
+    InvalidFormat(String),
+
+    #[error("invalid speed `{0}`, it has to be between 0.5 and 2")]
+    OutOfRange(String),
+}
+
+/// How fast to play, in percent of normal speed, from half to double
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Speed(u16);
+
+impl Speed {
+    pub const NORMAL: Self = Self(100);
+    pub const SLOWEST: Self = Self(50);
+    pub const FASTEST: Self = Self(200);
+
+    #[must_use]
+    pub fn from_percent(percent: u16) -> Self {
+        Self(percent.clamp(Self::SLOWEST.0, Self::FASTEST.0))
+    }
+
+    #[must_use]
+    pub fn percent(self) -> u16 {
+        self.0
+    }
+
+    /// How much source audio goes by for every second played
+    #[must_use]
+    pub fn ratio(self) -> f64 {
+        f64::from(self.0) / 100.0
+    }
+
+    #[must_use]
+    pub fn faster(self, percent: u16) -> Self {
+        Self::from_percent(self.0.saturating_add(percent))
+    }
+
+    #[must_use]
+    pub fn slower(self, percent: u16) -> Self {
+        Self::from_percent(self.0.saturating_sub(percent))
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self::NORMAL
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}×", self.ratio())
+    }
+}
+
+impl FromStr for Speed {
+    type Err = SpeedError;
+
+    /// Parse a ratio like `1.5`, optionally followed by `x` or `×`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let ratio: f64 = value
+            .trim()
+            .trim_end_matches(['x', '×'])
+            .parse()
+            .map_err(|_| SpeedError::InvalidFormat(value.to_owned()))?;
+        if !(Self::SLOWEST.ratio()..=Self::FASTEST.ratio()).contains(&ratio) {
+            return Err(SpeedError::OutOfRange(value.to_owned()));
+        }
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Ok(Self::from_percent((ratio * 100.0).round() as u16))
+    }
+}
+
+/// What happens to the pitch when the speed changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SpeedMode {
+    /// Stretch time and keep the pitch, for speech
+    #[default]
+    KeepPitch,
+    /// Play faster or slower like a tape, taking the pitch with it
+    Varispeed,
+}
+
+/// Changes the speed of interleaved audio as it streams through
+///
+/// Keeping the pitch uses WSOLA: overlapping windows of the input are added
+/// back together at a different spacing, each one nudged to wherever lines
+/// up best with the last so the waveforms don't cancel out. Varispeed just
+/// reads the input faster or slower, interpolating between frames.
+///
+/// Buffers are all allocated up front, so once it's made this doesn't
+/// allocate, which makes it safe to run from an audio callback. Input only
+/// goes in as fast as there's room for it.
+pub struct SpeedChanger {
+    channels: usize,
+    speed: Speed,
+    mode: SpeedMode,
+    /// Hann window over a segment. Halves of it add up to 1 when they
+    /// overlap.
+    window: Vec<f32>,
+    /// How far from where it ideally starts a segment can be taken from
+    tolerance: usize,
+    capacity: usize,
+    input: Vec<f32>,
+    /// Where in `input` the next segment would ideally start, in frames, or
+    /// the next frame to interpolate when varispeeding
+    nominal: f64,
+    /// Where the last segment would carry on from if it kept going
+    previous: Option<usize>,
+    /// The second half of the last segment, waiting for the next one
+    overlap: Vec<f32>,
+    ready: Vec<f32>,
+    ready_read: usize,
+    mono: Vec<f32>,
+    target: Vec<f32>,
+    /// No more input is coming, so whatever's left gets played
+    ended: bool,
+}
+
+impl SpeedChanger {
+    /// Most input there's room for at once on top of what's needed, in frames
+    const HEADROOM: usize = 2048;
+
+    #[must_use]
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let mut changer = Self {
+            channels: channels.max(1),
+            speed: Speed::NORMAL,
+            mode: SpeedMode::default(),
+            window: Vec::new(),
+            tolerance: 0,
+            capacity: 0,
+            input: Vec::new(),
+            nominal: 0.0,
+            previous: None,
+            overlap: Vec::new(),
+            ready: Vec::new(),
+            ready_read: 0,
+            mono: Vec::new(),
+            target: Vec::new(),
+            ended: false,
+        };
+        changer.set_sample_rate(sample_rate);
+        changer
+    }
+
+    /// Size everything for a new sample rate, starting over
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        // segments of 25ms, which can move by up to 5ms either way
+        let size = (sample_rate as usize / 80).max(32) * 2;
+        let half = size / 2;
+        self.tolerance = sample_rate as usize / 200;
+        #[allow(clippy::cast_precision_loss)]
+        let window = (0..size).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos());
+        self.window = window.collect();
+
+        let channels = self.channels;
+        self.capacity = (4 * size + 2 * self.tolerance + Self::HEADROOM) * channels;
+        self.input = Vec::with_capacity(self.capacity);
+        self.overlap = vec![0.0; half * channels];
+        self.ready = Vec::with_capacity(half * channels);
+        self.mono = Vec::with_capacity(2 * self.tolerance + half + 1);
+        self.target = Vec::with_capacity(half);
+        self.reset();
+    }
+
+    /// Change speed. Going to or from normal speed, or switching modes,
+    /// has to wait until whatever's buffered has been read out the old way,
+    /// so until then this only finishes the input and has to be called
+    /// again once it's empty.
+    pub fn set(&mut self, speed: Speed, mode: SpeedMode) {
+        let bypassed = speed == Speed::NORMAL;
+        if mode != self.mode || bypassed != self.is_bypassed() {
+            if !self.is_empty() {
+                self.finish();
+                return;
+            }
+            self.reset();
+        }
+        self.speed = speed;
+        self.mode = mode;
+    }
+
+    /// Whether audio should skip this altogether
+    #[must_use]
+    pub fn is_bypassed(&self) -> bool {
+        self.speed == Speed::NORMAL
+    }
+
+    /// Forget everything, like after a seek
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.nominal = 0.0;
+        self.previous = None;
+        self.overlap.fill(0.0);
+        self.ready.clear();
+        self.ready_read = 0;
+        self.ended = false;
+    }
+
+    /// Let `fill` write as many samples as there's room for, returning how
+    /// many it wrote
+    pub fn push_with<F: FnOnce(&mut [f32]) -> usize>(&mut self, fill: F) -> usize {
+        let len = self.input.len();
+        self.input.resize(self.capacity, 0.0);
+        let filled = fill(&mut self.input[len..]) / self.channels * self.channels;
+        self.input.truncate(len + filled);
+        if filled > 0 {
+            self.ended = false;
+        }
+        filled
+    }
+
+    /// Nothing more is coming for now, so whatever's held back for lining up
+    /// segments should be played
+    pub fn finish(&mut self) {
+        self.ended = true;
+    }
+
+    /// Whether everything that's gone in has come out
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let next = self.nominal as usize;
+        self.ready_read >= self.ready.len() && next >= self.frames()
+    }
+
+    /// Roughly how many of the samples that have gone in are still to be
+    /// heard, counting what's been stretched but not read yet at the speed
+    /// it was taken at
+    #[must_use]
+    pub fn buffered(&self) -> usize {
+        #[allow(clippy::cast_precision_loss)]
+        let unread = (self.ready.len() - self.ready_read.min(self.ready.len())) as f64;
+        #[allow(clippy::cast_precision_loss)]
+        let frames = self.frames() as f64 - self.nominal
+            + unread / self.channels as f64 * self.speed.ratio();
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let frames = frames.max(0.0).round() as usize;
+        frames * self.channels
+    }
+
+    /// Fill as much of `out` as the input allows, returning how many samples
+    /// that was
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let whole = out.len() / self.channels * self.channels;
+        let out = &mut out[..whole];
+        match self.mode {
+            SpeedMode::Varispeed => self.interpolate(out),
+            SpeedMode::KeepPitch => {
+                let mut written = 0;
+                loop {
+                    let ready = &self.ready[self.ready_read..];
+                    let n = ready.len().min(out.len() - written);
+                    out[written..written + n].copy_from_slice(&ready[..n]);
+                    self.ready_read += n;
+                    written += n;
+                    if written == out.len() || !self.stretch() {
+                        return written;
+                    }
+                }
+            }
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    /// A sample from the input, or silence past the end of it
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        let index = frame * self.channels + channel;
+        self.input.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Overlap-add the next segment into `ready`, if there's enough input
+    /// to pick one
+    fn stretch(&mut self) -> bool {
+        let channels = self.channels;
+        let half = self.window.len() / 2;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let nominal = self.nominal.round() as usize;
+        let (low, high) = match self.previous {
+            Some(_) => (
+                nominal.saturating_sub(self.tolerance),
+                nominal + self.tolerance,
+            ),
+            None => (nominal, nominal),
+        };
+        let needed = (high + self.window.len()).max(self.previous.map_or(0, |p| p + half));
+        let enough = if self.ended {
+            nominal < self.frames()
+        } else {
+            needed <= self.frames()
+        };
+        if !enough {
+            return false;
+        }
+
+        let start = match self.previous {
+            Some(previous) => self.best_match(low, high, previous),
+            None => nominal,
+        };
+        self.ready.clear();
+        self.ready_read = 0;
+        for i in 0..half {
+            for channel in 0..channels {
+                let sample = self.sample(start + i, channel);
+                // the very first segment comes in at full level rather than
+                // fading in from nothing
+                let mixed = match self.previous {
+                    Some(_) => self.overlap[i * channels + channel] + sample * self.window[i],
+                    None => sample,
+                };
+                self.ready.push(mixed);
+                self.overlap[i * channels + channel] =
+                    self.sample(start + half + i, channel) * self.window[half + i];
+            }
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let step = self.speed.ratio() * half as f64;
+        self.nominal += step;
+        // nothing before here can be part of a segment any more
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let next = (self.nominal.round() as usize).saturating_sub(self.tolerance);
+        let done = next.min(start + half).min(self.frames());
+        self.input.drain(..done * channels);
+        #[allow(clippy::cast_precision_loss)]
+        let done_frames = done as f64;
+        self.nominal -= done_frames;
+        self.previous = Some(start + half - done);
+        true
+    }
+
+    /// The start between `low` and `high` whose first half looks most like
+    /// the audio at `natural`
+    fn best_match(&mut self, low: usize, high: usize, natural: usize) -> usize {
+        let half = self.window.len() / 2;
+        let mono = |changer: &Self, frame| {
+            (0..changer.channels)
+                .map(|channel| changer.sample(frame, channel))
+                .sum::<f32>()
+        };
+        let mut candidates = std::mem::take(&mut self.mono);
+        let mut target = std::mem::take(&mut self.target);
+        candidates.clear();
+        candidates.extend((low..high + half).map(|frame| mono(self, frame)));
+        target.clear();
+        target.extend((natural..natural + half).map(|frame| mono(self, frame)));
+
+        let mut energy = candidates[..half].iter().map(|s| s * s).sum::<f32>();
+        let mut best = (low, f32::MIN);
+        for offset in 0..=high - low {
+            if offset > 0 {
+                let (gone, new) = (candidates[offset - 1], candidates[offset + half - 1]);
+                energy = (energy + new * new - gone * gone).max(0.0);
+            }
+            let window = &candidates[offset..offset + half];
+            let correlation = window.iter().zip(&target).map(|(a, b)| a * b).sum::<f32>();
+            let score = correlation / energy.max(1e-9).sqrt();
+            if score > best.1 {
+                best = (low + offset, score);
+            }
+        }
+
+        self.mono = candidates;
+        self.target = target;
+        best.0
+    }
+
+    /// Read through the input at the new speed, interpolating between frames
+    fn interpolate(&mut self, out: &mut [f32]) -> usize {
+        let channels = self.channels;
+        let step = self.speed.ratio();
+        let mut written = 0;
+        while written < out.len() {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let frame = self.nominal as usize;
+            let available = if self.ended {
+                frame < self.frames()
+            } else {
+                frame + 1 < self.frames()
+            };
+            if !available {
+                break;
+            }
+            #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+            let fraction = (self.nominal - frame as f64) as f32;
+            for channel in 0..channels {
+                let (from, to) = (self.sample(frame, channel), self.sample(frame + 1, channel));
+                out[written + channel] = from + (to - from) * fraction;
+            }
+            written += channels;
+            self.nominal += step;
+        }
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let done = (self.nominal as usize).min(self.frames());
+        self.input.drain(..done * channels);
+        #[allow(clippy::cast_precision_loss)]
+        let done_frames = done as f64;
+        self.nominal -= done_frames;
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speeds_parse() {
+        assert_eq!("1.5".parse(), Ok(Speed::from_percent(150)));
+        assert_eq!(" 0.75x".parse(), Ok(Speed::from_percent(75)));
+        assert_eq!("2×".parse(), Ok(Speed::FASTEST));
+        assert!(matches!(
+            "3".parse::<Speed>(),
+            Err(SpeedError::OutOfRange(_))
+        ));
+        assert!(matches!(
+            "fast".parse::<Speed>(),
+            Err(SpeedError::InvalidFormat(_))
+        ));
+        assert_eq!(Speed::from_percent(125).to_string(), "1.25×");
+        assert_eq!(Speed::FASTEST.faster(10), Speed::FASTEST);
+    }
+
+    /// Push a stereo sine through at `speed` and collect everything that
+    /// comes out of the left channel
+    fn run(speed: Speed, mode: SpeedMode, frequency: f32, frames: usize) -> Vec<f32> {
+        let rate = 44_100;
+        let mut changer = SpeedChanger::new(2, rate);
+        changer.set(speed, mode);
+        let input = (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * frequency * i as f32 / rate as f32).sin() * 0.5;
+                [s, s * 0.5]
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = vec![];
+        let mut buf = [0.0; 512];
+        let mut pushed = 0;
+        loop {
+            pushed += changer.push_with(|room| {
+                let n = room.len().min(input.len() - pushed);
+                room[..n].copy_from_slice(&input[pushed..pushed + n]);
+                n
+            });
+            if pushed == input.len() {
+                changer.finish();
+            }
+            let n = changer.read(&mut buf);
+            out.extend(buf[..n].chunks_exact(2).map(|frame| frame[0]));
+            if n == 0 && changer.is_empty() {
+                return out;
+            }
+        }
+    }
+
+    /// Frequency of a sine, going by how often it crosses zero on the way up
+    fn frequency(samples: &[f32]) -> f32 {
+        let rises = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        rises as f32 * 44_100.0 / samples.len() as f32
+    }
+
+    #[test]
+    fn test_keeping_pitch_changes_length_but_not_frequency() {
+        for (percent, expected) in [(50, 88_200.0), (150, 29_400.0), (200, 22_050.0)] {
+            let out = run(
+                Speed::from_percent(percent),
+                SpeedMode::KeepPitch,
+                440.0,
+                44_100,
+            );
+            assert!(
+                (out.len() as f32 - expected).abs() < 2_000.0,
+                "{percent}%: {} frames",
+                out.len()
+            );
+            let middle = &out[out.len() / 4..out.len() * 3 / 4];
+            assert!(
+                (frequency(middle) - 440.0).abs() < 10.0,
+                "{percent}%: {} Hz",
+                frequency(middle)
+            );
+            // segments line up, so they don't cancel each other out
+            let peak = middle.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!(peak > 0.45 && peak < 0.55, "{percent}%: peak {peak}");
+        }
+    }
+
+    #[test]
+    fn test_varispeed_takes_the_pitch_with_it() {
+        let out = run(
+            Speed::from_percent(150),
+            SpeedMode::Varispeed,
+            440.0,
+            44_100,
+        );
+        assert!((out.len() as f32 - 29_400.0).abs() < 10.0);
+        assert!((frequency(&out) - 660.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_switching_plays_out_whats_buffered_first() {
+        let mut changer = SpeedChanger::new(1, 44_100);
+        changer.set(Speed::FASTEST, SpeedMode::Varispeed);
+        let pushed = changer.push_with(|room| {
+            let n = room.len().min(4000);
+            for (i, sample) in room[..n].iter_mut().enumerate() {
+                *sample = i as f32;
+            }
+            n
+        });
+        assert_eq!(pushed, 4000);
+        let mut buf = [0.0; 4000];
+        assert_eq!(changer.read(&mut buf[..100]), 100);
+
+        for (speed, mode) in [
+            (Speed::NORMAL, SpeedMode::Varispeed),
+            (Speed::FASTEST, SpeedMode::KeepPitch),
+        ] {
+            changer.set(speed, mode);
+            assert!(!changer.is_bypassed());
+        }
+        // the rest still comes out at double speed, up to the very end
+        let n = changer.read(&mut buf);
+        assert_eq!(n, 1900);
+        assert_eq!(buf[n - 1], 3998.0);
+        assert!(changer.is_empty());
+
+        changer.set(Speed::NORMAL, SpeedMode::Varispeed);
+        assert!(changer.is_bypassed());
+    }
+}
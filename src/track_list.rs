@@ -0,0 +1,433 @@
+use crate::files::{self, SkippedFile};
+use crate::metadata::{Probe, ReplayGain, TrackMetadataError};
+use crate::resample;
+use crate::types::AudioParams;
+use itertools::Itertools;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing_unwrap::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub path: PathBuf,
+    pub sample_rate: u32,
+    pub samples: u64,
+    pub channels: u8,
+    pub album: String,
+    pub album_artist: String,
+    pub title: String,
+    pub track: u32,
+    pub replay_gain: ReplayGain,
+}
+
+impl Track {
+    /// Read a track's stream info and tags
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be probed or is missing any of the
+    /// tags the player needs to show it
+    pub fn from_path(path: PathBuf) -> Result<Self, TrackMetadataError> {
+        let probe = Probe::from_path(&path)?;
+        let title = probe
+            .title
+            .ok_or_else(|| TrackMetadataError::MissingTitle { path: path.clone() })?;
+        let album = probe
+            .album
+            .ok_or_else(|| TrackMetadataError::MissingAlbum { path: path.clone() })?;
+        let album_artist = probe
+            .album_artist
+            .ok_or_else(|| TrackMetadataError::MissingAlbumArtist { path: path.clone() })?;
+        let track = probe
+            .track
+            .ok_or_else(|| TrackMetadataError::MissingTrack { path: path.clone() })?;
+
+        Ok(Self {
+            path,
+            sample_rate: probe.sample_rate,
+            samples: probe.total_samples,
+            channels: probe.channels,
+            album,
+            album_artist,
+            title,
+            track,
+            replay_gain: probe.replay_gain,
+        })
+    }
+
+    /// Read every audio file among `paths`, walking directories, and split
+    /// them into tracks and the files that can't be played
+    pub fn from_paths<P: AsRef<Path>>(paths: &[P]) -> (Vec<Self>, Vec<SkippedFile>) {
+        let (tracks, skipped): (Vec<_>, Vec<SkippedFile>) = files::only_audio(paths)
+            .into_iter()
+            .map(|path| {
+                let path = path?;
+                let skip = |reason: String| SkippedFile {
+                    path: path.clone(),
+                    reason,
+                };
+                let track = Self::from_path(path.clone()).map_err(|e| skip(e.to_string()))?;
+                track.check_params().map_err(|e| skip(e.to_string()))?;
+                Ok(track)
+            })
+            .partition_result();
+        for file in &skipped {
+            tracing::warn!(path = ?file.path, reason = file.reason, "Skipping file");
+        }
+        (tracks, skipped)
+    }
+
+    /// Whether the player could play this track at all
+    fn check_params(&self) -> Result<(), TrackListError> {
+        if self.sample_rate == 0 || self.channels == 0 {
+            return Err(TrackListError::IncompatibleParams {
+                path: self.path.clone(),
+                sample_rate: self.sample_rate,
+                channels: self.channels,
+            });
+        }
+        Ok(())
+    }
+
+    /// How many samples (per channel) long this track is at `sample_rate`
+    #[must_use]
+    pub fn samples_at(&self, sample_rate: u32) -> u64 {
+        resample::convert_frames(self.samples, self.sample_rate, sample_rate)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackList {
+    /// Change these with the methods below, which keep `offsets` in step
+    pub tracks: Vec<Track>,
+    pub total_samples: u64,
+    /// The rate positions in the track list are counted at. Tracks at other
+    /// rates get resampled to it, so their lengths are converted too.
+    pub sample_rate: u32,
+    /// Where each track starts, followed by where the last one ends, so
+    /// finding a track by position is a binary search
+    offsets: Vec<u64>,
+}
+
+/// Why a track list couldn't be made
+#[derive(Error, Debug)]
+pub enum TrackListError {
+    #[error("there's nothing to play")]
+    Empty,
+
+    #[error(
+        "none of the files are in a format that can be played ({})",
+        files::SUPPORTED_EXTENSIONS.join(", ")
+    )]
+    Unsupported { paths: Vec<PathBuf> },
+
+    #[error("{} can't be played: it has {channels} channels at {sample_rate}Hz", path.display())]
+    IncompatibleParams {
+        path: PathBuf,
+        sample_rate: u32,
+        channels: u8,
+    },
+
+    #[error("none of the files could be read")]
+    Unreadable { files: Vec<SkippedFile> },
+}
+
+// Methods like `audio_params`, `get_track` and `find_playing` panic on an
+// empty list, and `unsafe_new` and `From<Vec<Track>>` will happily make one.
+// Anything that comes from outside the program should go through
+// `try_from_files` or `try_from_tracks` instead.
+impl TrackList {
+    /// Create a new empty track list
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe because methods on this struct will panic if the track list
+    /// is empty, so the user of this function must ensure correct initialization before
+    /// use, or else the program will panic.
+    pub fn unsafe_new() -> Self {
+        Self {
+            tracks: Vec::new(),
+            total_samples: 0,
+            sample_rate: 0,
+            offsets: vec![0],
+        }
+    }
+
+    /// Create a track list from files and directories, leaving out any files
+    /// that can't be played as long as some can
+    ///
+    /// Files that aren't audio at all, like cover art, are left out without
+    /// a mention. The rest come back with the reason they were left out.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there's nothing left to play, saying why
+    pub fn try_from_files<P: AsRef<Path>>(
+        paths: &[P],
+    ) -> Result<(Self, Vec<SkippedFile>), TrackListError> {
+        let (tracks, skipped) = Track::from_paths(paths);
+        if !tracks.is_empty() {
+            return Ok((Self::try_from_tracks(tracks)?, skipped));
+        }
+        if !skipped.is_empty() {
+            return Err(TrackListError::Unreadable { files: skipped });
+        }
+
+        // nothing even looked like audio
+        let (missing, unsupported): (Vec<_>, Vec<_>) =
+            files::walk(paths).into_iter().partition(|p| !p.exists());
+        if !missing.is_empty() {
+            let files = missing
+                .into_iter()
+                .map(|path| SkippedFile {
+                    path,
+                    reason: "no such file or directory".to_owned(),
+                })
+                .collect();
+            return Err(TrackListError::Unreadable { files });
+        }
+        if !unsupported.is_empty() {
+            return Err(TrackListError::Unsupported { paths: unsupported });
+        }
+        Err(TrackListError::Empty)
+    }
+
+    /// Create a track list that's safe to play from
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no tracks, or any of them can't be
+    /// played
+    pub fn try_from_tracks(tracks: Vec<Track>) -> Result<Self, TrackListError> {
+        if tracks.is_empty() {
+            return Err(TrackListError::Empty);
+        }
+        for track in &tracks {
+            track.check_params()?;
+        }
+        Ok(tracks.into())
+    }
+
+    pub fn add_track(&mut self, track: Track) {
+        self.add_tracks(vec![track]);
+    }
+
+    /// Add tracks to the end of the list
+    ///
+    /// The first tracks added to an empty list decide its sample rate: the
+    /// highest rate among them, so nothing gets downsampled.
+    pub fn add_tracks(&mut self, tracks: Vec<Track>) {
+        if self.sample_rate == 0 {
+            self.sample_rate = tracks.iter().map(|t| t.sample_rate).max().unwrap_or(0);
+        }
+        let appended = self.tracks.len();
+        self.tracks.extend(tracks);
+        self.recount_from(appended);
+    }
+
+    pub(crate) fn recount(&mut self) {
+        self.recount_from(0);
+    }
+
+    /// Redo the offsets of the tracks from `index` on, after they've changed
+    fn recount_from(&mut self, index: usize) {
+        self.offsets.truncate(index + 1);
+        let mut offset = self.offsets[index];
+        for track in &self.tracks[index..] {
+            offset += track.samples_at(self.sample_rate);
+            self.offsets.push(offset);
+        }
+        self.total_samples = offset;
+    }
+
+    /// Remove the track at the given **0-based index**
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the index is out of bounds
+    pub fn remove_track(&mut self, index: usize) -> Track {
+        let track = self.tracks.remove(index);
+        self.recount_from(index);
+        track
+    }
+
+    /// Move the track at `from` so it ends up at `to`, shifting the tracks
+    /// in between
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if either index is out of bounds
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        assert!(to < self.tracks.len(), "Index out of bounds");
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.recount_from(from.min(to));
+    }
+
+    /// The track playing at `current_sample`: the first one that ends after
+    /// it, or the last one if they've all ended
+    pub fn find_playing(&self, current_sample: u64) -> usize {
+        let ends = &self.offsets[1..];
+        let found = ends.partition_point(|&end| end <= current_sample);
+        found.min(self.tracks.len().saturating_sub(1))
+    }
+
+    /// Where the track at `index` starts, or where the list ends if there's
+    /// no such track
+    pub fn get_start_point(&self, index: usize) -> u64 {
+        self.offsets[index.min(self.tracks.len())]
+    }
+
+    /// Get the track by it's **0-based index** in the track list
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the index is out of bounds
+    pub fn get_track(&self, index: usize) -> &Track {
+        assert!(index < self.tracks.len(), "Index out of bounds");
+        &self.tracks[index]
+    }
+
+    pub fn get_end_point(&self, index: usize) -> u64 {
+        self.get_start_point(index) + self.get_sample_count(index)
+    }
+
+    /// Get the number of samples in the track at the given index, at the
+    /// track list's sample rate
+    pub fn get_sample_count(&self, index: usize) -> u64 {
+        self.get_track(index).samples_at(self.sample_rate)
+    }
+
+    /// Get the audio params for this track list
+    ///
+    /// Output is stereo unless every track is mono. Tracks with other channel
+    /// counts get remixed to fit.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the track list is empty
+    #[must_use]
+    pub fn audio_params(&self) -> AudioParams {
+        assert!(self.sample_rate > 0, "No sample rate found");
+
+        AudioParams {
+            channel_count: self.max_channels().min(2),
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    /// The most channels any track in the list has
+    #[must_use]
+    pub fn max_channels(&self) -> u8 {
+        self.tracks
+            .iter()
+            .map(|t| t.channels)
+            .max()
+            .expect_or_log("No channels found")
+    }
+
+    pub fn get_bounds(&self, i: usize) -> (u64, u64) {
+        let start = self.get_start_point(i);
+        let end = start + self.get_sample_count(i);
+        (start, end)
+    }
+}
+
+impl Default for TrackList {
+    fn default() -> Self {
+        Self::unsafe_new()
+    }
+}
+
+impl From<Vec<Track>> for TrackList {
+    fn from(v: Vec<Track>) -> Self {
+        let mut tl = Self::unsafe_new();
+        tl.add_tracks(v);
+        tl
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A stereo track at 44.1kHz that's never opened
+    pub(crate) fn test_track(album: &str, track: u32, samples: u64) -> Track {
+        Track {
+            path: PathBuf::from(format!("{album}-{track:02}.flac")),
+            sample_rate: 44_100,
+            samples,
+            channels: 2,
+            album: album.to_owned(),
+            album_artist: "Artist".to_owned(),
+            title: format!("Track {track}"),
+            track,
+            replay_gain: ReplayGain::default(),
+        }
+    }
+
+    #[test]
+    fn test_track_lists_need_something_to_play() {
+        let no_paths: &[String] = &[];
+        assert!(matches!(
+            TrackList::try_from_files(no_paths),
+            Err(TrackListError::Empty)
+        ));
+        assert!(matches!(
+            TrackList::try_from_tracks(vec![]),
+            Err(TrackListError::Empty)
+        ));
+        let mut silent = test_track("A", 2, 100);
+        silent.channels = 0;
+        assert!(matches!(
+            TrackList::try_from_tracks(vec![test_track("A", 1, 100), silent]),
+            Err(TrackListError::IncompatibleParams { channels: 0, .. })
+        ));
+
+        // a directory gets more and more wrong with it
+        let dir = std::env::temp_dir().join(format!("wigglyair-no-music-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = [&dir];
+        assert!(matches!(
+            TrackList::try_from_files(&paths),
+            Err(TrackListError::Empty)
+        ));
+        std::fs::write(dir.join("cover.jpg"), b"").unwrap();
+        assert!(matches!(
+            TrackList::try_from_files(&paths),
+            Err(TrackListError::Unsupported { paths }) if paths.len() == 1
+        ));
+        std::fs::write(dir.join("broken.flac"), b"not flac").unwrap();
+        let result = TrackList::try_from_files(&paths);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            result,
+            Err(TrackListError::Unreadable { files }) if files.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_mixed_sample_rates_share_one_timeline() {
+        let mut tracks = TrackList::from(vec![
+            test_track("A", 1, 44_100 * 10),
+            Track {
+                sample_rate: 96_000,
+                ..test_track("A", 2, 96_000 * 20)
+            },
+        ]);
+        assert_eq!(tracks.audio_params().sample_rate, 96_000);
+        assert_eq!(tracks.get_bounds(1), (96_000 * 10, 96_000 * 30));
+        assert_eq!(tracks.find_playing(96_000 * 10 - 1), 0);
+        assert_eq!(tracks.find_playing(96_000 * 10), 1);
+
+        // the rate sticks once there are tracks
+        tracks.add_track(Track {
+            sample_rate: 192_000,
+            ..test_track("B", 1, 192_000 * 5)
+        });
+        assert_eq!(tracks.sample_rate, 96_000);
+        assert_eq!(tracks.total_samples, 96_000 * 35);
+        tracks.remove_track(2);
+        assert_eq!(tracks.total_samples, 96_000 * 30);
+    }
+}
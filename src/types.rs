@@ -1,35 +1,38 @@
+use crate::analyzer::AudioTap;
 use crate::configuration::Settings;
-use crate::files;
-use audio_thread_priority::promote_current_thread_to_real_time;
-use crossbeam::channel::{self, Sender, TryRecvError};
-use itertools::FoldWhile::{Continue, Done};
+use crate::equalizer::{Equalizer, Preset};
+pub use crate::files::SkippedFile;
+use crate::metadata::ReplayGain;
+use crate::output::{CallbackMessage, GainRamp, Output, OutputSignal};
+use crate::queue::{Queue, QueueEdit};
+use crate::reader::{Decoded, FileReader, ReaderCommand};
+use crate::resample::RateConversion;
+use crate::ring::chunk_ring;
+use crate::sink::{AudioSink, DeviceSink, Failed, Render};
+use crate::speed::{Speed, SpeedChanger, SpeedMode};
+pub use crate::track_list::{Track, TrackList, TrackListError};
+use crate::waveform::Waveform;
+use clap::ValueEnum;
+use crossbeam::channel::{self, Receiver, Sender};
 use itertools::Itertools;
-use metaflac::Tag;
+use rand::seq::SliceRandom;
 use serde::Serialize;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::io::MediaSourceStreamOptions;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
-use tinyaudio::run_output_device;
+use thiserror::Error;
 use tinyaudio::OutputDeviceParameters;
 use tracing_unwrap::*;
 
 #[derive(Debug)]
 pub struct AppState {
     pub settings: Settings,
+    /// The library database, if the settings point at one
+    pub library: Option<tokio_rusqlite::Connection>,
 }
 
 pub type SharedState = Arc<AppState>;
@@ -39,6 +42,13 @@ pub struct DebugResponse {
     pub paths: Vec<String>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct WaveformResponse {
+    pub path: String,
+    #[serde(flatten)]
+    pub waveform: Waveform,
+}
+
 //
 // Volume
 //
@@ -47,21 +57,97 @@ pub struct DebugResponse {
 pub enum VolumeError {
     InvalidValue(u8),
     InvalidString(String),
+    InvalidCurve { min_db: f32, max_db: f32 },
+}
+
+/// How volume steps map to gain
+///
+/// Step 0 is silent, step 1 is `min_db` and step 100 is `max_db`, with the
+/// steps in between evenly spaced in dB, so each one sounds about as big as
+/// the last.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeCurve {
+    min_db: f32,
+    max_db: f32,
+}
+
+impl VolumeCurve {
+    /// Create a curve from `min_db` up to `max_db`. Setting `max_db` below
+    /// zero caps how loud the player can get; above zero boosts quiet tracks
+    /// at the risk of clipping.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `min_db` isn't below `max_db`
+    pub fn new(min_db: f32, max_db: f32) -> Result<Self, VolumeError> {
+        if min_db < max_db {
+            Ok(Self { min_db, max_db })
+        } else {
+            Err(VolumeError::InvalidCurve { min_db, max_db })
+        }
+    }
+
+    /// The gain in dB at `step`, or `None` if it's muted
+    #[must_use]
+    pub fn db(self, step: u8) -> Option<f32> {
+        let step = step.min(Volume::MAX);
+        (step > 0).then(|| {
+            let ratio = f32::from(step - 1) / f32::from(Volume::MAX - 1);
+            self.min_db + (self.max_db - self.min_db) * ratio
+        })
+    }
+
+    /// The factor to scale samples by at `step`
+    #[must_use]
+    pub fn gain(self, step: u8) -> f32 {
+        self.db(step).map_or(0.0, |db| 10f32.powf(db / 20.0))
+    }
+}
+
+impl Default for VolumeCurve {
+    fn default() -> Self {
+        Self {
+            min_db: -60.0,
+            max_db: 0.0,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct Volume(AtomicU8);
+pub struct Volume {
+    step: AtomicU8,
+    curve: VolumeCurve,
+}
 
 impl Volume {
     const MAX: u8 = 100;
 
     fn unsafe_from(initial: u8) -> Self {
-        Self(AtomicU8::new(initial))
+        Self {
+            step: AtomicU8::new(initial),
+            curve: VolumeCurve::default(),
+        }
+    }
+
+    /// Use `curve` to turn volume steps into gain
+    #[must_use]
+    pub fn with_curve(self, curve: VolumeCurve) -> Self {
+        Self { curve, ..self }
     }
 
     /// Get the current volume
     pub fn get(&self) -> u8 {
-        self.0.load(Ordering::Acquire)
+        self.step.load(Ordering::Acquire)
+    }
+
+    /// The current volume in dB, or `None` if it's muted
+    pub fn db(&self) -> Option<f32> {
+        self.curve.db(self.get())
+    }
+
+    /// The factor to scale samples by at the current volume
+    pub fn gain(&self) -> f32 {
+        self.curve.gain(self.get())
     }
 
     /// Set the volume
@@ -73,14 +159,14 @@ impl Volume {
         if value > Self::MAX {
             Err(VolumeError::InvalidValue(value))
         } else {
-            self.0.store(value, Ordering::Release);
+            self.step.store(value, Ordering::Release);
             Ok(())
         }
     }
 
     fn change(&self, value: i16) -> u8 {
         let mut ret = 0u8;
-        self.0
+        self.step
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev| {
                 let prev = i16::from(prev);
                 let new = (prev + value).clamp(0, 100);
@@ -158,6 +244,77 @@ impl FromStr for Volume {
     }
 }
 
+//
+// Timecode
+//
+
+#[derive(Error, Debug)]
+pub enum TimecodeError {
+    #[error("invalid timecode `{0}`, expected something like `1:23:45`, `23:45` or `45`")]
+    InvalidFormat(String),
+
+    #[error("invalid timecode `{0}`, minutes and seconds must be less than 60")]
+    OutOfRange(String),
+
+    #[error("invalid timecode `{0}`, it's too long")]
+    TooLong(String),
+}
+
+/// A point in time from the start of the track list
+///
+/// Parsed from `hours:minutes:seconds`, `minutes:seconds` or plain seconds.
+/// The seconds may be fractional, e.g. `1:23.5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timecode(Duration);
+
+impl Timecode {
+    pub fn as_duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Timecode {
+    type Err = TimecodeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || TimecodeError::InvalidFormat(value.to_owned());
+        let parts = value.trim().split(':').collect_vec();
+        let (seconds, rest) = parts.split_last().ok_or_else(invalid)?;
+        if rest.len() > 2 {
+            return Err(invalid());
+        }
+
+        let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+        if !seconds.is_finite() || seconds < 0.0 {
+            return Err(invalid());
+        }
+
+        let units = rest
+            .iter()
+            .map(|p| p.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // only the leading unit is allowed to overflow into the next one,
+        // so `90` and `90:00` are fine but `1:90` is probably a typo.
+        let is_leading = units.is_empty();
+        if !is_leading && seconds >= 60.0 || units.iter().skip(1).any(|&u| u >= 60) {
+            return Err(TimecodeError::OutOfRange(value.to_owned()));
+        }
+
+        let too_long = || TimecodeError::TooLong(value.to_owned());
+        let whole = units
+            .iter()
+            .try_fold(0u64, |acc, &u| acc.checked_mul(60)?.checked_add(u))
+            .and_then(|minutes| minutes.checked_mul(60))
+            .ok_or_else(too_long)?;
+        let fraction = Duration::try_from_secs_f64(seconds).map_err(|_| too_long())?;
+        Duration::from_secs(whole)
+            .checked_add(fraction)
+            .map(Self)
+            .ok_or_else(too_long)
+    }
+}
+
 //
 // Audio Params
 //
@@ -175,6 +332,25 @@ impl AudioParams {
         Self::DEFAULT_AUDIO_BUFFER_FRAMES
     }
 
+    /// Number of samples (across all channels) it takes to play `duration`
+    pub fn interleaved_samples_in(self, duration: Duration) -> usize {
+        let frames = usize::try_from(self.samples_in(duration)).unwrap_or(usize::MAX);
+        frames.saturating_mul(usize::from(self.channel_count))
+    }
+
+    /// Number of samples (across all channels) the ring buffer between the
+    /// reader and the audio callback holds when it fills up to `high_watermark`.
+    /// A quarter of it is left over, so there's always room for another chunk.
+    pub fn ring_buffer_samples(self, high_watermark: Duration) -> usize {
+        self.interleaved_samples_in(high_watermark) / 3 * 4
+    }
+
+    /// Number of samples (per channel) it takes to play `duration`
+    pub fn samples_in(self, duration: Duration) -> u64 {
+        let rate = u64::from(self.sample_rate);
+        duration.as_secs() * rate + u64::from(duration.subsec_nanos()) * rate / 1_000_000_000
+    }
+
     fn channel_sample_count(self) -> u32 {
         self.sample_rate / 10
     }
@@ -190,224 +366,298 @@ impl AudioParams {
 }
 
 //
-// TrackList
+// Shuffle and repeat
 //
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Track {
-    pub path: PathBuf,
-    pub sample_rate: u32,
-    pub samples: u64,
-    pub channels: u8,
-    pub album: String,
-    pub album_artist: String,
-    pub title: String,
-    pub track: u32,
-}
-
-impl Track {
-    fn from_path(path: PathBuf) -> Self {
-        let tag = Tag::read_from_path(&path).unwrap();
-        let si = tag.get_streaminfo().unwrap();
-        let samples = si.total_samples;
-        let channels = si.num_channels;
-        let sample_rate = si.sample_rate;
-
-        let comments = tag.vorbis_comments().unwrap_or_else(|| {
-            tracing::error!(?path, "File missing vorbis comments");
-            panic!("Missing comments: {}", path.display())
-        });
-
-        let title = comments
-            .title()
-            .and_then(|v| v.first().cloned())
-            .unwrap_or_else(|| {
-                tracing::error!(?path, "File missing title metadata");
-                panic!("Missing title: {}", path.display())
-            });
-
-        let album = comments
-            .album()
-            .and_then(|v| v.first().cloned())
-            .unwrap_or_else(|| {
-                tracing::error!(?path, "File missing album metadata");
-                panic!("Missing album: {}", path.display())
-            });
-
-        let album_artist = comments
-            .album_artist()
-            .and_then(|v| v.first().cloned())
-            .unwrap_or_else(|| {
-                tracing::error!(?path, "File missing album_artist metadata");
-                panic!("Missing album_artist: {}", path.display())
-            });
-
-        let track = comments.track().unwrap_or_else(|| {
-            tracing::error!(?path, "File missing track");
-            panic!("Missing track")
-        });
+/// What to do when a track or the whole queue finishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Play the current track again
+    One,
+    /// Go back to the start of the queue
+    All,
+}
 
-        Self {
-            path,
-            sample_rate,
-            samples,
-            channels,
-            album,
-            album_artist,
-            title,
-            track,
+impl RepeatMode {
+    /// The mode after this one, for toggling through them
+    #[must_use]
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::All,
+            Self::All => Self::One,
+            Self::One => Self::Off,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct TrackList {
-    pub tracks: Vec<Track>,
-    pub total_samples: u64,
-}
+/// Repeat mode that can be shared between threads
+#[derive(Debug, Default)]
+pub struct RepeatState(AtomicU8);
 
-// TODO: this needs work. In order to call something like `audio_params()` the track
-// list must not be empty. This is a problem because it's currently possible to construct
-// an empty track list in a few ways. We should probably make a smart constructor for this
-// that returns a Result and disallow empty track lists. In the meantime, I will rename
-// the constructors to include `unsafe_`.
-impl TrackList {
-    /// Create a new empty track list
-    ///
-    /// # Safety
-    ///
-    /// This method is unsafe because methods on this struct will panic if the track list
-    /// is empty, so the user of this function must ensure correct initialization before
-    /// use, or else the program will panic.
-    pub fn unsafe_new() -> Self {
-        Self {
-            tracks: Vec::new(),
-            total_samples: 0,
+impl RepeatState {
+    pub fn get(&self) -> RepeatMode {
+        match self.0.load(Ordering::SeqCst) {
+            1 => RepeatMode::One,
+            2 => RepeatMode::All,
+            _ => RepeatMode::Off,
         }
     }
 
-    /// Create a new track list from a list of files
-    ///
-    /// # Safety
+    /// Set the repeat mode
     ///
-    /// This function is unsafe because it may lead to a partially constructed
-    /// `TrackList`. If all files get filtered out because they are unsupported,
-    /// calls to some associated functions will panic.
-    pub fn unsafe_from_files(filenames: &[String]) -> Self {
-        files::only_audio(filenames)
-            .into_iter()
-            .map(Track::from_path)
-            .collect_vec()
-            .into()
+    /// Returns the *previous* mode.
+    pub fn set(&self, mode: RepeatMode) -> RepeatMode {
+        let value = match mode {
+            RepeatMode::Off => 0,
+            RepeatMode::One => 1,
+            RepeatMode::All => 2,
+        };
+        let previous = self.get();
+        self.0.store(value, Ordering::SeqCst);
+        previous
     }
+}
+
+/// How the queue is shuffled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    /// Shuffle individual tracks
+    Tracks,
+    /// Shuffle whole albums, keeping the tracks in each album in order
+    Albums,
+}
 
-    pub fn add_track(&mut self, track: Track) {
-        self.total_samples += track.samples;
-        self.tracks.push(track);
+impl ShuffleMode {
+    /// The mode after this one, for toggling through them
+    #[must_use]
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Tracks,
+            Self::Tracks => Self::Albums,
+            Self::Albums => Self::Off,
+        }
     }
 
-    pub fn add_tracks(&mut self, tracks: Vec<Track>) {
-        self.total_samples += tracks.iter().map(|t| t.samples).sum::<u64>();
-        self.tracks.extend(tracks);
+    /// Shuffle the tracks after `from`, returning the new order as indexes
+    /// into `tracks`
+    ///
+    /// When shuffling albums, whatever is left of the album that's playing,
+    /// which is the track just before `from`, stays up next.
+    fn order(self, tracks: &[Track], from: usize) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        let (fixed, rest) = (0..tracks.len()).partition::<Vec<_>, _>(|&i| i < from);
+
+        let shuffled = match self {
+            Self::Off => rest,
+            Self::Tracks => {
+                let mut rest = rest;
+                rest.shuffle(&mut rng);
+                rest
+            }
+            Self::Albums => {
+                let album = |i: usize| (&tracks[i].album_artist, &tracks[i].album);
+                let mut albums: Vec<Vec<usize>> = Vec::new();
+                for i in rest {
+                    match albums.iter_mut().find(|a| album(a[0]) == album(i)) {
+                        Some(a) => a.push(i),
+                        None => albums.push(vec![i]),
+                    }
+                }
+                let current = from
+                    .checked_sub(1)
+                    .filter(|&playing| {
+                        albums
+                            .first()
+                            .is_some_and(|a| album(a[0]) == album(playing))
+                    })
+                    .map(|_| albums.remove(0));
+                albums.shuffle(&mut rng);
+                current.into_iter().chain(albums).flatten().collect()
+            }
+        };
+        fixed.into_iter().chain(shuffled).collect()
     }
+}
+
+/// Shuffle mode plus what the queue looked like before it was shuffled
+#[derive(Debug, Default)]
+struct Shuffle {
+    mode: ShuffleMode,
+    unshuffled: Vec<PathBuf>,
+}
 
-    pub fn find_playing(&self, current_sample: u64) -> usize {
-        let (found, _) = self
-            .tracks
+impl Shuffle {
+    /// The order that puts `tracks` back the way they were before shuffling.
+    /// Tracks added since then go at the end, in the order they're in now.
+    fn unshuffled_order(&self, tracks: &[Track]) -> Vec<usize> {
+        let mut ranks: HashMap<&PathBuf, VecDeque<usize>> = HashMap::new();
+        for (rank, path) in self.unshuffled.iter().enumerate() {
+            ranks.entry(path).or_default().push_back(rank);
+        }
+        let keys = tracks
             .iter()
-            .enumerate()
-            .fold_while((0usize, 0u64), |(_, mut total), (i, track)| {
-                total += track.samples;
-                if total > current_sample {
-                    Done((i, total))
-                } else {
-                    Continue((i, total))
-                }
+            .map(|t| {
+                ranks
+                    .get_mut(&t.path)
+                    .and_then(VecDeque::pop_front)
+                    .unwrap_or(usize::MAX)
             })
-            .into_inner();
-        found
+            .collect_vec();
+        (0..tracks.len()).sorted_by_key(|&i| keys[i]).collect()
     }
+}
 
-    pub fn get_start_point(&self, index: usize) -> u64 {
-        self.tracks
-            .iter()
-            .take(index)
-            .map(|t| t.samples)
-            .sum::<u64>()
+//
+// Replay gain
+//
+
+/// Which ReplayGain adjustment to play tracks with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    /// Level every track with every other
+    Track,
+    /// Level whole albums, keeping the differences between their tracks
+    Album,
+}
+
+impl ReplayGainMode {
+    /// The mode after this one, for toggling through them
+    #[must_use]
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Track,
+            Self::Track => Self::Album,
+            Self::Album => Self::Off,
+        }
     }
 
-    /// Get the track by it's **0-based index** in the track list
+    /// How much to scale a track's samples by, with `preamp_db` on top of
+    /// its gain
     ///
-    /// # Panics
-    ///
-    /// This function will panic if the index is out of bounds
-    pub fn get_track(&self, index: usize) -> &Track {
-        assert!(index < self.tracks.len(), "Index out of bounds");
-        &self.tracks[index]
+    /// Each mode falls back to the other's gain if the track doesn't have
+    /// its own, and tracks without any play as they are. The factor is
+    /// capped so the track's peak doesn't go over full scale.
+    #[must_use]
+    pub fn factor(self, gain: &ReplayGain, preamp_db: f32) -> f32 {
+        let (db, peak) = match self {
+            Self::Off => return 1.0,
+            Self::Track => (
+                gain.track_gain.or(gain.album_gain),
+                gain.track_peak.or(gain.album_peak),
+            ),
+            Self::Album => (
+                gain.album_gain.or(gain.track_gain),
+                gain.album_peak.or(gain.track_peak),
+            ),
+        };
+        let Some(db) = db else {
+            return 1.0;
+        };
+        let factor = 10f32.powf((db + preamp_db) / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(peak.recip()),
+            _ => factor,
+        }
     }
+}
 
-    pub fn get_end_point(&self, index: usize) -> u64 {
-        self.get_start_point(index) + self.get_sample_count(index)
-    }
+/// Replay gain mode that can be shared between threads
+#[derive(Debug, Default)]
+pub struct ReplayGainState(AtomicU8);
 
-    /// Get the number of samples in the track at the given index
-    pub fn get_sample_count(&self, index: usize) -> u64 {
-        self.get_track(index).samples
+impl ReplayGainState {
+    pub fn get(&self) -> ReplayGainMode {
+        match self.0.load(Ordering::SeqCst) {
+            1 => ReplayGainMode::Track,
+            2 => ReplayGainMode::Album,
+            _ => ReplayGainMode::Off,
+        }
     }
 
-    /// Get the audio params for this track list
+    /// Set the replay gain mode
     ///
-    /// # Panics
+    /// Returns the *previous* mode.
+    pub fn set(&self, mode: ReplayGainMode) -> ReplayGainMode {
+        let value = match mode {
+            ReplayGainMode::Off => 0,
+            ReplayGainMode::Track => 1,
+            ReplayGainMode::Album => 2,
+        };
+        let previous = self.get();
+        self.0.store(value, Ordering::SeqCst);
+        previous
+    }
+}
+
+/// Which equalizer preset is in use, if any
+#[derive(Default)]
+pub struct EqualizerState(AtomicUsize);
+
+impl EqualizerState {
+    pub fn get(&self) -> Option<usize> {
+        self.0.load(Ordering::SeqCst).checked_sub(1)
+    }
+
+    /// Set the preset, or bypass the equalizer with `None`
     ///
-    /// This function will panic if the track list is empty, or if there are
-    /// tracks with different sample rates.
-    #[must_use]
-    pub fn audio_params(&self) -> AudioParams {
-        let tracks = &self.tracks;
-        let channels = tracks.iter().map(|t| t.channels).collect::<HashSet<_>>();
-        let sample_rates = tracks.iter().map(|t| t.sample_rate).collect::<HashSet<_>>();
+    /// Returns the *previous* preset.
+    pub fn set(&self, preset: Option<usize>) -> Option<usize> {
+        let value = preset.map_or(0, |i| i + 1);
+        self.0.swap(value, Ordering::SeqCst).checked_sub(1)
+    }
+}
 
-        // TODO: don't panic, warn the user of the problem and give them
-        // a suggestion on how to fix it. include an `--allow-resampling`
-        // flag and figure out how to resample the audio?
+/// How fast audio plays, and whether the pitch changes with it
+pub struct SpeedState {
+    percent: AtomicU16,
+    varispeed: AtomicBool,
+}
 
-        assert!(
-            channels.len() == 1,
-            "Multiple channel counts found in track list: {tracks:?}"
-        );
+impl SpeedState {
+    pub fn get(&self) -> Speed {
+        Speed::from_percent(self.percent.load(Ordering::SeqCst))
+    }
 
-        assert!(
-            sample_rates.len() == 1,
-            "Multiple samples rates found in track list: {tracks:?}"
-        );
+    /// Set the speed
+    ///
+    /// Returns the *previous* speed.
+    pub fn set(&self, speed: Speed) -> Speed {
+        Speed::from_percent(self.percent.swap(speed.percent(), Ordering::SeqCst))
+    }
 
-        AudioParams {
-            channel_count: *channels.iter().next().expect_or_log("No channels found"),
-            sample_rate: *sample_rates
-                .iter()
-                .next()
-                .expect_or_log("No sample rate found"),
+    pub fn mode(&self) -> SpeedMode {
+        if self.varispeed.load(Ordering::SeqCst) {
+            SpeedMode::Varispeed
+        } else {
+            SpeedMode::KeepPitch
         }
     }
 
-    pub fn get_bounds(&self, i: usize) -> (u64, u64) {
-        let start = self.get_start_point(i);
-        let end = start + self.get_sample_count(i);
-        (start, end)
+    /// Set how speed changes the pitch
+    ///
+    /// Returns the *previous* mode.
+    pub fn set_mode(&self, mode: SpeedMode) -> SpeedMode {
+        let varispeed = mode == SpeedMode::Varispeed;
+        if self.varispeed.swap(varispeed, Ordering::SeqCst) {
+            SpeedMode::Varispeed
+        } else {
+            SpeedMode::KeepPitch
+        }
     }
 }
 
-impl Default for TrackList {
+impl Default for SpeedState {
     fn default() -> Self {
-        Self::unsafe_new()
-    }
-}
-
-impl From<Vec<Track>> for TrackList {
-    fn from(v: Vec<Track>) -> Self {
-        let mut tl = Self::unsafe_new();
-        tl.add_tracks(v);
-        tl
+        Self {
+            percent: AtomicU16::new(Speed::NORMAL.percent()),
+            varispeed: AtomicBool::new(false),
+        }
     }
 }
 
@@ -422,8 +672,8 @@ impl CurrentSample {
         Self(AtomicU64::new(0))
     }
 
-    fn get_and_advance(&self, samples: u64) -> u64 {
-        self.0.fetch_add(samples, Ordering::SeqCst)
+    pub(crate) fn set(&self, sample: u64) {
+        self.0.store(sample, Ordering::SeqCst);
     }
 
     pub fn get(&self) -> u64 {
@@ -437,251 +687,1027 @@ impl Default for CurrentSample {
     }
 }
 
+//
+// BufferFill
+//
+
+/// How much decoded audio is waiting to be played, in microseconds
+#[derive(Default)]
+pub struct BufferFill(AtomicU64);
+
+impl BufferFill {
+    pub(crate) fn set(&self, frames: usize, sample_rate: u32) {
+        let micros = frames as u64 * 1_000_000 / u64::from(sample_rate.max(1));
+        self.0.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+}
+
 //
 // Player
 //
 
 pub struct Player {
-    pub current_sample: Arc<CurrentSample>,
-    pub total_samples: Arc<AtomicU64>,
-    pub state: Arc<PlayState>,
-    pub volume: Arc<Volume>,
-    pub track_list: Arc<TrackList>,
-    pub current_track: Arc<AtomicUsize>,
-    pub audio_params: Arc<AudioParams>,
+    current_sample: Arc<CurrentSample>,
+    buffer_fill: Arc<BufferFill>,
+    state: Arc<PlayState>,
+    volume: Arc<Volume>,
+    queue: Arc<Queue>,
+    current_track: Arc<AtomicUsize>,
+    audio_params: Arc<AudioParams>,
+    generation: Arc<AtomicU64>,
+    stopped: Arc<AtomicBool>,
+    repeat: Arc<RepeatState>,
+    shuffle: Arc<Mutex<Shuffle>>,
+    replay_gain: Arc<ReplayGainState>,
+    equalizer: Arc<EqualizerState>,
+    presets: Arc<Vec<Preset>>,
+    speed: Arc<SpeedState>,
+    tap: Arc<AudioTap>,
+    preamp_db: f32,
+    crossfade: Duration,
+    pause_fade: Duration,
+    watermarks: (Duration, Duration),
+    rate_conversion: RateConversion,
+    /// Where the audio goes, which is the audio device unless it's been set
+    sink: Option<Box<dyn AudioSink>>,
+    events: PlayerEvents,
+    skipped: SkippedFiles,
+    commands_tx: Sender<PlayerCommand>,
+    commands_rx: Receiver<PlayerCommand>,
+    reader_tx: Sender<ReaderCommand>,
+    reader_rx: Receiver<ReaderCommand>,
 }
 
 impl Player {
+    /// How far into a track `previous_track` restarts it instead of going back
+    const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
     pub fn new(track_list: TrackList) -> Self {
         Self::with_state(track_list, PlayState::with_state(true))
     }
 
     pub fn with_state(track_list: TrackList, state: PlayState) -> Self {
+        let (commands_tx, commands_rx) = channel::unbounded();
+        let (reader_tx, reader_rx) = channel::unbounded();
         Self {
             current_sample: Arc::new(CurrentSample::default()),
+            buffer_fill: Arc::new(BufferFill::default()),
             volume: Arc::new(Volume::default()),
             state: Arc::new(state),
-            total_samples: Arc::new(AtomicU64::new(track_list.total_samples)),
             current_track: Arc::new(AtomicUsize::new(0)),
             audio_params: Arc::new(track_list.audio_params()),
-            track_list: Arc::new(track_list),
+            queue: Arc::new(Queue::new(track_list)),
+            generation: Arc::new(AtomicU64::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            repeat: Arc::new(RepeatState::default()),
+            shuffle: Arc::new(Mutex::new(Shuffle::default())),
+            replay_gain: Arc::new(ReplayGainState::default()),
+            equalizer: Arc::new(EqualizerState::default()),
+            presets: Arc::new(Vec::new()),
+            speed: Arc::new(SpeedState::default()),
+            tap: Arc::new(AudioTap::default()),
+            preamp_db: 0.0,
+            crossfade: Duration::ZERO,
+            pause_fade: GainRamp::DEFAULT_FADE,
+            watermarks: FileReader::DEFAULT_WATERMARKS,
+            rate_conversion: RateConversion::default(),
+            sink: None,
+            events: PlayerEvents::default(),
+            skipped: SkippedFiles::default(),
+            commands_tx,
+            commands_rx,
+            reader_tx,
+            reader_rx,
         }
     }
 
-    pub fn start(self) -> JoinHandle<()> {
-        let track_list = self.track_list.clone();
-        let params = self.audio_params.clone();
-        let current_sample = self.current_sample.clone();
-        let channel_count = params.channel_count;
-        let current_track = self.current_track.clone();
-        let play_state = self.state.clone();
-        let volume = self.volume.clone();
-        let (samples_tx, samples_rx) = channel::bounded::<Vec<f32>>(256);
-        let paths = track_list
-            .tracks
-            .iter()
-            .map(|t| t.path.clone())
-            .collect_vec();
+    /// Choose how tracks at other sample rates get played. Only takes effect
+    /// when the player is started.
+    #[must_use]
+    pub fn with_rate_conversion(mut self, conversion: RateConversion) -> Self {
+        self.rate_conversion = conversion;
+        self
+    }
 
-        let (done_tx, done_rx) = channel::bounded::<()>(0);
-        thread::spawn(move || {
-            let reader_handle = start_file_reader(paths, samples_tx);
-
-            // buffer to store samples that are ready to be played. we'll resize it to
-            // the have enough capacity to hold what we need without reallocating.
-            let mut buf: Vec<f32> = Vec::new();
-
-            let mut initialized = false;
-            let mut is_done = false;
-            tracing::info!(?params, "Setting up audio device");
-            let _device = run_output_device(params.output_device_parameters(), move |data| {
-                if play_state.is_paused() || is_done {
-                    data.fill(0.0);
-                    return;
-                }
+    /// Boost or cut tracks with ReplayGain tags by `preamp_db` on top of
+    /// their gain. Only takes effect when the player is started.
+    #[must_use]
+    pub fn with_preamp(mut self, preamp_db: f32) -> Self {
+        self.preamp_db = preamp_db;
+        self
+    }
 
-                let size = data.len();
+    /// Crossfade for `duration` between tracks from different albums. Tracks
+    /// from the same album always play back to back, as do all tracks with a
+    /// duration of zero. Only takes effect when the player is started.
+    #[must_use]
+    pub fn with_crossfade(mut self, duration: Duration) -> Self {
+        self.crossfade = duration;
+        self
+    }
 
-                if !initialized {
-                    let tid = promote_current_thread_to_real_time(
-                        params.audio_buffer_frames(),
-                        params.sample_rate,
-                    )
-                    .unwrap_or_log();
-                    tracing::info!(?tid, "Thread promoted");
+    /// Make `presets` available to the equalizer, which starts out bypassed.
+    /// Call this before taking any handles, since they share the presets.
+    #[must_use]
+    pub fn with_equalizer(mut self, presets: Vec<Preset>) -> Self {
+        self.presets = Arc::new(presets);
+        self
+    }
 
-                    buf = Vec::with_capacity(size * 2);
-                    initialized = true;
-                }
+    /// Remember files that were left out of the track list, so they get
+    /// shown alongside the ones the player gives up on
+    #[must_use]
+    pub fn with_skipped_files(self, files: Vec<SkippedFile>) -> Self {
+        for file in files {
+            self.skipped.add(file);
+        }
+        self
+    }
 
-                let volume = volume.get();
-
-                while buf.len() < size {
-                    match samples_rx.try_recv() {
-                        Ok(samples) => {
-                            tracing::trace!(
-                                buf_len = buf.len(),
-                                size,
-                                samples_len = samples.len(),
-                                "Buffering samples"
-                            );
-                            let mut tmp = samples
-                                .iter()
-                                .map(|s| s * (f32::from(volume) / 100.0))
-                                .collect();
-                            buf.append(&mut tmp);
-                        }
-                        Err(TryRecvError::Empty) => {
-                            tracing::warn!("Samples channel empty");
-                            break;
-                        }
-                        Err(TryRecvError::Disconnected) => {
-                            tracing::info!("Samples channel disconnected");
-                            if let Err(error) = done_tx.send(()) {
-                                tracing::error!(?error, "Error sending done signal");
-                            }
-                            is_done = true;
-                            break;
-                        }
-                    }
-                }
+    /// Use `curve` to turn volume steps into gain. Call this before taking
+    /// any handles, since they share the volume.
+    #[must_use]
+    pub fn with_volume_curve(mut self, curve: VolumeCurve) -> Self {
+        let volume = Volume::unsafe_from(self.volume.get()).with_curve(curve);
+        self.volume = Arc::new(volume);
+        self
+    }
 
-                // the last buffer is unlikely to be perfectly full. if we're on the
-                // last buffer we go through the extra work of making sure the slice
-                // is zero-padded to the right size. this involves extra allocations
-                // so it's worth the tax of checking this boolean every callback.
-                let max = size.min(buf.len());
-                let slice = &buf[..max];
-                if max == size {
-                    data.copy_from_slice(slice);
-                } else {
-                    if !is_done {
-                        tracing::warn!(
-                            max,
-                            size,
-                            buf_len = buf.len(),
-                            "Buffer not full; padding with zeroes",
-                        );
-                    }
-                    let mut tmp = Vec::with_capacity(size);
-                    tmp.extend_from_slice(slice);
-                    tmp.resize(size, 0.0);
-                    data.copy_from_slice(&tmp);
-                }
+    /// Fade out over `duration` when pausing and back in when resuming.
+    /// Only takes effect when the player is started.
+    #[must_use]
+    pub fn with_pause_fade(mut self, duration: Duration) -> Self {
+        self.pause_fade = duration;
+        self
+    }
 
-                buf.drain(..max);
+    /// Stop decoding ahead once `high` worth of audio is buffered, and start
+    /// again once it's down to `low`. Only takes effect when the player is
+    /// started.
+    #[must_use]
+    pub fn with_watermarks(mut self, low: Duration, high: Duration) -> Self {
+        self.watermarks = (low.min(high), high);
+        self
+    }
 
-                let sample_count =
-                    current_sample.get_and_advance(max as u64 / u64::from(channel_count));
+    /// Play into `sink` instead of the audio device
+    #[must_use]
+    pub fn with_sink(mut self, sink: impl AudioSink + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
 
-                let track = track_list.find_playing(sample_count);
-                current_track.store(track, Ordering::SeqCst);
-            })
-            .unwrap_or_log();
+    /// Open the output device with as many channels as the track with the
+    /// most, so multichannel audio plays as-is instead of being downmixed.
+    /// Only use this if the device supports that many channels.
+    #[must_use]
+    pub fn with_channel_passthrough(mut self) -> Self {
+        let channel_count = self.queue.snapshot().max_channels();
+        self.audio_params = Arc::new(AudioParams {
+            channel_count,
+            ..*self.audio_params
+        });
+        self
+    }
 
-            reader_handle
-                .join()
-                .expect_or_log("Error joining reader thread");
-            done_rx.recv().unwrap_or_log();
-            tracing::info!("Player finished");
-        })
+    /// Get a handle for controlling and observing this player
+    ///
+    /// Handles can be cloned freely and keep working after the player is started.
+    pub fn handle(&self) -> PlayerHandle {
+        PlayerHandle {
+            commands: self.commands_tx.clone(),
+            current_sample: self.current_sample.clone(),
+            buffer_fill: self.buffer_fill.clone(),
+            current_track: self.current_track.clone(),
+            state: self.state.clone(),
+            volume: self.volume.clone(),
+            queue: self.queue.clone(),
+            audio_params: *self.audio_params,
+            repeat: self.repeat.clone(),
+            shuffle: self.shuffle.clone(),
+            replay_gain: self.replay_gain.clone(),
+            equalizer: self.equalizer.clone(),
+            presets: self.presets.clone(),
+            speed: self.speed.clone(),
+            tap: self.tap.clone(),
+            events: self.events.clone(),
+            skipped: self.skipped.clone(),
+        }
     }
-}
 
-fn start_file_reader(paths: Vec<PathBuf>, samples_tx: Sender<Vec<f32>>) -> JoinHandle<()> {
-    let mut total_samples = 0;
-    thread::spawn(move || {
-        for path in paths {
-            if path.extension().unwrap_or_default() != "flac" {
-                tracing::warn!(?path, "Skipping non-flac file");
-                continue;
+    /// Apply a single command to the player
+    ///
+    /// This is what the command loop started by `start` calls for every
+    /// command it receives, and it doesn't need an audio device.
+    pub fn apply(&self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Play => {
+                let was_playing = self.state.set(true);
+                self.publish_play_state(was_playing);
+            }
+            PlayerCommand::Pause => {
+                let was_playing = self.state.set(false);
+                self.publish_play_state(was_playing);
+            }
+            PlayerCommand::TogglePause => {
+                let was_playing = self.state.toggle();
+                self.publish_play_state(was_playing);
+            }
+            PlayerCommand::SeekTo(time) => self.seek_to(time),
+            PlayerCommand::SeekForward(amount) => self.seek_forward(amount),
+            PlayerCommand::SeekBackward(amount) => self.seek_backward(amount),
+            PlayerCommand::NextTrack => self.next_track(),
+            PlayerCommand::PreviousTrack => self.previous_track(),
+            PlayerCommand::SkipTo(index) => self.skip_to(index),
+            PlayerCommand::SetVolume(value) => {
+                let previous = self.volume.get();
+                match self.volume.set(value) {
+                    Ok(()) => self.publish_volume(previous),
+                    Err(error) => tracing::error!(?error, "Error setting volume"),
+                }
+            }
+            PlayerCommand::VolumeUp(amount) => {
+                let previous = self.volume.up(amount);
+                self.publish_volume(previous);
+            }
+            PlayerCommand::VolumeDown(amount) => {
+                let previous = self.volume.down(amount);
+                self.publish_volume(previous);
+            }
+            PlayerCommand::Enqueue(paths) => self.enqueue(&paths),
+            PlayerCommand::Remove(index) => self.edit_queue(QueueEdit::Remove(index)),
+            PlayerCommand::Move { from, to } => self.edit_queue(QueueEdit::Move { from, to }),
+            PlayerCommand::ClearQueue => {
+                let keep = self.current_track.load(Ordering::SeqCst);
+                self.edit_queue(QueueEdit::Clear { keep });
             }
+            PlayerCommand::SetRepeat(mode) => self.set_repeat(mode),
+            PlayerCommand::SetShuffle(mode) => self.set_shuffle(mode),
+            PlayerCommand::SetReplayGain(mode) => self.set_replay_gain(mode),
+            PlayerCommand::SetEqualizer(preset) => self.set_equalizer(preset),
+            PlayerCommand::SetSpeed(speed) => self.set_speed(speed),
+            PlayerCommand::SetSpeedMode(mode) => self.set_speed_mode(mode),
+            PlayerCommand::Stop => {
+                self.stopped.store(true, Ordering::SeqCst);
+                if let Err(error) = self.reader_tx.send(ReaderCommand::Stop) {
+                    tracing::error!(?error, "Error sending stop command");
+                }
+            }
+        }
+    }
+
+    /// Seek to a sample offset from the start of the track list
+    ///
+    /// Offsets past the end of the track list are clamped to the end. Can be
+    /// called before or after the player is started.
+    pub fn seek(&self, sample: u64) {
+        let track_list = self.queue.snapshot();
+        let sample = sample.min(track_list.total_samples);
+
+        // bump the generation first so the output callback starts discarding
+        // anything that was decoded for the old position.
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.current_sample.set(sample);
+        self.current_track
+            .store(track_list.find_playing(sample), Ordering::SeqCst);
+
+        tracing::info!(sample, generation, "Seeking");
+        if let Err(error) = self
+            .reader_tx
+            .send(ReaderCommand::Seek { generation, sample })
+        {
+            tracing::error!(?error, "Error sending seek command");
+        }
+    }
+
+    /// Seek to a point in time from the start of the track list
+    pub fn seek_to(&self, time: Duration) {
+        self.seek(self.audio_params.samples_in(time));
+    }
 
-            tracing::info!(?path, "Reading audio file");
-
-            let probed = {
-                let file = Box::new(File::open(&path).unwrap_or_log());
-                symphonia::default::get_probe()
-                    .format(
-                        &Hint::new(),
-                        MediaSourceStream::new(file, MediaSourceStreamOptions::default()),
-                        &FormatOptions::default(),
-                        &MetadataOptions::default(),
-                    )
-                    .unwrap_or_log()
+    /// Seek forward from the current position
+    pub fn seek_forward(&self, amount: Duration) {
+        let amount = self.audio_params.samples_in(amount);
+        self.seek(self.current_sample.get().saturating_add(amount));
+    }
+
+    /// Seek backward from the current position, stopping at the start
+    pub fn seek_backward(&self, amount: Duration) {
+        let amount = self.audio_params.samples_in(amount);
+        self.seek(self.current_sample.get().saturating_sub(amount));
+    }
+
+    /// Jump to the start of the track at the given **0-based index**
+    ///
+    /// Indexes past the end of the track list are ignored.
+    pub fn skip_to(&self, index: usize) {
+        let track_list = self.queue.snapshot();
+        if index >= track_list.tracks.len() {
+            tracing::warn!(index, "Track index out of bounds; not skipping");
+            return;
+        }
+        tracing::info!(index, "Skipping to track");
+        self.seek(track_list.get_start_point(index));
+    }
+
+    /// Skip to the start of the next track, if there is one
+    ///
+    /// When repeating the whole queue, the last track is followed by the first.
+    pub fn next_track(&self) {
+        let current = self.current_track.load(Ordering::SeqCst);
+        if current + 1 < self.queue.snapshot().tracks.len() {
+            self.skip_to(current + 1);
+        } else if self.repeat.get() == RepeatMode::All {
+            self.skip_to(0);
+        } else {
+            tracing::info!(current, "Already on the last track");
+        }
+    }
+
+    /// Go back to the start of the previous track
+    ///
+    /// If we're more than a few seconds into the current track, restart it
+    /// instead, the same way most players treat the "previous" button.
+    pub fn previous_track(&self) {
+        let current = self.current_track.load(Ordering::SeqCst);
+        let into_track = self
+            .current_sample
+            .get()
+            .saturating_sub(self.queue.snapshot().get_start_point(current));
+        if current == 0 || into_track > self.audio_params.samples_in(Self::RESTART_THRESHOLD) {
+            self.skip_to(current);
+        } else {
+            self.skip_to(current - 1);
+        }
+    }
+
+    /// Add files to the end of the queue
+    ///
+    /// Directories are walked for audio files. Tracks get resampled and
+    /// remixed to match the output device as they're played.
+    pub fn enqueue<P: AsRef<Path>>(&self, paths: &[P]) {
+        let (tracks, skipped) = Track::from_paths(paths);
+        for file in skipped {
+            self.skipped.publish(file, &self.events);
+        }
+
+        if tracks.is_empty() {
+            tracing::warn!("Nothing to enqueue");
+            return;
+        }
+        self.edit_queue(QueueEdit::Append(tracks));
+    }
+
+    pub fn set_repeat(&self, mode: RepeatMode) {
+        if self.repeat.set(mode) == mode {
+            return;
+        }
+        tracing::info!(?mode, "Repeat mode changed");
+        self.events.publish(PlayerEvent::RepeatChanged(mode));
+
+        let playing = self.current_track.load(Ordering::SeqCst);
+        if mode == RepeatMode::One && self.queue.decoding() != playing {
+            // the reader already moved on to the next track, so bring it back
+            self.seek(self.current_sample.get());
+        } else if let Err(error) = self.reader_tx.send(ReaderCommand::Wake) {
+            // the reader might be waiting at the end of the queue
+            tracing::error!(?error, "Error waking reader");
+        }
+    }
+
+    /// Change which ReplayGain adjustment tracks are played with
+    ///
+    /// Takes effect with the next samples to be buffered.
+    pub fn set_replay_gain(&self, mode: ReplayGainMode) {
+        if self.replay_gain.set(mode) != mode {
+            tracing::info!(?mode, "Replay gain mode changed");
+            self.events.publish(PlayerEvent::ReplayGainChanged(mode));
+        }
+    }
+
+    /// Switch to the equalizer preset at `preset`, or bypass the equalizer
+    /// with `None`
+    ///
+    /// Takes effect with the next buffer sent to the output.
+    pub fn set_equalizer(&self, preset: Option<usize>) {
+        if preset.is_some_and(|i| i >= self.presets.len()) {
+            tracing::warn!(?preset, "No such equalizer preset");
+            return;
+        }
+        if self.equalizer.set(preset) != preset {
+            let name = preset.map(|i| &self.presets[i].name);
+            tracing::info!(?name, "Equalizer preset changed");
+            self.events.publish(PlayerEvent::EqualizerChanged(preset));
+        }
+    }
+
+    /// Play faster or slower. Positions are still counted in samples of the
+    /// tracks themselves, so seeking and timecodes aren't affected.
+    ///
+    /// Takes effect with the next buffer sent to the output.
+    pub fn set_speed(&self, speed: Speed) {
+        if self.speed.set(speed) != speed {
+            tracing::info!(%speed, "Speed changed");
+            self.events.publish(PlayerEvent::SpeedChanged(speed));
+        }
+    }
+
+    /// Choose whether playing faster or slower keeps the pitch
+    ///
+    /// Takes effect with the next buffer sent to the output.
+    pub fn set_speed_mode(&self, mode: SpeedMode) {
+        if self.speed.set_mode(mode) != mode {
+            tracing::info!(?mode, "Speed mode changed");
+            self.events.publish(PlayerEvent::SpeedModeChanged(mode));
+        }
+    }
+
+    /// Shuffle the rest of the queue, or put it back in its original order
+    ///
+    /// The playing track keeps playing. If nothing has played yet, the whole
+    /// queue gets shuffled.
+    pub fn set_shuffle(&self, mode: ShuffleMode) {
+        let mut shuffle = self.shuffle.lock().unwrap_or_log();
+        if shuffle.mode == mode {
+            return;
+        }
+
+        let tracks = self.queue.snapshot();
+        let tracks = &tracks.tracks;
+        let unshuffled = if shuffle.mode == ShuffleMode::Off {
+            (0..tracks.len()).collect_vec()
+        } else {
+            shuffle.unshuffled_order(tracks)
+        };
+        let order = if mode == ShuffleMode::Off {
+            unshuffled
+        } else {
+            let restored = unshuffled.iter().map(|&i| tracks[i].clone()).collect_vec();
+            let playing = self.current_track.load(Ordering::SeqCst);
+            let from = if self.current_sample.get() == 0 {
+                0
+            } else {
+                unshuffled
+                    .iter()
+                    .position(|&i| i == playing)
+                    .unwrap_or_default()
+                    + 1
             };
+            shuffle.unshuffled = restored.iter().map(|t| t.path.clone()).collect();
+            mode.order(&restored, from)
+                .into_iter()
+                .map(|i| unshuffled[i])
+                .collect()
+        };
+
+        shuffle.mode = mode;
+        tracing::info!(?mode, "Shuffle mode changed");
+        self.events.publish(PlayerEvent::ShuffleChanged(mode));
+        self.edit_queue(QueueEdit::Reorder(order));
+    }
 
-            let mut format = probed.format;
-            let track = format.default_track().unwrap_or_log();
+    fn edit_queue(&self, edit: QueueEdit) {
+        let playing = self.current_track.load(Ordering::SeqCst);
+        let position = self.current_sample.get();
 
-            let mut decoder = symphonia::default::get_codecs()
-                .make(&track.codec_params, &DecoderOptions::default())
-                .unwrap_or_log();
+        let edited = match self.queue.edit(&edit) {
+            Ok(edited) => edited,
+            Err(error) => {
+                tracing::warn!(%error, ?edit, "Couldn't edit queue");
+                return;
+            }
+        };
+        tracing::info!(?edit, needs_seek = edited.needs_seek, "Edited queue");
+        self.events.publish(PlayerEvent::QueueChanged);
+
+        if edited.needs_seek {
+            // pick up from the same spot in the same track, wherever it ended up.
+            // if it was removed, carry on with whatever took its place.
+            let new = &edited.new;
+            let sample = match edit.map_index(playing) {
+                Some(index) => {
+                    let offset = position.saturating_sub(edited.old.get_start_point(playing));
+                    new.get_start_point(index) + offset
+                }
+                None if playing < new.tracks.len() => new.get_start_point(playing),
+                None => new.total_samples,
+            };
+            self.seek(sample);
+        } else if let Err(error) = self.reader_tx.send(ReaderCommand::Wake) {
+            // the reader might be waiting at the end of the old track list
+            tracing::error!(?error, "Error waking reader");
+        }
+    }
 
-            let track_id = track.id;
+    fn publish_play_state(&self, was_playing: bool) {
+        match (was_playing, self.state.is_paused()) {
+            (true, true) => self.events.publish(PlayerEvent::Paused),
+            (false, false) => self.events.publish(PlayerEvent::Resumed),
+            _ => {}
+        }
+    }
 
-            let mut sample_buf = None;
-            loop {
-                let packet = match format.next_packet() {
-                    Ok(packet) => packet,
-                    Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                    Err(err) => {
-                        tracing::error!(?err, ?path, "Error reading packet");
-                        break;
-                    }
-                };
+    fn publish_volume(&self, previous: u8) {
+        let volume = self.volume.get();
+        if volume != previous {
+            self.events.publish(PlayerEvent::VolumeChanged { volume });
+        }
+    }
 
-                if packet.track_id() != track_id {
-                    continue;
+    /// Start playback and process commands from `PlayerHandle`s
+    ///
+    /// The returned thread finishes once a `Stop` command has been handled and
+    /// the output device has shut down.
+    pub fn start(mut self) -> JoinHandle<()> {
+        let sink = self.sink.take().unwrap_or_else(|| Box::new(DeviceSink));
+        let output = self.start_output(sink);
+        thread::spawn(move || {
+            while let Ok(command) = self.commands_rx.recv() {
+                tracing::info!(?command, "Player command");
+                let is_stop = command == PlayerCommand::Stop;
+                self.apply(command);
+                if is_stop {
+                    break;
                 }
+            }
+            output.join().expect_or_log("Error joining output thread");
+        })
+    }
 
-                match decoder.decode(&packet) {
-                    Ok(audio_buf) => {
-                        if sample_buf.is_none() {
-                            let spec = *audio_buf.spec();
-                            let duration = audio_buf.capacity();
-                            tracing::info!(?spec, "Decoded audio buffer");
-                            sample_buf = Some((spec, SampleBuffer::new(duration as u64, spec)));
-                        }
+    fn start_output(&self, mut sink: Box<dyn AudioSink>) -> JoinHandle<()> {
+        let queue = self.queue.clone();
+        let params = *self.audio_params;
+        let current_sample = self.current_sample.clone();
+        let generation = self.generation.clone();
+        let (samples_tx, samples_rx) = chunk_ring::<Decoded>(
+            params.ring_buffer_samples(self.watermarks.1),
+            FileReader::MAX_CHUNKS,
+        );
+
+        // the audio callback can't take the subscriber lock or free memory, so
+        // it hands events and old snapshots to a dispatcher thread instead.
+        let (callback_tx, callback_rx) = channel::bounded::<CallbackMessage>(64);
+        let events = self.events.clone();
+        thread::spawn(move || {
+            for message in callback_rx {
+                match message {
+                    CallbackMessage::Event(event) => events.publish(event),
+                    CallbackMessage::Retire(track_list) => drop(track_list),
+                }
+            }
+        });
+
+        let reader = FileReader {
+            queue: queue.clone(),
+            repeat: self.repeat.clone(),
+            params,
+            conversion: self.rate_conversion,
+            crossfade: self.crossfade,
+            watermarks: self.watermarks,
+            replay_gain: self.replay_gain.clone(),
+            preamp_db: self.preamp_db,
+            tail: None,
+            fading: None,
+            position: current_sample.get(),
+            generation: generation.load(Ordering::SeqCst),
+            samples: samples_tx,
+            commands_rx: self.reader_rx.clone(),
+            events: self.events.clone(),
+            skipped: self.skipped.clone(),
+            stopped: false,
+            interrupted: false,
+        };
+
+        // when resampling, the device runs at the track list's rate. otherwise
+        // it starts at the rate of whatever's about to play.
+        let track_list = queue.snapshot();
+        let device_rate = match self.rate_conversion {
+            RateConversion::Resample(_) => params.sample_rate,
+            RateConversion::Native => {
+                let index = track_list.find_playing(current_sample.get());
+                track_list.get_track(index).sample_rate
+            }
+        };
+
+        // room for a reopen request, the done signal and a failure, so
+        // neither the audio callback nor the sink ever blocks sending them
+        let (signals_tx, signals_rx) = channel::bounded::<OutputSignal>(3);
+        let failures = signals_tx.clone();
+        let output = Arc::new(Mutex::new(Output {
+            params,
+            device_rate,
+            queue_version: queue.version(),
+            track_list,
+            queue,
+            current_sample: current_sample.clone(),
+            buffer_fill: self.buffer_fill.clone(),
+            current_track: self.current_track.clone(),
+            play_state: self.state.clone(),
+            volume: self.volume.clone(),
+            replay_gain: self.replay_gain.clone(),
+            preamp_db: self.preamp_db,
+            equalizer: Equalizer::new(
+                self.presets.to_vec(),
+                usize::from(params.channel_count),
+                device_rate,
+            ),
+            equalizer_preset: self.equalizer.clone(),
+            speed: self.speed.clone(),
+            speed_changer: SpeedChanger::new(usize::from(params.channel_count), device_rate),
+            tap: self.tap.clone(),
+            ramp: GainRamp::new(self.pause_fade, self.volume.gain()),
+            last_generation: generation.load(Ordering::SeqCst),
+            generation,
+            stopped: self.stopped.clone(),
+            realtime: sink.is_realtime(),
+            samples: samples_rx,
+            signals: signals_tx,
+            messages: callback_tx,
+            initialized: false,
+            is_done: false,
+            reopening: false,
+            at_end: false,
+            finished: false,
+            playing_track: None,
+            last_position: current_sample.get(),
+            seeked: false,
+            pushed: None,
+        }));
+
+        let events = self.events.clone();
+        thread::spawn(move || {
+            let reader_handle = reader.spawn();
 
-                        if let Some((_spec, buf)) = &mut sample_buf {
-                            buf.copy_interleaved_ref(audio_buf);
-                            let mut samples = buf.samples().to_owned();
-                            let duration = samples.len();
-                            total_samples += duration as u64;
-
-                            // try to send the sample buffer. if the channel is full, wait for
-                            // a bit. this lets us batch reads, which seems to be more efficient.
-                            loop {
-                                match samples_tx.try_send(samples) {
-                                    Err(err) if err.is_full() => {
-                                        samples = err.into_inner();
-                                        thread::sleep(Duration::from_secs(4));
-                                    }
-                                    Ok(_) => {
-                                        tracing::trace!("Sent samples");
-                                        break;
-                                    }
-                                    Err(err) => {
-                                        tracing::error!(?err, "Error sending samples");
-                                        break;
-                                    }
-                                }
+            let mut device_rate = device_rate;
+            loop {
+                let params = AudioParams {
+                    sample_rate: device_rate,
+                    ..params
+                };
+                tracing::info!(?params, "Setting up audio device");
+                let render: Render = {
+                    let output = output.clone();
+                    Box::new(move |data| {
+                        // the lock is only held elsewhere while the device is
+                        // being reopened, so there's nothing to wait for
+                        match output.try_lock() {
+                            Ok(mut output) => {
+                                let rendered = output.render(data);
+                                output.tap.write(
+                                    &data[..rendered],
+                                    usize::from(output.params.channel_count),
+                                    output.device_rate,
+                                );
+                                rendered
+                            }
+                            Err(_) => {
+                                data.fill(0.0);
+                                0
                             }
                         }
+                    })
+                };
+                let failed: Failed = {
+                    let failures = failures.clone();
+                    Box::new(move |error| {
+                        let _ = failures.try_send(OutputSignal::Failed(error));
+                    })
+                };
+                let device = match sink.open(params, render, failed) {
+                    Ok(device) => device,
+                    Err(error) => {
+                        tracing::error!(%error, "Can't open audio output; stopping");
+                        events.publish(PlayerEvent::OutputFailed {
+                            error: error.to_string(),
+                        });
+                        break;
                     }
-                    Err(Error::DecodeError(err)) => {
-                        tracing::error!(err, "Audio loop: decode error");
+                };
+
+                match signals_rx.recv() {
+                    Ok(OutputSignal::Reopen(rate)) => {
+                        drop(device);
+                        tracing::info!(rate, "Reopening audio device");
+                        let mut output = output.lock().unwrap_or_log();
+                        output.device_rate = rate;
+                        output.equalizer.set_sample_rate(rate);
+                        output.speed_changer.set_sample_rate(rate);
+                        output.initialized = false;
+                        output.reopening = false;
+                        device_rate = rate;
                     }
-                    Err(err) => {
-                        tracing::error!(%err, "Audio loop: error");
+                    Ok(OutputSignal::Failed(error)) => {
+                        tracing::error!(%error, "Audio output failed; stopping");
+                        events.publish(PlayerEvent::OutputFailed {
+                            error: error.to_string(),
+                        });
                         break;
                     }
+                    Ok(OutputSignal::Done) | Err(_) => break,
                 }
             }
-            tracing::info!(total_samples, ?path, "Finished reading file");
+
+            // lets the reader know nobody's listening, if it's waiting for room
+            drop(output);
+            reader_handle
+                .join()
+                .expect_or_log("Error joining reader thread");
+            if let Err(error) = sink.finish() {
+                tracing::error!(%error, "Error finishing audio output");
+            }
+            tracing::info!("Player finished");
+        })
+    }
+}
+
+//
+// PlayerCommand
+//
+
+/// Everything a `PlayerHandle` can ask a running `Player` to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayerCommand {
+    Play,
+    Pause,
+    TogglePause,
+    /// Seek to a point in time from the start of the track list
+    SeekTo(Duration),
+    SeekForward(Duration),
+    SeekBackward(Duration),
+    NextTrack,
+    PreviousTrack,
+    /// Jump to the track at the given **0-based index**
+    SkipTo(usize),
+    SetVolume(u8),
+    VolumeUp(u8),
+    VolumeDown(u8),
+    /// Add files to the end of the queue, walking any directories
+    Enqueue(Vec<PathBuf>),
+    /// Remove the track at the given **0-based index**. If it's playing,
+    /// playback carries on with the next track.
+    Remove(usize),
+    /// Move a track to a new position in the queue
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// Remove every track except the one that's playing
+    ClearQueue,
+    SetRepeat(RepeatMode),
+    /// Shuffle the rest of the queue, or unshuffle it with `ShuffleMode::Off`
+    SetShuffle(ShuffleMode),
+    SetReplayGain(ReplayGainMode),
+    /// Switch equalizer presets, or bypass the equalizer with `None`
+    SetEqualizer(Option<usize>),
+    SetSpeed(Speed),
+    SetSpeedMode(SpeedMode),
+    /// Stop playback and shut down the output device
+    Stop,
+}
+
+//
+// PlayerHandle
+//
+
+/// Cheap, cloneable handle for controlling a `Player` and reading its state
+#[derive(Clone)]
+pub struct PlayerHandle {
+    commands: Sender<PlayerCommand>,
+    current_sample: Arc<CurrentSample>,
+    buffer_fill: Arc<BufferFill>,
+    current_track: Arc<AtomicUsize>,
+    state: Arc<PlayState>,
+    volume: Arc<Volume>,
+    queue: Arc<Queue>,
+    audio_params: AudioParams,
+    repeat: Arc<RepeatState>,
+    shuffle: Arc<Mutex<Shuffle>>,
+    replay_gain: Arc<ReplayGainState>,
+    equalizer: Arc<EqualizerState>,
+    presets: Arc<Vec<Preset>>,
+    speed: Arc<SpeedState>,
+    tap: Arc<AudioTap>,
+    events: PlayerEvents,
+    skipped: SkippedFiles,
+}
+
+impl PlayerHandle {
+    /// Send a command to the player
+    ///
+    /// Commands are queued until the player is started.
+    pub fn send(&self, command: PlayerCommand) {
+        tracing::debug!(?command, "Sending player command");
+        if let Err(error) = self.commands.send(command) {
+            tracing::error!(?error, "Error sending player command");
         }
-    })
+    }
+
+    pub fn current_sample(&self) -> u64 {
+        self.current_sample.get()
+    }
+
+    /// How much decoded audio is waiting to be played, as of the last time
+    /// the output device asked for more
+    pub fn buffered(&self) -> Duration {
+        self.buffer_fill.get()
+    }
+
+    /// The **0-based index** of the track that's playing
+    pub fn current_track(&self) -> usize {
+        self.current_track.load(Ordering::SeqCst)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.is_paused()
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume.get()
+    }
+
+    /// The volume in dB, or `None` if it's muted
+    pub fn volume_db(&self) -> Option<f32> {
+        self.volume.db()
+    }
+
+    /// A snapshot of the queue as it is right now
+    ///
+    /// Edits made after this call don't show up in the returned list.
+    pub fn track_list(&self) -> Arc<TrackList> {
+        self.queue.snapshot()
+    }
+
+    pub fn audio_params(&self) -> AudioParams {
+        self.audio_params
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat.get()
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle.lock().unwrap_or_log().mode
+    }
+
+    pub fn replay_gain(&self) -> ReplayGainMode {
+        self.replay_gain.get()
+    }
+
+    /// The equalizer preset in use, or `None` if it's bypassed
+    pub fn equalizer(&self) -> Option<usize> {
+        self.equalizer.get()
+    }
+
+    pub fn equalizer_presets(&self) -> &[Preset] {
+        &self.presets
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed.get()
+    }
+
+    pub fn speed_mode(&self) -> SpeedMode {
+        self.speed.mode()
+    }
+
+    /// The audio the output has just been handed, after the equalizer and
+    /// volume, for showing what's being heard
+    pub fn tap(&self) -> &AudioTap {
+        &self.tap
+    }
+
+    /// Subscribe to events from the player
+    ///
+    /// Only events published after subscribing are received.
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Files that were left out of the queue or given up on while playing
+    pub fn skipped_files(&self) -> Vec<SkippedFile> {
+        self.skipped.snapshot()
+    }
+}
+
+//
+// PlayerEvent
+//
+
+/// Things that happen during playback, published to every subscriber
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayerEvent {
+    /// The track at `index` started playing, either in order or after a seek
+    TrackStarted {
+        index: usize,
+    },
+    /// The track at `index` played through to its end
+    TrackFinished {
+        index: usize,
+    },
+    Paused,
+    Resumed,
+    VolumeChanged {
+        volume: u8,
+    },
+    /// The output callback ran out of samples and padded with silence
+    BufferUnderrun {
+        missing_samples: usize,
+    },
+    DecodeError {
+        path: PathBuf,
+        error: String,
+    },
+    /// A file couldn't be played, so it was left out of the queue or
+    /// skipped over
+    FileSkipped {
+        path: PathBuf,
+        reason: String,
+    },
+    /// Reached the end of the queue. Enqueueing more tracks resumes playback.
+    PlaylistFinished,
+    /// Tracks were added, removed or moved
+    QueueChanged,
+    RepeatChanged(RepeatMode),
+    ShuffleChanged(ShuffleMode),
+    ReplayGainChanged(ReplayGainMode),
+    EqualizerChanged(Option<usize>),
+    SpeedChanged(Speed),
+    SpeedModeChanged(SpeedMode),
+    /// The output couldn't be opened or written to, so playback has stopped
+    OutputFailed {
+        error: String,
+    },
+}
+
+/// Fans player events out to any number of subscribers
+#[derive(Clone, Default)]
+pub struct PlayerEvents {
+    subscribers: Arc<Mutex<Vec<Sender<PlayerEvent>>>>,
+}
+
+impl PlayerEvents {
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = channel::unbounded();
+        self.subscribers.lock().unwrap_or_log().push(tx);
+        rx
+    }
+
+    /// Send an event to every subscriber, forgetting the ones that hung up
+    pub fn publish(&self, event: PlayerEvent) {
+        tracing::debug!(?event, "Player event");
+        self.subscribers
+            .lock()
+            .unwrap_or_log()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+//
+// SkippedFiles
+//
+
+/// Every file the player has had to leave out or give up on, and why
+#[derive(Clone, Default)]
+pub struct SkippedFiles(Arc<Mutex<BTreeMap<PathBuf, String>>>);
+
+impl SkippedFiles {
+    /// Returns whether the file is news
+    fn add(&self, file: SkippedFile) -> bool {
+        let mut files = self.0.lock().unwrap_or_log();
+        files.insert(file.path, file.reason).is_none()
+    }
+
+    /// Add a file, letting subscribers know if it's news
+    pub(crate) fn publish(&self, file: SkippedFile, events: &PlayerEvents) {
+        if self.add(file.clone()) {
+            events.publish(PlayerEvent::FileSkipped {
+                path: file.path,
+                reason: file.reason,
+            });
+        }
+    }
+
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.0.lock().unwrap_or_log().contains_key(path)
+    }
+
+    /// Sorted by path
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<SkippedFile> {
+        self.0
+            .lock()
+            .unwrap_or_log()
+            .iter()
+            .map(|(path, reason)| SkippedFile {
+                path: path.clone(),
+                reason: reason.clone(),
+            })
+            .collect()
+    }
 }
 
 //
@@ -715,6 +1741,13 @@ impl PlayState {
             .unwrap_or_log()
     }
 
+    /// Set the play state, `true` being playing.
+    ///
+    /// Returns the *previous* state.
+    pub fn set(&self, playing: bool) -> bool {
+        self.0.swap(playing, Ordering::SeqCst)
+    }
+
     /// Whether the player is currently playing.
     pub fn is_paused(&self) -> bool {
         !self.0.load(Ordering::SeqCst)
@@ -734,8 +1767,17 @@ impl Default for PlayState {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::track_list::tests::test_track;
     use proptest::prelude::*;
 
+    fn test_player() -> Player {
+        Player::new(TrackList::from(vec![
+            test_track("A", 1, 44_100 * 60),
+            test_track("A", 2, 44_100 * 90),
+            test_track("B", 1, 44_100 * 30),
+        ]))
+    }
+
     proptest! {
         #[test]
         fn test_volume_up_stays_below_100(amount: u8) {
@@ -750,5 +1792,278 @@ mod tests {
             let result = v.down(amount);
             prop_assert!(result <= 100);
         }
+
+        #[test]
+        fn test_volume_curve_is_monotonic(step in 0u8..100, min_db in -100.0f32..-1.0) {
+            let curve = VolumeCurve::new(min_db, 0.0).unwrap();
+            prop_assert!(curve.gain(step) < curve.gain(step + 1));
+            prop_assert!(curve.gain(step + 1) <= 1.0);
+        }
+
+        #[test]
+        fn test_timecode_parses_hours_minutes_seconds(h in 0u64..100, m in 0u64..60, s in 0u64..60) {
+            let timecode: Timecode = format!("{h}:{m:02}:{s:02}").parse().unwrap();
+            prop_assert_eq!(timecode.as_duration(), Duration::from_secs(h * 3600 + m * 60 + s));
+        }
+    }
+
+    #[test]
+    fn test_apply_commands_without_a_device() {
+        let player = test_player();
+        let handle = player.handle();
+
+        player.apply(PlayerCommand::Pause);
+        assert!(handle.is_paused());
+        player.apply(PlayerCommand::TogglePause);
+        assert!(!handle.is_paused());
+
+        player.apply(PlayerCommand::SetVolume(40));
+        player.apply(PlayerCommand::VolumeDown(5));
+        assert_eq!(handle.volume(), 35);
+
+        player.apply(PlayerCommand::SkipTo(1));
+        assert_eq!(handle.current_track(), 1);
+        assert_eq!(handle.current_sample(), 44_100 * 60);
+
+        player.apply(PlayerCommand::SeekForward(Duration::from_secs(100)));
+        assert_eq!(handle.current_track(), 2);
+        assert_eq!(handle.current_sample(), 44_100 * 160);
+
+        // far enough in that "previous" restarts the track first
+        player.apply(PlayerCommand::PreviousTrack);
+        assert_eq!(handle.current_track(), 2);
+        assert_eq!(handle.current_sample(), 44_100 * 150);
+        player.apply(PlayerCommand::PreviousTrack);
+        assert_eq!(handle.current_track(), 1);
+    }
+
+    #[test]
+    fn test_queue_edits_keep_the_playing_position() {
+        let player = test_player();
+        let handle = player.handle();
+        let generation = || player.generation.load(Ordering::SeqCst);
+
+        player.apply(PlayerCommand::SkipTo(1));
+        player.apply(PlayerCommand::SeekForward(Duration::from_secs(10)));
+        player.queue.begin_decoding(handle.current_sample());
+        let before = generation();
+
+        // nothing at or before the track being decoded changes, so no seek
+        player.enqueue::<&str>(&[]);
+        player.edit_queue(QueueEdit::Append(vec![test_track("C", 1, 44_100)]));
+        player.apply(PlayerCommand::Remove(2));
+        assert_eq!(generation(), before);
+        assert_eq!(handle.track_list().tracks.len(), 3);
+        assert_eq!(handle.current_sample(), 44_100 * 70);
+
+        // removing the first track moves the playing one up
+        player.apply(PlayerCommand::Remove(0));
+        assert_eq!(generation(), before + 1);
+        assert_eq!(handle.current_track(), 0);
+        assert_eq!(handle.current_sample(), 44_100 * 10);
+
+        player.apply(PlayerCommand::Move { from: 0, to: 1 });
+        assert_eq!(handle.current_track(), 1);
+        assert_eq!(handle.current_sample(), 44_100 * 11);
+
+        player.apply(PlayerCommand::ClearQueue);
+        assert_eq!(handle.track_list().tracks.len(), 1);
+        assert_eq!(handle.current_sample(), 44_100 * 10);
+
+        // the queue can't be emptied
+        player.apply(PlayerCommand::Remove(0));
+        assert_eq!(handle.track_list().tracks.len(), 1);
+    }
+
+    #[test]
+    fn test_shuffle_albums_and_back() {
+        let player = Player::new(TrackList::from(
+            ["A", "B", "C", "D"]
+                .into_iter()
+                .flat_map(|album| (1..=3).map(move |i| test_track(album, i, 44_100)))
+                .collect_vec(),
+        ));
+        let handle = player.handle();
+        let original = handle.track_list();
+
+        player.apply(PlayerCommand::SkipTo(1));
+        player.apply(PlayerCommand::SeekForward(Duration::from_millis(500)));
+        player.apply(PlayerCommand::SetShuffle(ShuffleMode::Albums));
+        assert_eq!(handle.shuffle(), ShuffleMode::Albums);
+
+        // the playing track stays put and the rest of its album is up next
+        let shuffled = handle.track_list();
+        assert_eq!(handle.current_track(), 1);
+        assert_eq!(shuffled.tracks[..3], original.tracks[..3]);
+        for album in shuffled.tracks.chunks(3) {
+            assert!(album.iter().all(|t| t.album == album[0].album));
+            assert_eq!(album.iter().map(|t| t.track).collect_vec(), [1, 2, 3]);
+        }
+
+        player.apply(PlayerCommand::Enqueue(vec![]));
+        player.edit_queue(QueueEdit::Append(vec![test_track("E", 1, 44_100)]));
+        player.apply(PlayerCommand::SetShuffle(ShuffleMode::Off));
+        let restored = handle.track_list();
+        assert_eq!(restored.tracks[..12], original.tracks[..]);
+        assert_eq!(restored.tracks[12].album, "E");
+        assert_eq!(handle.current_track(), 1);
+        assert_eq!(handle.current_sample(), 44_100 * 3 / 2);
+    }
+
+    /// Albums in the order they come in each of a bunch of shuffles of
+    /// albums A to D from `from`
+    fn album_orders(from: usize) -> Vec<Vec<String>> {
+        let tracks = ["A", "B", "C", "D"]
+            .into_iter()
+            .flat_map(|album| (1..=3).map(move |i| test_track(album, i, 44_100)))
+            .collect_vec();
+        (0..50)
+            .map(|_| {
+                let order = ShuffleMode::Albums.order(&tracks, from);
+                order
+                    .iter()
+                    .map(|&i| tracks[i].album.clone())
+                    .dedup()
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shuffle_albums_after_the_last_track_of_one() {
+        // the third track, the last of A, is playing, so nothing is left of
+        // its album and B shouldn't be stuck up next
+        let orders = album_orders(3);
+        assert!(orders.iter().all(|albums| albums[0] == "A"));
+        assert!(orders.iter().any(|albums| albums[1] != "B"));
+    }
+
+    #[test]
+    fn test_shuffle_albums_before_anything_has_played() {
+        let orders = album_orders(0);
+        assert!(orders.iter().all(|albums| albums.len() == 4));
+        assert!(orders.iter().any(|albums| albums[0] != "A"));
+    }
+
+    #[test]
+    fn test_repeat_all_wraps_next_track() {
+        let player = test_player();
+        let handle = player.handle();
+
+        player.apply(PlayerCommand::SkipTo(2));
+        player.apply(PlayerCommand::NextTrack);
+        assert_eq!(handle.current_track(), 2);
+
+        player.apply(PlayerCommand::SetRepeat(RepeatMode::All));
+        assert_eq!(handle.repeat(), RepeatMode::All);
+        player.apply(PlayerCommand::NextTrack);
+        assert_eq!(handle.current_track(), 0);
+        assert_eq!(handle.current_sample(), 0);
+    }
+
+    #[test]
+    fn test_replay_gain_factor() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: None,
+            album_peak: None,
+        };
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+
+        assert!(close(ReplayGainMode::Off.factor(&gain, 0.0), 1.0));
+        assert!(close(ReplayGainMode::Track.factor(&gain, 0.0), 0.501));
+        // no album gain, so it's the track's
+        assert!(close(ReplayGainMode::Album.factor(&gain, 0.0), 0.501));
+        // +12dB would take the peak over full scale
+        assert!(close(ReplayGainMode::Track.factor(&gain, 18.0), 2.0));
+        assert!(close(
+            ReplayGainMode::Track.factor(&ReplayGain::default(), 12.0),
+            1.0
+        ));
+    }
+
+    #[test]
+    fn test_mixed_channel_counts_play_in_stereo() {
+        let mono = || Track {
+            channels: 1,
+            ..test_track("A", 1, 44_100)
+        };
+        assert_eq!(
+            TrackList::from(vec![mono()]).audio_params().channel_count,
+            1
+        );
+
+        let mixed = TrackList::from(vec![
+            mono(),
+            Track {
+                channels: 6,
+                ..test_track("B", 1, 44_100)
+            },
+        ]);
+        assert_eq!(mixed.audio_params().channel_count, 2);
+        let player = Player::new(mixed).with_channel_passthrough();
+        assert_eq!(player.handle().audio_params().channel_count, 6);
+    }
+
+    #[test]
+    fn test_events_reach_every_subscriber() {
+        let player = test_player();
+        let handle = player.handle();
+        let first = handle.subscribe();
+        let second = handle.subscribe();
+
+        player.apply(PlayerCommand::Pause);
+        player.apply(PlayerCommand::Pause);
+        player.apply(PlayerCommand::SetVolume(50));
+        drop(second);
+        player.apply(PlayerCommand::Play);
+
+        let received = first.try_iter().collect_vec();
+        assert_eq!(
+            received,
+            vec![
+                PlayerEvent::Paused,
+                PlayerEvent::VolumeChanged { volume: 50 },
+                PlayerEvent::Resumed,
+            ]
+        );
+        assert_eq!(player.events.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_handle_queues_commands_until_started() {
+        let player = test_player();
+        let handle = player.handle();
+        handle.send(PlayerCommand::NextTrack);
+        handle.clone().send(PlayerCommand::Stop);
+
+        let received = player.commands_rx.try_iter().collect_vec();
+        assert_eq!(
+            received,
+            vec![PlayerCommand::NextTrack, PlayerCommand::Stop]
+        );
+    }
+
+    #[test]
+    fn test_timecode_formats() {
+        let parse = |s: &str| s.parse::<Timecode>().map(Timecode::as_duration).ok();
+        assert_eq!(parse("45"), Some(Duration::from_secs(45)));
+        assert_eq!(parse("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse("23:45"), Some(Duration::from_secs(23 * 60 + 45)));
+        assert_eq!(parse("1:23.5"), Some(Duration::from_millis(83_500)));
+        assert_eq!(parse("1:90"), None);
+        assert_eq!(parse("1:2:3:4"), None);
+        assert_eq!(parse("-5"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn test_timecode_out_of_range() {
+        let too_long = |s: &str| matches!(s.parse::<Timecode>(), Err(TimecodeError::TooLong(_)));
+        assert!(too_long("1e30"));
+        assert!(too_long("307445734561825861:00"));
+        assert!(too_long("307445734561825860:00:00"));
+        assert!(too_long("18446744073709551615:59.5"));
     }
 }
@@ -0,0 +1,157 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+
+/// An overview of a whole track: the lowest and highest sample in each of a
+/// fixed number of buckets, across all of its channels
+///
+/// Samples are scaled so 127 is full scale, which is plenty for drawing and
+/// keeps a track's waveform down to a couple of kilobytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Waveform {
+    pub min: Vec<i8>,
+    pub max: Vec<i8>,
+}
+
+impl Waveform {
+    /// How many buckets a track gets split into
+    pub const BUCKETS: usize = 1000;
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.min.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.min.is_empty()
+    }
+
+    /// Each bucket's min and max, one after the other, as they're stored in
+    /// the library
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.min
+            .iter()
+            .zip(&self.max)
+            .flat_map(|(&min, &max)| [min.to_le_bytes()[0], max.to_le_bytes()[0]])
+            .collect()
+    }
+
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let (min, max) = bytes
+            .chunks_exact(2)
+            .map(|pair| (i8::from_le_bytes([pair[0]]), i8::from_le_bytes([pair[1]])))
+            .unzip();
+        Self { min, max }
+    }
+
+    /// The loudest peak in each of `columns` even slices of the track, from 0
+    /// for silence to 1 for full scale
+    #[must_use]
+    pub fn peaks(&self, columns: usize) -> Vec<f32> {
+        let len = self.len();
+        (0..columns)
+            .map(|column| {
+                let start = column * len / columns;
+                let end = ((column + 1) * len / columns).max(start + 1).min(len);
+                let peak = self.min[start..end]
+                    .iter()
+                    .zip(&self.max[start..end])
+                    .map(|(&min, &max)| min.unsigned_abs().max(max.unsigned_abs()))
+                    .max()
+                    .unwrap_or(0);
+                f32::from(peak) / 127.0
+            })
+            .collect()
+    }
+
+    /// Look up the waveform for `path` in the library
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the library can't be queried
+    pub fn load(conn: &Connection, path: &Path) -> rusqlite::Result<Option<Self>> {
+        let mut stmt =
+            conn.prepare_cached("SELECT `buckets` FROM `track_waveforms` WHERE `path` = ?1")?;
+        stmt.query_row(params![path.to_string_lossy()], |row| {
+            row.get::<_, Vec<u8>>(0)
+        })
+        .optional()
+        .map(|bytes| bytes.as_deref().map(Self::from_bytes))
+    }
+}
+
+/// Builds a waveform from a track's samples as they're decoded
+pub struct WaveformBuilder {
+    total_frames: u64,
+    frames: u64,
+    min: Vec<f32>,
+    max: Vec<f32>,
+}
+
+impl WaveformBuilder {
+    /// A builder for a track that's `total_frames` long, split into
+    /// `buckets` buckets
+    #[must_use]
+    pub fn new(total_frames: u64, buckets: usize) -> Self {
+        Self {
+            total_frames: total_frames.max(1),
+            frames: 0,
+            min: vec![0.0; buckets.max(1)],
+            max: vec![0.0; buckets.max(1)],
+        }
+    }
+
+    /// Add the next interleaved `samples`. Anything past the length the
+    /// track said it was ends up in the last bucket.
+    pub fn process(&mut self, samples: &[f32], channels: usize) {
+        let buckets = self.min.len() as u64;
+        let last = self.min.len() - 1;
+        for frame in samples.chunks_exact(channels.max(1)) {
+            #[allow(clippy::cast_possible_truncation)]
+            let bucket = ((self.frames * buckets / self.total_frames) as usize).min(last);
+            for &sample in frame {
+                self.min[bucket] = self.min[bucket].min(sample);
+                self.max[bucket] = self.max[bucket].max(sample);
+            }
+            self.frames += 1;
+        }
+    }
+
+    #[must_use]
+    pub fn finish(self) -> Waveform {
+        #[allow(clippy::cast_possible_truncation)]
+        let scale = |sample: f32| (sample.clamp(-1.0, 1.0) * 127.0).round() as i8;
+        Waveform {
+            min: self.min.into_iter().map(scale).collect(),
+            max: self.max.into_iter().map(scale).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_keep_the_extremes_of_every_channel() {
+        let mut builder = WaveformBuilder::new(8, 4);
+        // the right channel is the loudest in the second bucket
+        builder.process(&[0.5, -0.25, 0.0, 0.1, 0.2, 0.2, -0.1, -1.0], 2);
+        builder.process(&[0.0; 6], 2);
+        // more than the track said it had goes in the last bucket
+        builder.process(&[2.0, 0.0, 0.0, 0.0], 2);
+        let waveform = builder.finish();
+        assert_eq!(waveform.min, vec![-32, -127, 0, 0]);
+        assert_eq!(waveform.max, vec![64, 25, 0, 127]);
+
+        assert_eq!(Waveform::from_bytes(&waveform.to_bytes()), waveform);
+        assert_eq!(waveform.peaks(2), vec![1.0, 1.0]);
+        assert_eq!(
+            waveform.peaks(8)[..4],
+            [64.0 / 127.0, 64.0 / 127.0, 1.0, 1.0]
+        );
+    }
+}
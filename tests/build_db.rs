@@ -0,0 +1,66 @@
+use rusqlite::Connection;
+use std::path::Path;
+use std::process::Command;
+use wigglyair::waveform::Waveform;
+
+/// A RIFF chunk, padded to an even length
+fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let size = u32::try_from(body.len()).unwrap();
+    let pad: &[u8] = if body.len() % 2 == 1 { &[0] } else { &[] };
+    [&id[..], &size.to_le_bytes(), body, pad].concat()
+}
+
+/// Write a second of quiet mono 16-bit WAV with the tags build-db needs
+fn write_tagged_wav(path: &Path) {
+    let format = [
+        &1u16.to_le_bytes()[..],
+        &1u16.to_le_bytes(),
+        &44_100u32.to_le_bytes(),
+        &88_200u32.to_le_bytes(),
+        &2u16.to_le_bytes(),
+        &16u16.to_le_bytes(),
+    ]
+    .concat();
+    let tags = [
+        &b"INFO"[..],
+        &chunk(b"INAM", b"Song\0"),
+        &chunk(b"IPRD", b"Record\0"),
+        &chunk(b"IART", b"Band\0"),
+        &chunk(b"ITRK", b"1\0"),
+    ]
+    .concat();
+    let samples: Vec<u8> = (0..44_100)
+        .flat_map(|i| i16::try_from(i % 200 - 100).unwrap().to_le_bytes())
+        .collect();
+    let body = [
+        &b"WAVE"[..],
+        &chunk(b"fmt ", &format),
+        &chunk(b"LIST", &tags),
+        &chunk(b"data", &samples),
+    ]
+    .concat();
+    std::fs::write(path, chunk(b"RIFF", &body)).unwrap();
+}
+
+#[test]
+fn test_waveforms_built_from_a_relative_root_load_by_absolute_path() {
+    let dir = std::env::temp_dir().join(format!("wigglyair-build-db-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("music")).unwrap();
+    write_tagged_wav(&dir.join("music/song.wav"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_build-db"))
+        .current_dir(&dir)
+        .args(["--waveforms", "library.db", "music"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // how the player finds tracks
+    let song = dir.join("music/song.wav").canonicalize().unwrap();
+    let conn = Connection::open(dir.join("library.db")).unwrap();
+    let waveform = Waveform::load(&conn, &song);
+    drop(conn);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(waveform.unwrap().is_some_and(|w| !w.is_empty()));
+}
@@ -0,0 +1,532 @@
+use crossbeam::channel::Receiver;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use wigglyair::metadata::{Probe, ReplayGain};
+use wigglyair::sink::{PcmSink, WavSink};
+use wigglyair::speed::Speed;
+use wigglyair::types::{Player, PlayerCommand, PlayerEvent, PlayerHandle, Track, TrackList};
+
+const RATE: u32 = 44_100;
+
+/// The value of each frame in a test track
+type Signal = fn(u32) -> f32;
+
+/// Write a stereo float WAV file where each sample is `value(frame)` and
+/// return a track for it
+fn write_track(name: &str, track: u32, frames: u32, value: Signal) -> Track {
+    let path = std::env::temp_dir().join(format!(
+        "wigglyair-{name}-{track}-{}.wav",
+        std::process::id()
+    ));
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(&path, spec).unwrap();
+    for frame in 0..frames {
+        writer.write_sample(value(frame)).unwrap();
+        writer.write_sample(-value(frame)).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    Track {
+        path,
+        sample_rate: RATE,
+        samples: u64::from(frames),
+        channels: 2,
+        album: name.to_owned(),
+        album_artist: "Artist".to_owned(),
+        title: format!("Track {track}"),
+        track,
+        replay_gain: ReplayGain::default(),
+    }
+}
+
+/// An MP4 box
+fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let size = u32::try_from(body.len() + 8).unwrap();
+    [&size.to_be_bytes()[..], kind, body].concat()
+}
+
+/// A full box, which starts with a version and flags
+fn full_atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    atom(kind, &[&[0; 4], body].concat())
+}
+
+/// Write a stereo 16-bit PCM M4A file where each sample is `value(frame)`
+/// and return a track for it
+///
+/// Timestamps tick twice per frame, the way plenty of real files have a
+/// timescale that isn't their sample rate.
+fn write_m4a(name: &str, frames: u32, value: fn(u32) -> i16) -> Track {
+    const PACKET: u32 = 1024;
+    const TICKS: u32 = 2;
+    let path = std::env::temp_dir().join(format!("wigglyair-{name}-{}.m4a", std::process::id()));
+    let be = |n: u32| n.to_be_bytes();
+    let packets = frames.div_ceil(PACKET);
+    let last = frames - (packets - 1) * PACKET;
+    let duration = frames * TICKS;
+
+    let sample_entry = atom(
+        b"lpcm",
+        &[
+            &[0; 6][..],
+            &1u16.to_be_bytes(),
+            // version 2, with the real format after the legacy fields
+            &2u16.to_be_bytes(),
+            &[0; 6],
+            &3u16.to_be_bytes(),
+            &16u16.to_be_bytes(),
+            &0xfffeu16.to_be_bytes(),
+            &[0; 2],
+            &be(0x0001_0000),
+            &be(72),
+            &f64::from(RATE).to_be_bytes(),
+            &be(2),
+            &be(0x7f00_0000),
+            &be(16),
+            // signed, little endian
+            &be(0x4),
+            &be(4),
+            &be(PACKET),
+        ]
+        .concat(),
+    );
+    let stbl = |offset: u32| {
+        let sizes = (0..packets).flat_map(|i| be(if i + 1 == packets { last } else { PACKET } * 4));
+        atom(
+            b"stbl",
+            &[
+                full_atom(b"stsd", &[&be(1)[..], &sample_entry].concat()),
+                full_atom(
+                    b"stts",
+                    &[
+                        be(2),
+                        be(packets - 1),
+                        be(PACKET * TICKS),
+                        be(1),
+                        be(last * TICKS),
+                    ]
+                    .concat(),
+                ),
+                full_atom(b"stsc", &[be(1), be(1), be(packets), be(1)].concat()),
+                full_atom(
+                    b"stsz",
+                    &[be(0), be(packets)]
+                        .into_iter()
+                        .flatten()
+                        .chain(sizes)
+                        .collect::<Vec<_>>(),
+                ),
+                full_atom(b"stco", &[be(1), be(offset)].concat()),
+            ]
+            .concat(),
+        )
+    };
+    let matrix = [
+        be(0x0001_0000),
+        be(0),
+        be(0),
+        be(0),
+        be(0x0001_0000),
+        be(0),
+        be(0),
+        be(0),
+        be(0x4000_0000),
+    ]
+    .concat();
+    let moov = |offset: u32| {
+        let mdia = atom(
+            b"mdia",
+            &[
+                full_atom(
+                    b"mdhd",
+                    &[
+                        &be(0)[..],
+                        &be(0),
+                        &be(RATE * TICKS),
+                        &be(duration),
+                        &[0; 4],
+                    ]
+                    .concat(),
+                ),
+                full_atom(b"hdlr", &[&be(0)[..], b"soun", &[0; 13]].concat()),
+                atom(
+                    b"minf",
+                    &[full_atom(b"smhd", &[0; 4]), stbl(offset)].concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let tkhd = full_atom(
+            b"tkhd",
+            &[
+                &[0; 8][..],
+                &be(1),
+                &[0; 4],
+                &be(frames),
+                &[0; 8],
+                &[0, 0, 0, 0, 1, 0, 0, 0],
+                &matrix,
+                &[0; 8],
+            ]
+            .concat(),
+        );
+        let mvhd = full_atom(
+            b"mvhd",
+            &[
+                &[0; 8][..],
+                &be(RATE),
+                &be(frames),
+                &be(0x0001_0000),
+                &[1, 0],
+                &[0; 10],
+                &matrix,
+                &[0; 24],
+                &be(2),
+            ]
+            .concat(),
+        );
+        atom(
+            b"moov",
+            &[mvhd, atom(b"trak", &[tkhd, mdia].concat())].concat(),
+        )
+    };
+
+    let ftyp = atom(b"ftyp", &[&b"M4A "[..], &be(0), b"M4A isommp42"].concat());
+    let offset = u32::try_from(ftyp.len() + moov(0).len() + 8).unwrap();
+    let data = (0..frames)
+        .flat_map(|frame| [value(frame), -value(frame)])
+        .flat_map(i16::to_le_bytes)
+        .collect::<Vec<_>>();
+    std::fs::write(&path, [ftyp, moov(offset), atom(b"mdat", &data)].concat()).unwrap();
+
+    Track {
+        path,
+        sample_rate: RATE,
+        samples: u64::from(frames),
+        channels: 2,
+        album: name.to_owned(),
+        album_artist: "Artist".to_owned(),
+        title: "Track 1".to_owned(),
+        track: 1,
+        replay_gain: ReplayGain::default(),
+    }
+}
+
+fn ramp(frame: u32) -> f32 {
+    (frame % 1000) as f32 / 1000.0
+}
+
+fn half(_: u32) -> f32 {
+    0.5
+}
+
+/// Everything the tracks should sound like, back to back
+fn expected(tracks: &[(u32, Signal)]) -> Vec<f32> {
+    tracks
+        .iter()
+        .flat_map(|&(frames, value)| (0..frames).flat_map(move |f| [value(f), -value(f)]))
+        .collect()
+}
+
+fn wait_for_end(events: &Receiver<PlayerEvent>) -> Vec<PlayerEvent> {
+    let mut received = vec![];
+    loop {
+        let event = events
+            .recv_timeout(Duration::from_secs(30))
+            .expect("player never finished");
+        let done = event == PlayerEvent::PlaylistFinished;
+        received.push(event);
+        if done {
+            return received;
+        }
+    }
+}
+
+fn cleanup(tracks: &TrackList) {
+    for track in &tracks.tracks {
+        std::fs::remove_file(&track.path).unwrap();
+    }
+}
+
+#[test]
+fn test_plays_gaplessly_into_a_wav_file() {
+    let tracks = TrackList::from(vec![
+        write_track("wav", 1, 30_000, ramp),
+        write_track("wav", 2, 20_000, half),
+    ]);
+    let out = std::env::temp_dir().join(format!("wigglyair-out-{}.wav", std::process::id()));
+
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(WavSink::new(&out));
+    let handle = player.handle();
+    let events = handle.subscribe();
+    let thread = player.start();
+    let received = wait_for_end(&events);
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+
+    let played = received
+        .into_iter()
+        .filter(|e| {
+            matches!(
+                e,
+                PlayerEvent::TrackStarted { .. } | PlayerEvent::TrackFinished { .. }
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        played,
+        vec![
+            PlayerEvent::TrackStarted { index: 0 },
+            PlayerEvent::TrackFinished { index: 0 },
+            PlayerEvent::TrackStarted { index: 1 },
+            PlayerEvent::TrackFinished { index: 1 },
+        ]
+    );
+    assert_eq!(handle.current_sample(), 50_000);
+
+    let mut reader = hound::WavReader::open(&out).unwrap();
+    assert_eq!(reader.spec().sample_rate, RATE);
+    let samples = reader
+        .samples::<f32>()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+    std::fs::remove_file(&out).unwrap();
+    cleanup(&tracks);
+
+    assert_eq!(samples, expected(&[(30_000, ramp), (20_000, half)]));
+}
+
+/// A `Write` we can look into after the sink's done with it
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pcm_starts_where_it_was_seeked_to() {
+    let tracks = TrackList::from(vec![
+        write_track("pcm", 1, 10_000, ramp),
+        write_track("pcm", 2, 10_000, ramp),
+    ]);
+    let out = Shared::default();
+
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(PcmSink::new(out.clone()));
+    player.seek(15_000);
+    let handle = player.handle();
+    let events = handle.subscribe();
+    let thread = player.start();
+    wait_for_end(&events);
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+    cleanup(&tracks);
+
+    let bytes = out.0.lock().unwrap();
+    let samples = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let rest = expected(&[(10_000, ramp)]).split_off(10_000);
+    assert_eq!(samples, rest);
+}
+
+/// Takes a few kilobytes and then fails, like a disk filling up
+struct FailingWriter(usize);
+
+impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.0 < buf.len() {
+            return Err(io::Error::other("disk full"));
+        }
+        self.0 -= buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_output_failing_stops_playback_with_an_event() {
+    let tracks = TrackList::from(vec![write_track("failing", 1, 100_000, ramp)]);
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(PcmSink::new(FailingWriter(16_384)));
+    let handle = player.handle();
+    let events = handle.subscribe();
+    let thread = player.start();
+
+    let failed = loop {
+        match events.recv_timeout(Duration::from_secs(30)) {
+            Ok(PlayerEvent::OutputFailed { error }) => break error,
+            Ok(PlayerEvent::PlaylistFinished) => panic!("played through a failing output"),
+            Ok(_) => {}
+            Err(_) => panic!("never heard the output failed"),
+        }
+    };
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+    cleanup(&tracks);
+
+    assert_eq!(failed, "disk full");
+}
+
+#[test]
+fn test_m4a_seeks_by_its_own_timescale() {
+    fn steps(frame: u32) -> i16 {
+        (frame % 1000) as i16 * 32
+    }
+    let track = write_m4a("m4a", 20_000, steps);
+    let probe = Probe::from_path(&track.path).unwrap();
+    assert_eq!((probe.sample_rate, probe.total_samples), (RATE, 20_000));
+
+    let tracks = TrackList::from(vec![track]);
+    let out = Shared::default();
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(PcmSink::new(out.clone()));
+    player.seek(15_000);
+    let handle = player.handle();
+    let events = handle.subscribe();
+    let thread = player.start();
+    wait_for_end(&events);
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+    cleanup(&tracks);
+
+    let bytes = out.0.lock().unwrap();
+    let samples = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let expected = (15_000..20_000)
+        .flat_map(|frame| [steps(frame), -steps(frame)])
+        .map(|s| f32::from(s) / 32768.0)
+        .collect::<Vec<_>>();
+    assert_eq!(samples, expected);
+}
+
+#[test]
+fn test_skips_files_it_cant_open() {
+    let first = write_track("broken", 1, 10_000, ramp);
+    let last = write_track("broken", 3, 10_000, half);
+    let mut broken = write_track("broken", 2, 10_000, half);
+    std::fs::write(&broken.path, b"not a wav file").unwrap();
+    broken.title = "Broken".to_owned();
+    let tracks = TrackList::from(vec![first, broken.clone(), last]);
+    let out = Shared::default();
+
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(PcmSink::new(out.clone()));
+    let handle = player.handle();
+    let events = handle.subscribe();
+    let thread = player.start();
+    let received = wait_for_end(&events);
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+    cleanup(&tracks);
+
+    let skipped = handle.skipped_files();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].path, broken.path);
+    assert!(received
+        .iter()
+        .any(|e| matches!(e, PlayerEvent::FileSkipped { path, .. } if *path == broken.path)));
+
+    let bytes = out.0.lock().unwrap();
+    let samples = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(samples, expected(&[(10_000, ramp), (10_000, half)]));
+}
+
+/// Counts the stereo float frames written, noting where the player says it
+/// is as each buffer comes out
+#[derive(Clone, Default)]
+struct Positions {
+    handle: Arc<OnceLock<PlayerHandle>>,
+    seen: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl Positions {
+    fn frames(&self) -> u64 {
+        self.seen
+            .lock()
+            .unwrap()
+            .last()
+            .map_or(0, |&(frames, _)| frames)
+    }
+}
+
+impl Write for Positions {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frames = self.frames() + buf.len() as u64 / 8;
+        let position = self.handle.get().map_or(0, PlayerHandle::current_sample);
+        self.seen.lock().unwrap().push((frames, position));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_positions_stay_in_track_time_when_playing_faster() {
+    let tracks = TrackList::from(vec![
+        write_track("speed", 1, 30_000, ramp),
+        write_track("speed", 2, 30_000, half),
+    ]);
+    let out = Positions::default();
+
+    let player = Player::new(tracks.clone())
+        .with_pause_fade(Duration::ZERO)
+        .with_sink(PcmSink::new(out.clone()));
+    player.set_speed(Speed::FASTEST);
+    let handle = player.handle();
+    let _ = out.handle.set(handle.clone());
+    let events = handle.subscribe();
+    let thread = player.start();
+    let received = wait_for_end(&events);
+    handle.send(PlayerCommand::Stop);
+    thread.join().unwrap();
+    cleanup(&tracks);
+
+    assert!(received.contains(&PlayerEvent::TrackFinished { index: 1 }));
+    assert_eq!(handle.current_sample(), 60_000);
+
+    // half as long, give or take what's held back for lining up segments
+    let frames = out.frames();
+    assert!((frames as i64 - 30_000).abs() < 2_000, "{frames} frames");
+
+    // the position goes along with what's been heard, twice as fast, rather
+    // than with what's gone into the speed changer. once it's at the end it
+    // can't go any further while the last of the changer plays out.
+    for &(frames, position) in out.seen.lock().unwrap().iter() {
+        let ahead = position as i64 - 2 * frames as i64;
+        assert!(
+            position == 60_000 || ahead.abs() < 500,
+            "at {position} after {frames} frames"
+        );
+    }
+}